@@ -9,9 +9,16 @@
 //! new memory settings.
 
 use std::env;
+use std::fs;
 use std::fs::File;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+#[path = "build/mml.rs"]
+mod mml;
+#[allow(dead_code)]
+#[path = "src/audio/song.rs"]
+mod song;
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
@@ -29,8 +36,36 @@ fn main() {
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
 
+    compile_songs(out);
+
+    // The simulator builds for the host, which has its own linker setup.
+    if env::var("CARGO_CFG_TARGET_OS").as_deref() != Ok("none") {
+        return;
+    }
+
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     println!("cargo:rustc-link-arg-bins=-Tlink-rp.x");
     println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
 }
+
+/// Compiles every `assets/*.mml` into `<name>.song` in the output directory,
+/// for `include_bytes!(concat!(env!("OUT_DIR"), "/<name>.song"))`
+fn compile_songs(out: &Path) {
+    println!("cargo:rerun-if-changed=assets");
+    println!("cargo:rerun-if-changed=build/mml.rs");
+    println!("cargo:rerun-if-changed=src/audio/song.rs");
+    for entry in fs::read_dir("assets").unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().and_then(|extension| extension.to_str()) != Some("mml") {
+            continue;
+        }
+
+        println!("cargo:rerun-if-changed={}", path.display());
+        let source = fs::read_to_string(&path).unwrap();
+        let song =
+            mml::compile(&source).unwrap_or_else(|error| panic!("{}: {error}", path.display()));
+        let name = path.file_stem().unwrap().to_str().unwrap();
+        fs::write(out.join(format!("{name}.song")), song).unwrap();
+    }
+}
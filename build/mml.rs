@@ -0,0 +1,594 @@
+//! Compiles songs written in MML into the binary format of `src/audio/song.rs`.
+//! It runs on the host from `build.rs`, for every `assets/*.mml`.
+//!
+//! ```text
+//! ; A comment runs to the end of the line
+//! #tempo 140
+//! #instrument 0 square 5 60 70 80
+//! #instrument 1 square+noise 0 30 0 30 64
+//! A @0 o4 l8 L [cdeg]2 >c4 r4
+//! B @1 o2 l4 c c g g
+//! ```
+//!
+//! `#tempo` sets the beats per minute and `#instrument` defines an instrument: its index,
+//! its waveforms (`square`, `saw`, `triangle`, `noise`, `sine`, joined with `+`), attack,
+//! decay, sustain, release and optionally the pulse width out of 256.
+//! Lines starting with `A`, `B` or `C` add to that channel's part, which can take:
+//!
+//! * `c d e f g a b`, optionally with `+`/`#` or `-`, a length and a dot, play a note
+//! * `r` rests, with a length and a dot like notes
+//! * `o4` sets the octave, `>` and `<` go up and down one
+//! * `l8` sets the length of notes without one, 4 is a beat and the shortest is 16
+//! * `v12` sets the volume out of 15
+//! * `@1` switches instrument
+//! * `%a4`, `%u4`, `%d4` and `%x2` put an arpeggio, slide up, slide down or cut on the next note
+//! * `[...]3` repeats what is in between, twice without a number
+//! * `L` is where the song loops back to once every part has ended, it plays once without one
+
+// `super` rather than `crate`, so the audio module's tests can include this file too
+use super::song::{Cell, Effect, Instrument, KEEP, MAGIC, MAX_CHANNELS, NOTE_OFF, NO_LOOP};
+
+/// Rows in a beat, a 16th note is one row
+const ROWS_PER_BEAT: u32 = 4;
+
+/// Rows per pattern when the loop point allows, a bar of 4/4
+const ROWS_PER_BAR: u32 = 16;
+
+const MAX_REPEAT_DEPTH: usize = 8;
+
+/// A channel's part turned into cells at the rows they start on
+#[derive(Default)]
+struct Part {
+    cells: Vec<(u32, Cell)>,
+    rows: u32,
+    loop_row: Option<u32>,
+}
+
+/// Where a part is up to while it is read
+struct PartState {
+    octave: i32,
+    length: u32,
+    volume: u8,
+    instrument: u8,
+    effect: Effect,
+    /// Volume and instrument the last cell left the channel with
+    sent_volume: u8,
+    sent_instrument: u8,
+}
+
+/// Compiles an MML source into a song, or explains what is wrong with it
+pub fn compile(source: &str) -> Result<Vec<u8>, String> {
+    let mut tempo = 120u16;
+    let mut instruments: Vec<Option<Instrument>> = Vec::new();
+    let mut parts: Vec<String> = vec![String::new(); MAX_CHANNELS as usize];
+
+    for (number, line) in source.lines().enumerate() {
+        let line = line.split(';').next().unwrap_or("").trim();
+        let error = |message: String| format!("line {}: {message}", number + 1);
+        if line.is_empty() {
+            continue;
+        }
+
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("#tempo") => {
+                tempo = match number_word(words.next()).map_err(error)? {
+                    bpm @ 1..=0xffff => bpm as u16,
+                    bpm => return Err(error(format!("tempo {bpm} is not 1 to 65535"))),
+                };
+            }
+            Some("#instrument") => {
+                let words: Vec<&str> = words.collect();
+                let (index, instrument) = instrument(&words).map_err(error)?;
+                if instruments.len() <= index {
+                    instruments.resize(index + 1, None);
+                }
+                instruments[index] = Some(instrument);
+            }
+            Some(word) if word.starts_with('#') => {
+                return Err(error(format!("unknown directive {word}")));
+            }
+            Some(_) => {
+                let channel = match line.as_bytes()[0] {
+                    letter @ b'A'..=b'Z' => (letter - b'A') as usize,
+                    _ => return Err(error("a part has to start with its channel letter".into())),
+                };
+                let part = parts
+                    .get_mut(channel)
+                    .ok_or_else(|| error(format!("only {MAX_CHANNELS} channels are available")))?;
+                part.push_str(&line[1..]);
+                part.push(' ');
+            }
+            None => {}
+        }
+    }
+
+    if instruments.is_empty() {
+        instruments.push(Some(Instrument {
+            waveforms: 1,
+            attack: 5,
+            decay: 50,
+            sustain: 70,
+            release: 50,
+            pulse_width: 0x8000,
+        }));
+    }
+    let instruments: Vec<Instrument> = instruments
+        .into_iter()
+        .enumerate()
+        .map(|(index, instrument)| instrument.ok_or(format!("instrument {index} is missing")))
+        .collect::<Result<_, _>>()?;
+
+    let used = parts
+        .iter()
+        .rposition(|part| !part.trim().is_empty())
+        .map_or(0, |last| last + 1);
+    if used == 0 {
+        return Err("the song has no parts".into());
+    }
+
+    let mut compiled = Vec::new();
+    for (channel, part) in parts[..used].iter().enumerate() {
+        let letter = (b'A' + channel as u8) as char;
+        let part = compile_part(part).map_err(|message| format!("part {letter}: {message}"))?;
+        if let Some(cell) = part.cells.iter().find(|(_, cell)| {
+            cell.instrument != KEEP && cell.instrument as usize >= instruments.len()
+        }) {
+            return Err(format!(
+                "part {letter}: instrument {} is not defined",
+                cell.1.instrument
+            ));
+        }
+        compiled.push(part);
+    }
+
+    let loop_row = compiled.iter().find_map(|part| part.loop_row);
+    if compiled
+        .iter()
+        .any(|part| part.loop_row.is_some_and(|row| Some(row) != loop_row))
+    {
+        return Err("the parts loop back to different places".into());
+    }
+
+    write_song(tempo, &instruments, &compiled, loop_row)
+}
+
+fn write_song(
+    tempo: u16,
+    instruments: &[Instrument],
+    parts: &[Part],
+    loop_row: Option<u32>,
+) -> Result<Vec<u8>, String> {
+    // Patterns are a bar long unless the loop starts part way through one
+    let rows_per_pattern = match loop_row {
+        Some(row) if row % ROWS_PER_BAR != 0 => gcd(row, ROWS_PER_BAR),
+        _ => ROWS_PER_BAR,
+    } as usize;
+    let channels = parts.len();
+    let rows = parts.iter().map(|part| part.rows).max().unwrap_or(0) as usize;
+    let rows = rows.div_ceil(rows_per_pattern).max(1) * rows_per_pattern;
+
+    let mut grid = vec![Cell::EMPTY; rows * channels];
+    for (channel, part) in parts.iter().enumerate() {
+        for &(row, cell) in &part.cells {
+            grid[row as usize * channels + channel] = cell;
+        }
+    }
+
+    // Identical patterns are only stored once
+    let pattern_cells = rows_per_pattern * channels;
+    let mut patterns: Vec<&[Cell]> = Vec::new();
+    let mut order = Vec::new();
+    for pattern in grid.chunks(pattern_cells) {
+        let index = match patterns.iter().position(|known| *known == pattern) {
+            Some(index) => index,
+            None => {
+                patterns.push(pattern);
+                patterns.len() - 1
+            }
+        };
+        order.push(index);
+    }
+
+    if patterns.len() > 255 || order.len() > 255 {
+        return Err(format!(
+            "the song is too long, it needs {} patterns in an order of {}, 255 fit",
+            patterns.len(),
+            order.len()
+        ));
+    }
+    if instruments.len() > 255 {
+        return Err("at most 255 instruments fit".into());
+    }
+    let loop_to = match loop_row {
+        Some(row) if row as usize >= rows => return Err("the loop point is at the very end".into()),
+        Some(row) => (row as usize / rows_per_pattern) as u8,
+        None => NO_LOOP,
+    };
+
+    let mut song = Vec::new();
+    song.extend_from_slice(MAGIC);
+    song.extend_from_slice(&tempo.to_le_bytes());
+    song.push(ROWS_PER_BEAT as u8);
+    song.push(channels as u8);
+    song.push(rows_per_pattern as u8);
+    song.push(instruments.len() as u8);
+    song.push(patterns.len() as u8);
+    song.push(order.len() as u8);
+    song.push(loop_to);
+    for instrument in instruments {
+        song.extend_from_slice(&instrument.to_bytes());
+    }
+    song.extend(order.iter().map(|&index| index as u8));
+    for pattern in patterns {
+        for cell in pattern {
+            song.extend_from_slice(&cell.to_bytes());
+        }
+    }
+    Ok(song)
+}
+
+fn compile_part(source: &str) -> Result<Part, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut part = Part::default();
+    let mut state = PartState {
+        octave: 4,
+        length: 4,
+        volume: 100,
+        instrument: 0,
+        effect: Effect::None,
+        sent_volume: 100,
+        sent_instrument: 0,
+    };
+    let mut first = true;
+
+    // Open repeats as where they start and how many times they still have to play
+    let mut repeats: Vec<(usize, Option<u32>)> = Vec::new();
+    let mut position = 0;
+    while let Some(&command) = chars.get(position) {
+        let start = position;
+        position += 1;
+        let describe = format!("at character {}", start + 1);
+
+        match command {
+            ' ' | '\t' | '|' => {}
+            'c' | 'd' | 'e' | 'f' | 'g' | 'a' | 'b' => {
+                let semitone = match command {
+                    'c' => 0,
+                    'd' => 2,
+                    'e' => 4,
+                    'f' => 5,
+                    'g' => 7,
+                    'a' => 9,
+                    _ => 11,
+                };
+                let accidental = match chars.get(position) {
+                    Some('+' | '#') => 1,
+                    Some('-') => -1,
+                    _ => 0,
+                };
+                if accidental != 0 {
+                    position += 1;
+                }
+                let rows = length(&chars, &mut position, state.length)
+                    .map_err(|message| format!("{message} {describe}"))?;
+
+                let note = (state.octave + 1) * 12 + semitone + accidental;
+                if !(1..=127).contains(&note) {
+                    return Err(format!("note out of range {describe}"));
+                }
+                let cell = Cell {
+                    note: note as u8,
+                    instrument: match first || state.instrument != state.sent_instrument {
+                        true => state.instrument,
+                        false => KEEP,
+                    },
+                    volume: match first || state.volume != state.sent_volume {
+                        true => state.volume,
+                        false => KEEP,
+                    },
+                    effect: state.effect,
+                };
+                state.sent_instrument = state.instrument;
+                state.sent_volume = state.volume;
+                state.effect = Effect::None;
+                first = false;
+
+                part.cells.push((part.rows, cell));
+                part.rows += rows;
+            }
+            'r' => {
+                let rows = length(&chars, &mut position, state.length)
+                    .map_err(|message| format!("{message} {describe}"))?;
+                let cell = Cell {
+                    note: NOTE_OFF,
+                    ..Cell::EMPTY
+                };
+                part.cells.push((part.rows, cell));
+                part.rows += rows;
+            }
+            'o' => {
+                state.octave = number(&chars, &mut position)
+                    .ok_or(format!("octave missing {describe}"))?
+                    as i32
+            }
+            '>' => state.octave += 1,
+            '<' => state.octave -= 1,
+            'l' => {
+                state.length = match length(&chars, &mut position, 0) {
+                    Ok(0) => return Err(format!("length missing {describe}")),
+                    Ok(rows) => rows,
+                    Err(message) => return Err(format!("{message} {describe}")),
+                };
+            }
+            'v' => {
+                let volume =
+                    number(&chars, &mut position).ok_or(format!("volume missing {describe}"))?;
+                state.volume = (volume.min(15) * 100 / 15) as u8;
+            }
+            '@' => {
+                let instrument = number(&chars, &mut position)
+                    .ok_or(format!("instrument missing {describe}"))?;
+                state.instrument = instrument.min(254) as u8;
+            }
+            '%' => {
+                let kind = chars.get(position).copied();
+                position += 1;
+                let param = number(&chars, &mut position)
+                    .ok_or(format!("effect amount missing {describe}"))?;
+                let param = param.min(15) as u8;
+                state.effect = match kind {
+                    Some('a') => Effect::Arpeggio(param),
+                    Some('u') => Effect::SlideUp(param),
+                    Some('d') => Effect::SlideDown(param),
+                    Some('x') => Effect::Cut(param),
+                    _ => return Err(format!("unknown effect {describe}")),
+                };
+            }
+            'L' => {
+                if part.loop_row.is_some() {
+                    return Err(format!("second loop point {describe}"));
+                }
+                if !repeats.is_empty() {
+                    return Err(format!("loop point inside a repeat {describe}"));
+                }
+                part.loop_row = Some(part.rows);
+                // The channel can come back here with any instrument and volume
+                first = true;
+            }
+            '[' => {
+                if repeats.len() == MAX_REPEAT_DEPTH {
+                    return Err(format!("repeats nested too deep {describe}"));
+                }
+                repeats.push((position, None));
+            }
+            ']' => {
+                let Some((body, left)) = repeats.pop() else {
+                    return Err(format!("] without [ {describe}"));
+                };
+                let left = match left {
+                    Some(left) => left,
+                    None => number(&chars, &mut position).unwrap_or(2),
+                };
+                if left > 1 {
+                    repeats.push((body, Some(left - 1)));
+                    position = body;
+                } else {
+                    // Skip the count after the last time through
+                    number(&chars, &mut position);
+                }
+            }
+            _ => return Err(format!("unknown command {command:?} {describe}")),
+        }
+    }
+
+    if !repeats.is_empty() {
+        return Err("[ without ]".into());
+    }
+    Ok(part)
+}
+
+/// Reads a number if one is next
+fn number(chars: &[char], position: &mut usize) -> Option<u32> {
+    let start = *position;
+    while chars.get(*position).is_some_and(|c| c.is_ascii_digit()) {
+        *position += 1;
+    }
+    chars[start..*position]
+        .iter()
+        .collect::<String>()
+        .parse()
+        .ok()
+}
+
+/// Reads a note length and its dot, as rows, `default` if there is none
+fn length(chars: &[char], position: &mut usize, default: u32) -> Result<u32, &'static str> {
+    let mut rows = match number(chars, position) {
+        Some(0) => return Err("length 0"),
+        Some(length @ (1 | 2 | 4 | 8 | 16)) => ROWS_PER_BEAT * 4 / length,
+        Some(_) => return Err("length shorter than 16 or uneven"),
+        None => default,
+    };
+    if chars.get(*position) == Some(&'.') {
+        *position += 1;
+        if rows % 2 != 0 {
+            return Err("dotted 16ths are too short");
+        }
+        rows += rows / 2;
+    }
+    Ok(rows)
+}
+
+fn number_word(word: Option<&str>) -> Result<u32, String> {
+    let word = word.ok_or("number missing")?;
+    word.parse().map_err(|_| format!("{word} is not a number"))
+}
+
+fn instrument(words: &[&str]) -> Result<(usize, Instrument), String> {
+    if !(6..=7).contains(&words.len()) {
+        return Err("an instrument needs an index, waveforms, attack, decay, sustain, release and maybe a pulse width".into());
+    }
+
+    let mut waveforms = 0;
+    for name in words[1].split('+') {
+        waveforms |= match name {
+            "square" => 1 << 0,
+            "saw" => 1 << 1,
+            "triangle" => 1 << 2,
+            "noise" => 1 << 3,
+            "sine" => 1 << 4,
+            _ => return Err(format!("unknown waveform {name}")),
+        };
+    }
+
+    let pulse_width = match words.get(6) {
+        Some(word) => number_word(Some(word))?.min(255) << 8,
+        None => 0x8000,
+    };
+    let index = number_word(Some(words[0]))? as usize;
+    Ok((
+        index,
+        Instrument {
+            waveforms,
+            attack: number_word(Some(words[2]))?.min(u16::MAX as u32) as u16,
+            decay: number_word(Some(words[3]))?.min(u16::MAX as u32) as u16,
+            sustain: number_word(Some(words[4]))?.min(100) as u8,
+            release: number_word(Some(words[5]))?.min(u16::MAX as u32) as u16,
+            pulse_width: pulse_width as u16,
+        },
+    ))
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    match b {
+        0 => a,
+        _ => gcd(b, a % b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::song::{Song, CELL_LEN, HEADER_LEN, INSTRUMENT_LEN};
+    use super::*;
+
+    fn cells(song: &Song, channel: usize) -> Vec<Cell> {
+        (0..song.order_len())
+            .flat_map(|entry| {
+                let pattern = song.order(entry).unwrap();
+                (0..song.rows_per_pattern()).map(move |row| song.cell(pattern, row, channel))
+            })
+            .collect()
+    }
+
+    fn note(note: u8, instrument: u8, volume: u8) -> Cell {
+        Cell {
+            note,
+            instrument,
+            volume,
+            effect: Effect::None,
+        }
+    }
+
+    #[test]
+    fn compiles_to_a_song_the_player_reads() {
+        let source = "
+            ; two parts, the second one quieter
+            #tempo 90
+            #instrument 0 square 5 60 70 80
+            #instrument 1 saw+noise 0 30 0 30 64
+            A o4 l8 c d+ %a4 e-4. r16
+            B @1 v6 o2 c1
+        ";
+        let data = compile(source).unwrap();
+        let song = Song::parse(&data).unwrap();
+        assert_eq!(
+            (song.tempo(), song.rows_per_beat(), song.channels()),
+            (90, 4, 2)
+        );
+        assert_eq!(song.rows_per_pattern(), 16);
+        assert_eq!(song.order_len(), 1);
+        assert_eq!(song.loop_to(), None);
+        assert_eq!(
+            song.instrument(1),
+            Some(Instrument {
+                waveforms: 0b1010,
+                attack: 0,
+                decay: 30,
+                sustain: 0,
+                release: 30,
+                pulse_width: 64 << 8,
+            })
+        );
+
+        let a = cells(&song, 0);
+        assert_eq!(a[0], note(60, 0, 100));
+        assert_eq!(a[2], note(63, KEEP, KEEP));
+        assert_eq!(
+            a[4],
+            Cell {
+                effect: Effect::Arpeggio(4),
+                ..note(63, KEEP, KEEP)
+            }
+        );
+        assert_eq!(a[10].note, NOTE_OFF);
+        assert!(a[11..].iter().all(|cell| *cell == Cell::EMPTY));
+        assert_eq!(cells(&song, 1)[0], note(36, 1, 40));
+    }
+
+    #[test]
+    fn repeats_and_shares_patterns() {
+        // Six bars, the last four from a nested repeat. Only the first sets the instrument
+        // and volume, the other five are the same pattern.
+        let data = compile("A l1 [c]2 [[c]]").unwrap();
+        let song = Song::parse(&data).unwrap();
+        let order: Vec<_> = (0..song.order_len())
+            .filter_map(|entry| song.order(entry))
+            .collect();
+        assert_eq!(order, [0, 1, 1, 1, 1, 1]);
+        assert_eq!(
+            data.len(),
+            HEADER_LEN + INSTRUMENT_LEN + 6 + 2 * 16 * CELL_LEN
+        );
+    }
+
+    #[test]
+    fn loops_back_to_the_pattern_with_the_loop_point() {
+        let data = compile("A l4 c c L c c c c c c").unwrap();
+        let song = Song::parse(&data).unwrap();
+        // The loop is half a bar in, so patterns are half a bar long
+        assert_eq!(song.rows_per_pattern(), 8);
+        assert_eq!(song.loop_to(), Some(1));
+        // It plays the instrument again where it loops back to
+        let a = cells(&song, 0);
+        assert_eq!(a[8], note(60, 0, 100));
+        assert_eq!(a[12], note(60, KEEP, KEEP));
+    }
+
+    #[test]
+    fn explains_mistakes() {
+        let error = |source: &str| compile(source).unwrap_err();
+        assert_eq!(error(""), "the song has no parts");
+        assert_eq!(error("#speed 3"), "line 1: unknown directive #speed");
+        assert_eq!(error("#tempo 0"), "line 1: tempo 0 is not 1 to 65535");
+        assert_eq!(
+            error("A c\n#tempo 70000"),
+            "line 2: tempo 70000 is not 1 to 65535"
+        );
+        assert_eq!(error("D c"), "line 1: only 3 channels are available");
+        assert_eq!(error("A c q"), "part A: unknown command 'q' at character 4");
+        assert_eq!(
+            error("A c3"),
+            "part A: length shorter than 16 or uneven at character 2"
+        );
+        assert_eq!(error("A [c"), "part A: [ without ]");
+        assert_eq!(error("A @2 c"), "part A: instrument 2 is not defined");
+        assert_eq!(
+            error("A c L c\nB L c c"),
+            "the parts loop back to different places"
+        );
+        assert_eq!(
+            error("#instrument 1 sine 0 0 0 0\nA c"),
+            "instrument 0 is missing"
+        );
+    }
+}
@@ -0,0 +1,198 @@
+//! Sound through the piezo speaker on GPIO 11. A [`synth::Synth`] shared by the whole game
+//! is streamed to the pin by a background task. Simple tones can also be queued from
+//! anywhere and are played one after another on the synth's last channel, and a
+//! [`song::Song`] plays as background music on the channels before it.
+//! The queue lives here so game code works the same whether or not the task is running.
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_time::Duration;
+
+use sequencer::Sequencer;
+use song::Song;
+use synth::{Envelope, Synth, Voice, Waveforms, CHANNELS, SAMPLE_RATE};
+
+// The build script's MML compiler, included to test songs against the sequencer
+#[cfg(test)]
+#[path = "../../build/mml.rs"]
+mod mml;
+pub mod sequencer;
+pub mod song;
+#[cfg(not(feature = "simulator"))]
+pub mod speaker;
+pub mod synth;
+
+/// Tones that can wait in the queue, [`play`] waits for room once it is full
+pub const QUEUE_CAPACITY: usize = 16;
+
+/// A square wave played for a while.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Tone {
+    /// Pitch in Hz, 0 is silence
+    pub frequency: u32,
+    pub duration: Duration,
+    /// Loudness in percent, scaled by the master [`volume`]
+    pub volume: u8,
+}
+
+impl Tone {
+    pub const fn new(frequency: u32, duration: Duration, volume: u8) -> Self {
+        Self {
+            frequency,
+            duration,
+            volume,
+        }
+    }
+
+    /// A pause between tones
+    pub const fn rest(duration: Duration) -> Self {
+        Self::new(0, duration, 0)
+    }
+}
+
+static TONES: Channel<CriticalSectionRawMutex, Tone, QUEUE_CAPACITY> = Channel::new();
+
+/// Raised by [`stop`] to cut the playing tone short
+static STOP: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Raised by the player whenever it runs out of tones
+static IDLE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+static PLAYING: AtomicBool = AtomicBool::new(false);
+
+static VOLUME: AtomicU8 = AtomicU8::new(100);
+
+static SYNTH: Mutex<CriticalSectionRawMutex, RefCell<Synth>> =
+    Mutex::new(RefCell::new(Synth::new(SAMPLE_RATE)));
+
+/// Background music, rendered along with the synth
+static MUSIC: Mutex<CriticalSectionRawMutex, RefCell<Option<Sequencer<'static>>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Synth channel queued tones play on, games can use the others freely
+pub const TONE_CHANNEL: usize = CHANNELS - 1;
+
+/// Plain square wave beeps for queued tones
+const TONE_VOICE: Voice = Voice::new(Waveforms::SQUARE).with_envelope(Envelope::INSTANT);
+
+/// Runs `f` with the synth, keep it short as the audio task waits meanwhile
+pub fn with_synth<R>(f: impl FnOnce(&mut Synth) -> R) -> R {
+    SYNTH.lock(|synth| f(&mut synth.borrow_mut()))
+}
+
+/// Renders the next samples of the synth and the music at the master volume
+pub fn render(out: &mut [i16]) {
+    let volume = volume();
+    MUSIC.lock(|music| {
+        with_synth(|synth| match music.borrow_mut().as_mut() {
+            Some(sequencer) => sequencer.render(synth, out, volume),
+            None => synth.fill(out, volume),
+        })
+    });
+}
+
+/// Starts `song` from the beginning as the background music, replacing any other
+pub fn play_music(song: Song<'static>) {
+    stop_music();
+    MUSIC.lock(|music| *music.borrow_mut() = Some(Sequencer::new(song)));
+}
+
+/// Stops the background music, its notes fade out
+pub fn stop_music() {
+    MUSIC.lock(|music| {
+        if let Some(sequencer) = music.borrow_mut().take() {
+            with_synth(|synth| sequencer.release(synth));
+        }
+    });
+}
+
+/// True while music plays, songs without a loop stop by themselves
+pub fn is_music_playing() -> bool {
+    MUSIC.lock(|music| {
+        music
+            .borrow()
+            .as_ref()
+            .is_some_and(|sequencer| !sequencer.is_finished())
+    })
+}
+
+/// Queues a tone, waiting for room if the queue is full
+pub async fn play(tone: Tone) {
+    TONES.send(tone).await;
+}
+
+/// Queues a tone, false if the queue is full
+pub fn try_play(tone: Tone) -> bool {
+    TONES.try_send(tone).is_ok()
+}
+
+/// Silences the speaker and throws away every queued tone
+pub fn stop() {
+    TONES.clear();
+    STOP.signal(());
+}
+
+/// True while a tone is playing or waiting in the queue
+pub fn is_playing() -> bool {
+    PLAYING.load(Ordering::Relaxed) || !TONES.is_empty()
+}
+
+/// Waits for every queued tone to finish
+pub async fn wait_idle() {
+    while is_playing() {
+        IDLE.wait().await;
+    }
+}
+
+/// Master volume in percent, applied to everything the synth plays
+pub fn volume() -> u8 {
+    VOLUME.load(Ordering::Relaxed)
+}
+
+pub fn set_volume(volume: u8) {
+    VOLUME.store(volume.min(100), Ordering::Relaxed);
+}
+
+/// Sounds a tone on the synth's [`TONE_CHANNEL`], 0 Hz or 0% is silence
+pub fn synth_tone(frequency: u32, volume: u8) {
+    with_synth(|synth| match frequency > 0 && volume > 0 {
+        true => synth.play(TONE_CHANNEL, &TONE_VOICE, frequency, 0, volume),
+        false => synth.stop(TONE_CHANNEL),
+    });
+}
+
+///
+/// Plays queued tones forever on whatever `output` makes the sound. Run it from a task,
+/// the PicoSystem's is [`speaker::audio_task`] which plays them through [`synth_tone`].
+///
+/// # Arguments
+///
+/// * `output` - called with the frequency and loudness in percent to sound, `(0, 0)` is silence
+///
+pub async fn run_player(mut output: impl FnMut(u32, u8)) -> ! {
+    use embassy_futures::select::{select, Either};
+    use embassy_time::Timer;
+
+    loop {
+        let tone = TONES.receive().await;
+        PLAYING.store(true, Ordering::Relaxed);
+        STOP.reset();
+
+        output(tone.frequency, tone.volume.min(100));
+        let stopped = matches!(
+            select(Timer::after(tone.duration), STOP.wait()).await,
+            Either::Second(())
+        );
+
+        if stopped || TONES.is_empty() {
+            output(0, 0);
+            PLAYING.store(false, Ordering::Relaxed);
+            IDLE.signal(());
+        }
+    }
+}
@@ -0,0 +1,297 @@
+//! Plays a [`Song`] on the synth. Time is counted in samples rendered, not read from a clock,
+//! so a song always renders to exactly the same samples.
+
+use super::song::{note_frequency, Cell, Effect, Instrument, Song, KEEP, MAX_CHANNELS, NOTE_OFF};
+use super::synth::{Envelope, Synth, Voice, Waveforms};
+
+/// Ticks each row is split into, effects change on ticks
+pub const TICKS_PER_ROW: u32 = 4;
+
+/// Hz per second a slide's parameter is counted in
+const SLIDE_STEP: i32 = 32;
+
+/// What a song channel is playing
+#[derive(Copy, Clone, Debug)]
+struct Track {
+    note: u8,
+    instrument: u8,
+    volume: u8,
+    effect: Effect,
+}
+
+impl Track {
+    const fn new() -> Self {
+        Self {
+            note: 0,
+            instrument: 0,
+            volume: 100,
+            effect: Effect::None,
+        }
+    }
+}
+
+///
+/// Steps through a song's order, pattern rows and ticks, playing song channel `n`
+/// on synth channel `n`.
+///
+pub struct Sequencer<'a> {
+    song: Song<'a>,
+    entry: usize,
+    row: usize,
+    tick: u32,
+    /// Samples until the next tick
+    samples_left: u32,
+    /// Sample remainder carried between ticks, so ticks average out to the exact tempo
+    remainder: u32,
+    finished: bool,
+    tracks: [Track; MAX_CHANNELS as usize],
+}
+
+impl<'a> Sequencer<'a> {
+    /// Starts at the beginning of `song`
+    pub fn new(song: Song<'a>) -> Self {
+        Self {
+            song,
+            entry: 0,
+            row: 0,
+            tick: 0,
+            samples_left: 0,
+            remainder: 0,
+            finished: song.order_len() == 0,
+            tracks: [Track::new(); MAX_CHANNELS as usize],
+        }
+    }
+
+    pub fn song(&self) -> &Song<'a> {
+        &self.song
+    }
+
+    /// Order entry and row playing now
+    pub fn position(&self) -> (usize, usize) {
+        (self.entry, self.row)
+    }
+
+    /// True once a song without a loop has played its last row
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    ///
+    /// Renders the next samples, playing the song's rows as they come up
+    ///
+    /// # Arguments
+    ///
+    /// * `synth` - synth the song plays on, its other channels keep sounding too
+    /// * `out` - samples to fill
+    /// * `volume` - master volume in percent
+    ///
+    pub fn render(&mut self, synth: &mut Synth, out: &mut [i16], volume: u8) {
+        let mut done = 0;
+        while done < out.len() {
+            if self.finished {
+                synth.fill(&mut out[done..], volume);
+                return;
+            }
+
+            if self.samples_left == 0 {
+                self.samples_left = self.tick_samples(synth.sample_rate());
+                self.play_tick(synth);
+            }
+
+            let count = (self.samples_left as usize).min(out.len() - done);
+            synth.fill(&mut out[done..done + count], volume);
+            done += count;
+            self.samples_left -= count as u32;
+        }
+    }
+
+    /// Lets go of every channel the song uses
+    pub fn release(&self, synth: &mut Synth) {
+        for channel in 0..self.song.channels() {
+            synth.release(channel);
+        }
+    }
+
+    fn tick_samples(&mut self, sample_rate: u32) -> u32 {
+        let per_minute = sample_rate * 60 + self.remainder;
+        let ticks_per_minute =
+            self.song.tempo() as u32 * self.song.rows_per_beat() as u32 * TICKS_PER_ROW;
+        self.remainder = per_minute % ticks_per_minute;
+        (per_minute / ticks_per_minute).max(1)
+    }
+
+    fn play_tick(&mut self, synth: &mut Synth) {
+        let Some(pattern) = self.song.order(self.entry) else {
+            self.finished = true;
+            return;
+        };
+
+        for channel in 0..self.song.channels() {
+            if self.tick == 0 {
+                let cell = self.song.cell(pattern, self.row, channel);
+                self.play_cell(synth, channel, cell);
+            }
+            self.apply_effect(synth, channel);
+        }
+
+        self.tick += 1;
+        if self.tick == TICKS_PER_ROW {
+            self.tick = 0;
+            self.row += 1;
+        }
+        if self.row == self.song.rows_per_pattern() {
+            self.row = 0;
+            self.entry += 1;
+        }
+        if self.entry == self.song.order_len() {
+            match self.song.loop_to() {
+                Some(entry) => self.entry = entry,
+                None => self.finished = true,
+            }
+        }
+    }
+
+    fn play_cell(&mut self, synth: &mut Synth, channel: usize, cell: Cell) {
+        let track = &mut self.tracks[channel];
+        if cell.instrument != KEEP {
+            track.instrument = cell.instrument;
+        }
+        if cell.volume != KEEP {
+            track.volume = cell.volume.min(100);
+            synth.set_volume(channel, track.volume);
+        }
+
+        // Slides only last for the row they are on
+        if matches!(track.effect, Effect::SlideUp(_) | Effect::SlideDown(_)) {
+            synth.set_bend(channel, 0);
+        }
+        track.effect = cell.effect;
+
+        match cell.note {
+            0 => {}
+            NOTE_OFF => synth.release(channel),
+            note => {
+                track.note = note;
+                let voice = voice(self.song.instrument(track.instrument));
+                synth.play(channel, &voice, note_frequency(note), 0, track.volume);
+            }
+        }
+    }
+
+    fn apply_effect(&mut self, synth: &mut Synth, channel: usize) {
+        let track = &self.tracks[channel];
+        match track.effect {
+            Effect::None => {}
+            Effect::Arpeggio(semitones) => {
+                let note = match self.tick % 2 {
+                    0 => track.note,
+                    _ => track.note.saturating_add(semitones),
+                };
+                synth.set_frequency(channel, note_frequency(note));
+            }
+            Effect::SlideUp(speed) if self.tick == 0 => {
+                synth.set_bend(channel, speed as i32 * SLIDE_STEP);
+            }
+            Effect::SlideDown(speed) if self.tick == 0 => {
+                synth.set_bend(channel, -(speed as i32) * SLIDE_STEP);
+            }
+            Effect::Cut(ticks) if self.tick == ticks as u32 => synth.release(channel),
+            Effect::SlideUp(_) | Effect::SlideDown(_) | Effect::Cut(_) => {}
+        }
+    }
+}
+
+/// Synth voice for an instrument, a plain square wave if the song lacks it
+fn voice(instrument: Option<Instrument>) -> Voice {
+    match instrument {
+        Some(instrument) => Voice::new(Waveforms(instrument.waveforms))
+            .with_envelope(Envelope::new(
+                instrument.attack,
+                instrument.decay,
+                instrument.sustain,
+                instrument.release,
+            ))
+            .with_pulse_width(instrument.pulse_width),
+        None => Voice::new(Waveforms::SQUARE),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::mml;
+
+    /// 60 * 9600 samples a minute over 150 bpm * 4 rows * 4 ticks gives 240 samples a tick
+    const RATE: u32 = 9600;
+    const ROW: usize = 4 * 240;
+
+    /// Renders `samples` samples of `sequencer` one at a time, returns the positions it
+    /// moved through
+    fn render(sequencer: &mut Sequencer, synth: &mut Synth, samples: usize) -> Vec<(usize, usize)> {
+        let mut positions = vec![sequencer.position()];
+        let mut sample = [0];
+        for _ in 0..samples {
+            sequencer.render(synth, &mut sample, 100);
+            if positions.last() != Some(&sequencer.position()) {
+                positions.push(sequencer.position());
+            }
+        }
+        positions
+    }
+
+    #[test]
+    fn plays_the_order_and_loops() {
+        // Three bars, the first one twice, then looping back to the second
+        let source = "#tempo 150\nA l1 c L c d";
+        let data = mml::compile(source).unwrap();
+        let song = Song::parse(&data).unwrap();
+        assert_eq!((song.order_len(), song.loop_to()), (3, Some(1)));
+        let mut synth = Synth::new(RATE);
+        let mut sequencer = Sequencer::new(song);
+
+        let positions = render(&mut sequencer, &mut synth, 16 * ROW);
+        assert_eq!(positions.len(), 17);
+        assert_eq!(positions[15..], [(0, 15), (1, 0)]);
+        assert!(synth.is_playing(0));
+
+        let positions = render(&mut sequencer, &mut synth, 32 * ROW);
+        assert_eq!(positions.last(), Some(&(1, 0)));
+        assert!(positions.contains(&(2, 15)));
+        assert!(!sequencer.is_finished());
+    }
+
+    #[test]
+    fn stops_at_the_end_without_a_loop() {
+        let data = mml::compile("#tempo 150\n#instrument 0 square 0 0 100 0\nA l4 c r2.").unwrap();
+        let mut synth = Synth::new(RATE);
+        let mut sequencer = Sequencer::new(Song::parse(&data).unwrap());
+
+        render(&mut sequencer, &mut synth, 4 * ROW);
+        assert!(synth.is_playing(0));
+        // The rest lets go of the note, which has no release
+        render(&mut sequencer, &mut synth, ROW);
+        assert!(!synth.is_playing(0));
+
+        // It ends as the last tick of the last row starts
+        render(&mut sequencer, &mut synth, 10 * ROW + 3 * ROW / 4);
+        assert!(!sequencer.is_finished());
+        render(&mut sequencer, &mut synth, 1);
+        assert!(sequencer.is_finished());
+        let mut silence = [1; 16];
+        sequencer.render(&mut synth, &mut silence, 100);
+        assert_eq!(silence, [0; 16]);
+    }
+
+    #[test]
+    fn keeps_exact_tempo_over_uneven_ticks() {
+        // At 140 bpm a tick is 257.14 samples, the remainder carries over
+        let data = mml::compile("#tempo 140\nA L l16 c").unwrap();
+        let mut synth = Synth::new(RATE);
+        let mut sequencer = Sequencer::new(Song::parse(&data).unwrap());
+
+        // A minute of samples plays 140 beats of 4 rows, each row is a change of position
+        let positions = render(&mut sequencer, &mut synth, 60 * RATE as usize);
+        assert_eq!(positions.len(), 140 * 4 + 1);
+        assert_eq!(sequencer.position(), (0, 0));
+    }
+}
@@ -0,0 +1,428 @@
+//! The binary song format played by the [sequencer](super::sequencer), small enough to embed
+//! with `include_bytes!`. Songs are usually written in MML and compiled by the build script,
+//! see `build/mml.rs`, which shares this file.
+//!
+//! All numbers are little endian. A song is a header, then its instruments, then the order
+//! the patterns play in, then the patterns themselves:
+//!
+//! | Bytes | Header field |
+//! |-------|--------------|
+//! | 4     | `PSS1` |
+//! | 2     | tempo in beats per minute |
+//! | 1     | rows per beat |
+//! | 1     | channels |
+//! | 1     | rows per pattern |
+//! | 1     | instruments |
+//! | 1     | patterns |
+//! | 1     | order length |
+//! | 1     | order entry to loop back to at the end, 255 stops instead |
+//!
+//! An instrument is [`INSTRUMENT_LEN`] bytes: waveforms, attack, decay, sustain, release and
+//! the high byte of the pulse width. A pattern is its rows one after another, each row a
+//! [`CELL_LEN`] byte cell per channel: note, instrument, volume and effect.
+
+/// Starts every song
+pub const MAGIC: &[u8; 4] = b"PSS1";
+
+pub const HEADER_LEN: usize = 13;
+
+pub const INSTRUMENT_LEN: usize = 9;
+
+pub const CELL_LEN: usize = 4;
+
+/// Most channels a song can use, one of the synth's channels stays free for tones
+pub const MAX_CHANNELS: u8 = 3;
+
+/// Header value for a song that stops at the end of its order
+pub const NO_LOOP: u8 = 0xff;
+
+/// Cell note that lets go of the playing note
+pub const NOTE_OFF: u8 = 0xff;
+
+/// Cell instrument or volume that keeps the channel's current one
+pub const KEEP: u8 = 0xff;
+
+/// Why a song could not be read
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SongError {
+    /// Does not start with [`MAGIC`]
+    NotASong,
+    /// Ends before all its parts
+    Truncated,
+    /// Uses more than [`MAX_CHANNELS`] channels, or none
+    Channels,
+    /// Rows per beat, rows per pattern or the tempo is 0
+    Timing,
+    /// The order names a pattern that does not exist, or the loop an entry
+    Order,
+}
+
+/// What a channel does besides playing its note, for the row the cell is on.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Effect {
+    None,
+    /// Switches between the note and the note this many semitones up every tick
+    Arpeggio(u8),
+    /// Bends the note up by this many times 32 Hz per second
+    SlideUp(u8),
+    /// Bends the note down by this many times 32 Hz per second
+    SlideDown(u8),
+    /// Lets go of the note after this many ticks
+    Cut(u8),
+}
+
+impl Effect {
+    /// Effect kind in the high nibble and its parameter in the low one
+    pub fn from_byte(byte: u8) -> Self {
+        let param = byte & 0x0f;
+        match byte >> 4 {
+            1 => Effect::Arpeggio(param),
+            2 => Effect::SlideUp(param),
+            3 => Effect::SlideDown(param),
+            4 => Effect::Cut(param),
+            _ => Effect::None,
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Effect::None => 0,
+            Effect::Arpeggio(param) => 0x10 | param.min(15),
+            Effect::SlideUp(param) => 0x20 | param.min(15),
+            Effect::SlideDown(param) => 0x30 | param.min(15),
+            Effect::Cut(param) => 0x40 | param.min(15),
+        }
+    }
+}
+
+/// One channel of one row.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Cell {
+    /// MIDI note number to start, 0 for none or [`NOTE_OFF`]
+    pub note: u8,
+    /// Instrument for this and later notes, or [`KEEP`]
+    pub instrument: u8,
+    /// Volume in percent for this and later notes, or [`KEEP`]
+    pub volume: u8,
+    pub effect: Effect,
+}
+
+impl Cell {
+    pub const EMPTY: Cell = Cell {
+        note: 0,
+        instrument: KEEP,
+        volume: KEEP,
+        effect: Effect::None,
+    };
+
+    pub fn from_bytes(bytes: [u8; CELL_LEN]) -> Self {
+        Self {
+            note: bytes[0],
+            instrument: bytes[1],
+            volume: bytes[2],
+            effect: Effect::from_byte(bytes[3]),
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; CELL_LEN] {
+        [
+            self.note,
+            self.instrument,
+            self.volume,
+            self.effect.to_byte(),
+        ]
+    }
+}
+
+/// How an instrument sounds, the fields of a synth voice.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Instrument {
+    /// Synth waveform bits
+    pub waveforms: u8,
+    pub attack: u16,
+    pub decay: u16,
+    pub sustain: u8,
+    pub release: u16,
+    pub pulse_width: u16,
+}
+
+impl Instrument {
+    pub fn from_bytes(bytes: [u8; INSTRUMENT_LEN]) -> Self {
+        Self {
+            waveforms: bytes[0],
+            attack: u16::from_le_bytes([bytes[1], bytes[2]]),
+            decay: u16::from_le_bytes([bytes[3], bytes[4]]),
+            sustain: bytes[5],
+            release: u16::from_le_bytes([bytes[6], bytes[7]]),
+            pulse_width: (bytes[8] as u16) << 8,
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; INSTRUMENT_LEN] {
+        let [attack_low, attack_high] = self.attack.to_le_bytes();
+        let [decay_low, decay_high] = self.decay.to_le_bytes();
+        let [release_low, release_high] = self.release.to_le_bytes();
+        [
+            self.waveforms,
+            attack_low,
+            attack_high,
+            decay_low,
+            decay_high,
+            self.sustain,
+            release_low,
+            release_high,
+            (self.pulse_width >> 8) as u8,
+        ]
+    }
+}
+
+/// Frequency of MIDI notes 120 to 131 in 1/16 Hz, the other octaves are shifted from these
+const TOP_OCTAVE: [u32; 12] = [
+    133952, 141918, 150356, 159297, 168769, 178805, 189437, 200702, 212636, 225280, 238676, 252868,
+];
+
+/// Frequency in Hz of a MIDI note, 69 is A4 at 440 Hz
+pub fn note_frequency(note: u8) -> u32 {
+    let note = note.min(131);
+    let shift = 4 + (10 - note / 12) as u32;
+    (TOP_OCTAVE[note as usize % 12] + (1 << (shift - 1))) >> shift
+}
+
+///
+/// A song read from its binary form, without copying it.
+///
+#[derive(Copy, Clone, Debug)]
+pub struct Song<'a> {
+    data: &'a [u8],
+    tempo: u16,
+    rows_per_beat: u8,
+    channels: u8,
+    rows_per_pattern: u8,
+    instruments: u8,
+    patterns: u8,
+    order_len: u8,
+    loop_to: u8,
+}
+
+impl<'a> Song<'a> {
+    /// Checks a song's header and that all of it is there
+    pub fn parse(data: &'a [u8]) -> Result<Self, SongError> {
+        let header = data.get(..HEADER_LEN).ok_or(SongError::Truncated)?;
+        if &header[..4] != MAGIC {
+            return Err(SongError::NotASong);
+        }
+
+        let song = Self {
+            data,
+            tempo: u16::from_le_bytes([header[4], header[5]]),
+            rows_per_beat: header[6],
+            channels: header[7],
+            rows_per_pattern: header[8],
+            instruments: header[9],
+            patterns: header[10],
+            order_len: header[11],
+            loop_to: header[12],
+        };
+
+        if song.channels == 0 || song.channels > MAX_CHANNELS {
+            return Err(SongError::Channels);
+        }
+        if song.tempo == 0 || song.rows_per_beat == 0 || song.rows_per_pattern == 0 {
+            return Err(SongError::Timing);
+        }
+        if data.len() < song.patterns_start() + song.patterns as usize * song.pattern_len() {
+            return Err(SongError::Truncated);
+        }
+        let order = &data[song.order_start()..song.order_start() + song.order_len as usize];
+        if order.iter().any(|&pattern| pattern >= song.patterns)
+            || (song.loop_to != NO_LOOP && song.loop_to >= song.order_len)
+        {
+            return Err(SongError::Order);
+        }
+
+        Ok(song)
+    }
+
+    /// Beats per minute
+    pub fn tempo(&self) -> u16 {
+        self.tempo
+    }
+
+    pub fn rows_per_beat(&self) -> u8 {
+        self.rows_per_beat
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels as usize
+    }
+
+    pub fn rows_per_pattern(&self) -> usize {
+        self.rows_per_pattern as usize
+    }
+
+    pub fn order_len(&self) -> usize {
+        self.order_len as usize
+    }
+
+    /// Order entry the song goes back to after the last one, None if it stops there
+    pub fn loop_to(&self) -> Option<usize> {
+        (self.loop_to != NO_LOOP).then_some(self.loop_to as usize)
+    }
+
+    pub fn instrument(&self, index: u8) -> Option<Instrument> {
+        if index >= self.instruments {
+            return None;
+        }
+        let start = HEADER_LEN + index as usize * INSTRUMENT_LEN;
+        let bytes = self.data[start..start + INSTRUMENT_LEN].try_into().ok()?;
+        Some(Instrument::from_bytes(bytes))
+    }
+
+    /// Pattern played at an entry of the order
+    pub fn order(&self, entry: usize) -> Option<u8> {
+        (entry < self.order_len()).then(|| self.data[self.order_start() + entry])
+    }
+
+    /// A cell of a pattern, empty outside of it
+    pub fn cell(&self, pattern: u8, row: usize, channel: usize) -> Cell {
+        if pattern >= self.patterns || row >= self.rows_per_pattern() || channel >= self.channels()
+        {
+            return Cell::EMPTY;
+        }
+        let start = self.patterns_start()
+            + pattern as usize * self.pattern_len()
+            + (row * self.channels() + channel) * CELL_LEN;
+        match self.data[start..start + CELL_LEN].try_into() {
+            Ok(bytes) => Cell::from_bytes(bytes),
+            Err(_) => Cell::EMPTY,
+        }
+    }
+
+    fn order_start(&self) -> usize {
+        HEADER_LEN + self.instruments as usize * INSTRUMENT_LEN
+    }
+
+    fn patterns_start(&self) -> usize {
+        self.order_start() + self.order_len as usize
+    }
+
+    fn pattern_len(&self) -> usize {
+        self.rows_per_pattern() * self.channels() * CELL_LEN
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INSTRUMENT: Instrument = Instrument {
+        waveforms: 1,
+        attack: 300,
+        decay: 20,
+        sustain: 70,
+        release: 1000,
+        pulse_width: 0x4000,
+    };
+
+    /// One channel, one instrument and two patterns of two rows, played 1, 0, 1 then
+    /// looping back to the second entry
+    fn song() -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&150u16.to_le_bytes());
+        data.extend_from_slice(&[4, 1, 2, 1, 2, 3, 1]);
+        data.extend_from_slice(&INSTRUMENT.to_bytes());
+        data.extend_from_slice(&[1, 0, 1]);
+        for note in [60, NOTE_OFF, 0, 72] {
+            let cell = Cell {
+                note,
+                effect: Effect::Arpeggio(7),
+                ..Cell::EMPTY
+            };
+            data.extend_from_slice(&cell.to_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn reads_a_song() {
+        let data = song();
+        let song = Song::parse(&data).unwrap();
+        assert_eq!((song.tempo(), song.rows_per_beat()), (150, 4));
+        assert_eq!((song.channels(), song.rows_per_pattern()), (1, 2));
+        assert_eq!(song.instrument(0), Some(INSTRUMENT));
+        assert_eq!(song.instrument(1), None);
+        assert_eq!(song.order_len(), 3);
+        assert_eq!(
+            (song.order(0), song.order(2), song.order(3)),
+            (Some(1), Some(1), None)
+        );
+        assert_eq!(song.loop_to(), Some(1));
+        assert_eq!(song.cell(1, 1, 0).note, 72);
+        assert_eq!(song.cell(0, 1, 0).note, NOTE_OFF);
+        assert_eq!(song.cell(0, 0, 0).effect, Effect::Arpeggio(7));
+        assert_eq!(song.cell(2, 0, 0), Cell::EMPTY);
+        assert_eq!(song.cell(0, 0, 1), Cell::EMPTY);
+    }
+
+    #[test]
+    fn rejects_broken_songs() {
+        let broken = |edits: &[(usize, u8)]| {
+            let mut data = song();
+            for &(offset, byte) in edits {
+                data[offset] = byte;
+            }
+            Song::parse(&data).err()
+        };
+        let data = song();
+
+        assert_eq!(
+            Song::parse(&data[..HEADER_LEN - 1]).err(),
+            Some(SongError::Truncated)
+        );
+        assert_eq!(
+            Song::parse(&data[..data.len() - 1]).err(),
+            Some(SongError::Truncated)
+        );
+        assert_eq!(broken(&[(3, b'2')]), Some(SongError::NotASong));
+        assert_eq!(broken(&[(7, 0)]), Some(SongError::Channels));
+        assert_eq!(broken(&[(7, MAX_CHANNELS + 1)]), Some(SongError::Channels));
+        assert_eq!(broken(&[(4, 0), (5, 0)]), Some(SongError::Timing));
+        assert_eq!(broken(&[(6, 0)]), Some(SongError::Timing));
+        assert_eq!(broken(&[(8, 0)]), Some(SongError::Timing));
+        assert_eq!(
+            broken(&[(HEADER_LEN + INSTRUMENT_LEN, 2)]),
+            Some(SongError::Order)
+        );
+        assert_eq!(broken(&[(12, 3)]), Some(SongError::Order));
+        assert!(broken(&[(12, NO_LOOP)]).is_none());
+    }
+
+    #[test]
+    fn cells_and_effects_round_trip_through_bytes() {
+        for effect in [
+            Effect::None,
+            Effect::Arpeggio(4),
+            Effect::SlideUp(15),
+            Effect::SlideDown(1),
+            Effect::Cut(2),
+        ] {
+            assert_eq!(Effect::from_byte(effect.to_byte()), effect);
+        }
+        let cell = Cell {
+            note: 61,
+            instrument: 3,
+            volume: 80,
+            effect: Effect::Cut(3),
+        };
+        assert_eq!(Cell::from_bytes(cell.to_bytes()), cell);
+        assert_eq!(Instrument::from_bytes(INSTRUMENT.to_bytes()), INSTRUMENT);
+    }
+
+    #[test]
+    fn note_frequencies() {
+        assert_eq!(note_frequency(69), 440);
+        assert_eq!(note_frequency(57), 220);
+        assert_eq!(note_frequency(60), 262);
+        assert_eq!(note_frequency(131), note_frequency(200));
+    }
+}
@@ -0,0 +1,104 @@
+//! The piezo speaker on GPIO 11, driven by PWM slice 5 channel B.
+//!
+//! The slice wraps once per sample, and each wrap has a DMA channel write the next level into
+//! its compare register. Two buffers take turns: one is played while the synth renders the
+//! other, so the CPU only wakes up once per buffer.
+
+use embassy_futures::select::{select, Either};
+use embassy_rp::clocks::clk_sys_freq;
+use embassy_rp::dma::{self, Channel};
+use embassy_rp::peripherals::{DMA_CH1, PIN_11, PWM_SLICE5};
+use embassy_rp::pwm::{Config, Pwm};
+use embassy_rp::{pac, Peripheral};
+
+use super::synth::SAMPLE_RATE;
+
+/// Samples per DMA buffer, about 12ms at 22kHz
+const BUFFER_SAMPLES: usize = 256;
+
+/// PWM slice the speaker pin belongs to
+const SLICE: usize = 5;
+
+/// DREQ_PWM_WRAP0 is 24, one per slice after that
+const DREQ_PWM_WRAP: u8 = 24 + SLICE as u8;
+
+///
+/// Plays samples on the speaker. The PWM period is one sample, so the counter only reaches
+/// a few thousand and the level has around 12 bits of resolution.
+///
+pub struct Speaker<'a> {
+    _pwm: Pwm<'a>,
+    top: u16,
+}
+
+impl<'a> Speaker<'a> {
+    pub fn new(slice: PWM_SLICE5, pin: PIN_11) -> Self {
+        let top = (clk_sys_freq() / SAMPLE_RATE - 1).min(u16::MAX as u32) as u16;
+        let mut config = Config::default();
+        config.top = top;
+        config.compare_b = top / 2;
+        Self {
+            _pwm: Pwm::new_output_b(slice, pin, config),
+            top,
+        }
+    }
+
+    /// Compare register value for a sample, channel A is unused and left at 0
+    fn level(&self, sample: i16) -> u32 {
+        let level = (sample as i32 + 32768) as u32 * (self.top as u32 + 1) >> 16;
+        level << 16
+    }
+
+    ///
+    /// Streams samples forever, each call of `render` fills the next buffer
+    ///
+    /// # Arguments
+    ///
+    /// * `dma` - channel that copies the levels into the PWM
+    /// * `render` - fills a buffer with the next samples
+    ///
+    pub async fn stream(
+        &mut self,
+        mut dma: impl Peripheral<P = impl Channel>,
+        mut render: impl FnMut(&mut [i16]),
+    ) -> ! {
+        let compare = pac::PWM.ch(SLICE).cc().as_ptr() as *mut u32;
+        let mut samples = [0i16; BUFFER_SAMPLES];
+        let mut levels = [[self.level(0); BUFFER_SAMPLES]; 2];
+        let mut playing = 0;
+
+        loop {
+            // Safety: the buffer is not touched until the transfer is awaited, and the
+            // compare register of our own slice is a valid target for every word
+            let transfer = unsafe {
+                dma::write(
+                    dma.reborrow(),
+                    &levels[playing][..] as *const [u32],
+                    compare,
+                    DREQ_PWM_WRAP.into(),
+                )
+            };
+
+            render(&mut samples);
+            for (level, sample) in levels[playing ^ 1].iter_mut().zip(samples) {
+                *level = self.level(sample);
+            }
+
+            transfer.await;
+            playing ^= 1;
+        }
+    }
+}
+
+/// Streams the synth to the speaker and plays queued tones on it, spawn it once
+#[embassy_executor::task]
+pub async fn audio_task(mut speaker: Speaker<'static>, dma: DMA_CH1) -> ! {
+    match select(
+        speaker.stream(dma, super::render),
+        super::run_player(super::synth_tone),
+    )
+    .await
+    {
+        Either::First(never) | Either::Second(never) => never,
+    }
+}
@@ -0,0 +1,565 @@
+//! A small chiptune synthesizer in the spirit of the C++ SDK's `voice`/`play`: a handful of
+//! channels, each mixing square, saw, triangle, noise and sine waves under an ADSR envelope.
+//! It only does integer maths on plain memory, the RP2040 has no FPU, and it knows nothing
+//! about the hardware, so the same samples come out on the device and on the host.
+
+use core::ops::BitOr;
+
+/// Samples per second the synth is normally rendered at
+pub const SAMPLE_RATE: u32 = 22_050;
+
+/// Channels that can sound at once
+pub const CHANNELS: usize = 4;
+
+/// Envelope level of a channel at full volume
+const LEVEL_MAX: u32 = 1 << 24;
+
+/// Waves a voice mixes, any combination of them.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct Waveforms(pub u8);
+
+impl Waveforms {
+    pub const NONE: Waveforms = Waveforms(0);
+    pub const SQUARE: Waveforms = Waveforms(1 << 0);
+    pub const SAW: Waveforms = Waveforms(1 << 1);
+    pub const TRIANGLE: Waveforms = Waveforms(1 << 2);
+    pub const NOISE: Waveforms = Waveforms(1 << 3);
+    pub const SINE: Waveforms = Waveforms(1 << 4);
+
+    pub fn contains(self, other: Waveforms) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl BitOr for Waveforms {
+    type Output = Waveforms;
+
+    fn bitor(self, other: Waveforms) -> Waveforms {
+        Waveforms(self.0 | other.0)
+    }
+}
+
+///
+/// How loud a note is over time: it rises to full over `attack`, falls to `sustain` over
+/// `decay`, stays there until released and then fades out over `release`.
+///
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Envelope {
+    /// Milliseconds from silence to full
+    pub attack: u16,
+    /// Milliseconds from full to the sustain level
+    pub decay: u16,
+    /// Level held until release, in percent
+    pub sustain: u8,
+    /// Milliseconds from the sustain level to silence
+    pub release: u16,
+}
+
+impl Envelope {
+    pub const fn new(attack: u16, decay: u16, sustain: u8, release: u16) -> Self {
+        Self {
+            attack,
+            decay,
+            sustain,
+            release,
+        }
+    }
+
+    /// Full volume straight away and silence as soon as it is released, for plain beeps
+    pub const INSTANT: Envelope = Envelope::new(0, 0, 100, 0);
+}
+
+impl Default for Envelope {
+    /// The C++ SDK's default voice
+    fn default() -> Self {
+        Self::new(100, 50, 80, 100)
+    }
+}
+
+///
+/// What a note sounds like, the SDK's `voice_t`. Hand it to [`Synth::play`] with a pitch.
+///
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Voice {
+    pub waveforms: Waveforms,
+    pub envelope: Envelope,
+    /// Pitch change in Hz per second while the note plays, negative bends down
+    pub bend: i32,
+    /// Share of each square wave period spent high, 0x8000 is a 50% duty cycle
+    pub pulse_width: u16,
+}
+
+impl Voice {
+    pub const fn new(waveforms: Waveforms) -> Self {
+        Self {
+            waveforms,
+            envelope: Envelope::new(100, 50, 80, 100),
+            bend: 0,
+            pulse_width: 0x8000,
+        }
+    }
+
+    pub const fn with_envelope(mut self, envelope: Envelope) -> Self {
+        self.envelope = envelope;
+        self
+    }
+
+    pub const fn with_bend(mut self, bend: i32) -> Self {
+        self.bend = bend;
+        self
+    }
+
+    pub const fn with_pulse_width(mut self, pulse_width: u16) -> Self {
+        self.pulse_width = pulse_width;
+        self
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Stage {
+    Off,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+/// A voice playing a note, with where it is in its wave and envelope
+#[derive(Clone, Debug)]
+struct Channel {
+    voice: Voice,
+    volume: u8,
+    /// Position in the wave, a full turn of the u32 is one period
+    phase: u32,
+    /// Phase advance per sample, the pitch
+    step: u32,
+    /// Step change per sample, the bend
+    step_bend: i32,
+    stage: Stage,
+    level: u32,
+    /// Level change per sample in the current stage
+    rate: u32,
+    /// Samples left before the note releases by itself
+    hold: Option<u32>,
+    noise: u16,
+    noise_sample: i32,
+}
+
+impl Channel {
+    const fn new() -> Self {
+        Self {
+            voice: Voice::new(Waveforms::SQUARE),
+            volume: 0,
+            phase: 0,
+            step: 0,
+            step_bend: 0,
+            stage: Stage::Off,
+            level: 0,
+            rate: 0,
+            hold: None,
+            noise: 0xace1,
+            noise_sample: 0,
+        }
+    }
+
+    /// Level change per sample that covers `span` in `millis`, rounded up so the stage ends
+    /// on time
+    fn rate(span: u32, millis: u16, sample_rate: u32) -> u32 {
+        let samples = (millis as u64 * sample_rate as u64 / 1000).max(1);
+        (span as u64).div_ceil(samples).max(1) as u32
+    }
+
+    fn sustain_level(&self) -> u32 {
+        (LEVEL_MAX >> 8) * (self.voice.envelope.sustain.min(100) as u32 * 256 / 100)
+    }
+
+    fn release(&mut self, sample_rate: u32) {
+        if self.stage != Stage::Off {
+            self.stage = Stage::Release;
+            self.rate = Self::rate(self.level, self.voice.envelope.release, sample_rate);
+            self.hold = None;
+        }
+    }
+
+    fn advance_envelope(&mut self, sample_rate: u32) {
+        match self.stage {
+            Stage::Off | Stage::Sustain => {}
+            Stage::Attack => {
+                self.level = (self.level + self.rate).min(LEVEL_MAX);
+                if self.level == LEVEL_MAX {
+                    self.stage = Stage::Decay;
+                    let span = LEVEL_MAX - self.sustain_level();
+                    self.rate = Self::rate(span, self.voice.envelope.decay, sample_rate);
+                }
+            }
+            Stage::Decay => {
+                let sustain = self.sustain_level();
+                self.level = self.level.saturating_sub(self.rate).max(sustain);
+                if self.level == sustain {
+                    self.stage = Stage::Sustain;
+                }
+            }
+            Stage::Release => {
+                self.level = self.level.saturating_sub(self.rate);
+                if self.level == 0 {
+                    self.stage = Stage::Off;
+                }
+            }
+        }
+
+        if let Some(hold) = self.hold.as_mut() {
+            *hold = hold.saturating_sub(1);
+            if *hold == 0 {
+                self.release(sample_rate);
+            }
+        }
+    }
+
+    /// Value of the mixed waves at `phase`, -32767 to 32767
+    fn wave(&mut self, phase: u32) -> i32 {
+        let waveforms = self.voice.waveforms;
+        let position = (phase >> 16) as i32;
+        let mut sum = 0;
+        let mut count = 0;
+
+        if waveforms.contains(Waveforms::SQUARE) {
+            sum += match position < self.voice.pulse_width as i32 {
+                true => 32767,
+                false => -32767,
+            };
+            count += 1;
+        }
+        if waveforms.contains(Waveforms::SAW) {
+            sum += (position - 32768).max(-32767);
+            count += 1;
+        }
+        if waveforms.contains(Waveforms::TRIANGLE) {
+            sum += match position < 32768 {
+                true => (position * 2 - 32768).max(-32767),
+                false => 32767 - (position - 32768) * 2,
+            };
+            count += 1;
+        }
+        if waveforms.contains(Waveforms::NOISE) {
+            sum += self.noise_sample;
+            count += 1;
+        }
+        if waveforms.contains(Waveforms::SINE) {
+            sum += sine(position);
+            count += 1;
+        }
+
+        match count {
+            0 => 0,
+            _ => sum / count,
+        }
+    }
+
+    fn sample(&mut self, sample_rate: u32) -> i32 {
+        if self.stage == Stage::Off {
+            return 0;
+        }
+
+        let value = self.wave(self.phase);
+        let next = self.phase.wrapping_add(self.step);
+        // Noise changes 16 times a period, so it follows the pitch like the SDK's
+        if (next ^ self.phase) >> 28 != 0 {
+            let bit = (self.noise ^ (self.noise >> 2) ^ (self.noise >> 3) ^ (self.noise >> 5)) & 1;
+            self.noise = (self.noise >> 1) | (bit << 15);
+            self.noise_sample = self.noise as i16 as i32;
+        }
+        self.phase = next;
+        self.step = self.step.saturating_add_signed(self.step_bend);
+
+        let level = (self.level >> 9) as i32;
+        self.advance_envelope(sample_rate);
+        ((value * level) >> 15) * self.volume as i32 / 100
+    }
+}
+
+/// Sine of `position` (0 to 65535 is one period) from a parabola, within about 5%
+fn sine(position: i32) -> i32 {
+    // -32768 to 32767 over the period, so the parabola is x * (1 - |x|)
+    let x = position - 32768;
+    let y = (x * (32768 - x.abs())) >> 13;
+    -y.clamp(-32767, 32767)
+}
+
+///
+/// The channels and their mix, rendered one sample at a time.
+///
+pub struct Synth {
+    channels: [Channel; CHANNELS],
+    sample_rate: u32,
+}
+
+impl Synth {
+    pub const fn new(sample_rate: u32) -> Self {
+        Self {
+            channels: [const { Channel::new() }; CHANNELS],
+            sample_rate,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    ///
+    /// Starts a note on a channel, cutting off whatever it was playing, like the SDK's `play`
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - channel to play on, below [`CHANNELS`]
+    /// * `voice` - what the note sounds like
+    /// * `frequency` - pitch in Hz
+    /// * `duration` - milliseconds before the note is released, 0 holds it until [`Synth::release`]
+    /// * `volume` - loudness in percent
+    ///
+    pub fn play(
+        &mut self,
+        channel: usize,
+        voice: &Voice,
+        frequency: u32,
+        duration: u32,
+        volume: u8,
+    ) {
+        let sample_rate = self.sample_rate;
+        let Some(playing) = self.channels.get_mut(channel) else {
+            return;
+        };
+
+        playing.voice = *voice;
+        playing.volume = volume.min(100);
+        playing.phase = 0;
+        playing.stage = Stage::Attack;
+        playing.level = 0;
+        playing.rate = Channel::rate(LEVEL_MAX, voice.envelope.attack, sample_rate);
+        // A long note at a high rate overflows a u32 before the divide
+        let samples = duration as u64 * sample_rate as u64 / 1000;
+        playing.hold = (duration > 0).then(|| samples.clamp(1, u32::MAX as u64) as u32);
+        self.set_frequency(channel, frequency);
+        self.set_bend(channel, voice.bend);
+    }
+
+    /// Changes the pitch of a playing note without restarting it
+    pub fn set_frequency(&mut self, channel: usize, frequency: u32) {
+        let sample_rate = self.sample_rate as u64;
+        if let Some(channel) = self.channels.get_mut(channel) {
+            channel.step = (((frequency as u64) << 32) / sample_rate).min(u32::MAX as u64) as u32;
+        }
+    }
+
+    /// Bends a playing note by `bend` Hz per second
+    pub fn set_bend(&mut self, channel: usize, bend: i32) {
+        let sample_rate = self.sample_rate as i64;
+        if let Some(channel) = self.channels.get_mut(channel) {
+            channel.step_bend = (((bend as i64) << 32) / (sample_rate * sample_rate)) as i32;
+        }
+    }
+
+    pub fn set_volume(&mut self, channel: usize, volume: u8) {
+        if let Some(channel) = self.channels.get_mut(channel) {
+            channel.volume = volume.min(100);
+        }
+    }
+
+    /// Lets a note go, it fades out over its release
+    pub fn release(&mut self, channel: usize) {
+        let sample_rate = self.sample_rate;
+        if let Some(channel) = self.channels.get_mut(channel) {
+            channel.release(sample_rate);
+        }
+    }
+
+    /// Silences a channel straight away
+    pub fn stop(&mut self, channel: usize) {
+        if let Some(channel) = self.channels.get_mut(channel) {
+            channel.stage = Stage::Off;
+            channel.level = 0;
+        }
+    }
+
+    pub fn stop_all(&mut self) {
+        for channel in 0..CHANNELS {
+            self.stop(channel);
+        }
+    }
+
+    /// True until the channel's note has faded out completely
+    pub fn is_playing(&self, channel: usize) -> bool {
+        self.channels
+            .get(channel)
+            .is_some_and(|channel| channel.stage != Stage::Off)
+    }
+
+    /// Mixes the next sample of every channel, scaled by `volume` in percent
+    pub fn sample(&mut self, volume: u8) -> i16 {
+        let sample_rate = self.sample_rate;
+        let mix: i32 = self
+            .channels
+            .iter_mut()
+            .map(|channel| channel.sample(sample_rate))
+            .sum();
+        (mix * volume.min(100) as i32 / 100).clamp(-32767, 32767) as i16
+    }
+
+    /// Renders the next samples into `out`
+    pub fn fill(&mut self, out: &mut [i16], volume: u8) {
+        for sample in out {
+            *sample = self.sample(volume);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One sample per millisecond keeps the envelope maths easy to follow
+    const RATE: u32 = 1000;
+
+    fn synth_playing(voice: Voice, duration: u32) -> Synth {
+        let mut synth = Synth::new(RATE);
+        synth.play(0, &voice, 100, duration, 100);
+        synth
+    }
+
+    /// Renders `samples` samples, returns the stage of channel 0 after each
+    fn stages(synth: &mut Synth, samples: usize) -> Vec<Stage> {
+        (0..samples)
+            .map(|_| {
+                synth.sample(100);
+                synth.channels[0].stage
+            })
+            .collect()
+    }
+
+    #[test]
+    fn envelope_goes_through_every_stage() {
+        let voice = Voice::new(Waveforms::SQUARE).with_envelope(Envelope::new(10, 10, 50, 10));
+        let mut synth = synth_playing(voice, 0);
+
+        let held = stages(&mut synth, 30);
+        assert_eq!(held[8], Stage::Attack);
+        assert_eq!(held[9], Stage::Decay);
+        assert_eq!(held[18], Stage::Decay);
+        assert_eq!(held[19], Stage::Sustain);
+        assert_eq!(held[29], Stage::Sustain);
+        assert_eq!(synth.channels[0].level, LEVEL_MAX / 256 * 128);
+
+        synth.release(0);
+        assert_eq!(synth.channels[0].stage, Stage::Release);
+        let released = stages(&mut synth, 10);
+        assert_eq!(released[8], Stage::Release);
+        assert_eq!(released[9], Stage::Off);
+        assert!(!synth.is_playing(0));
+    }
+
+    #[test]
+    fn instant_envelope_is_full_from_the_first_sample() {
+        let mut synth = synth_playing(
+            Voice::new(Waveforms::SQUARE).with_envelope(Envelope::INSTANT),
+            0,
+        );
+        assert_eq!(stages(&mut synth, 2), [Stage::Decay, Stage::Sustain]);
+        assert_eq!(synth.channels[0].level, LEVEL_MAX);
+
+        // With no release it goes quiet on the next sample
+        synth.release(0);
+        assert_eq!(stages(&mut synth, 1), [Stage::Off]);
+    }
+
+    #[test]
+    fn duration_releases_the_note_by_itself() {
+        let voice = Voice::new(Waveforms::SQUARE).with_envelope(Envelope::new(0, 0, 100, 5));
+        let mut synth = synth_playing(voice, 20);
+
+        let stages = stages(&mut synth, 30);
+        assert_eq!(stages[18], Stage::Sustain);
+        assert_eq!(stages[19], Stage::Release);
+        assert_eq!(stages[24], Stage::Off);
+    }
+
+    #[test]
+    fn long_notes_do_not_overflow() {
+        let mut synth = Synth::new(SAMPLE_RATE);
+        synth.play(0, &Voice::new(Waveforms::SQUARE), 440, 1_000_000, 100);
+        assert_eq!(synth.channels[0].hold, Some(1000 * SAMPLE_RATE));
+    }
+
+    #[test]
+    fn bend_moves_the_pitch_by_hz_per_second() {
+        let voice = Voice::new(Waveforms::SINE).with_bend(-200);
+        let mut synth = Synth::new(SAMPLE_RATE);
+        synth.play(0, &voice, 1000, 0, 100);
+        for _ in 0..SAMPLE_RATE {
+            synth.sample(100);
+        }
+
+        let mut expected = Synth::new(SAMPLE_RATE);
+        expected.set_frequency(0, 800);
+        let (step, want) = (synth.channels[0].step, expected.channels[0].step);
+        assert!(
+            step.abs_diff(want) < want / 100,
+            "{step} is not about {want}"
+        );
+    }
+
+    #[test]
+    fn mixes_channels_and_clamps() {
+        let voice = Voice::new(Waveforms::SQUARE).with_envelope(Envelope::INSTANT);
+        let mut synth = Synth::new(RATE);
+        for channel in 0..CHANNELS {
+            synth.play(channel, &voice, 100, 0, 100);
+        }
+        // The first sample is at level 0 while the attack starts
+        synth.sample(100);
+        assert_eq!(synth.sample(100), 32767);
+
+        for channel in 0..CHANNELS {
+            synth.set_volume(channel, 20);
+        }
+        let quieter = synth.sample(100);
+        assert!((26_000..32_767).contains(&quieter), "{quieter}");
+        assert_eq!(synth.sample(50), quieter / 2);
+
+        synth.stop_all();
+        assert_eq!(synth.sample(100), 0);
+    }
+
+    #[test]
+    fn every_waveform_stays_in_range() {
+        for waveforms in [
+            Waveforms::SQUARE,
+            Waveforms::SAW,
+            Waveforms::TRIANGLE,
+            Waveforms::NOISE,
+            Waveforms::SINE,
+            Waveforms::SQUARE | Waveforms::SINE,
+        ] {
+            let mut channel = Channel::new();
+            channel.voice = Voice::new(waveforms);
+            let (mut low, mut high) = (i32::MAX, i32::MIN);
+            for position in (0..1 << 16).step_by(64) {
+                // Noise only changes as the phase moves on, give it a new value each time
+                channel.noise_sample = channel.noise as i16 as i32;
+                channel.noise = channel.noise.rotate_left(3) ^ position as u16;
+                let value = channel.wave((position as u32) << 16);
+                (low, high) = (low.min(value), high.max(value));
+            }
+            assert!(
+                low >= -32767 && high <= 32767,
+                "{waveforms:?} {low}..{high}"
+            );
+            assert!(
+                low < -24_000 && high > 24_000,
+                "{waveforms:?} {low}..{high}"
+            );
+        }
+    }
+}
@@ -0,0 +1,229 @@
+//! The screen backlight. Brightness is set here in perceived percent and faded smoothly by
+//! [`pwm::backlight_task`] on the PicoSystem. Being on or off is kept apart from the
+//! brightness, so turning it back on returns to where it was.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+
+#[cfg(not(feature = "simulator"))]
+pub mod pwm;
+
+/// Brightness while dimmed, in percent of the set brightness
+pub const DIM_PERCENT: u8 = 30;
+
+/// Time taken to dim once idle
+const DIM_FADE: Duration = Duration::from_millis(1000);
+
+/// Time taken to come back from dimmed, quick so a button press lights it up right away
+const UNDIM_FADE: Duration = Duration::from_millis(150);
+
+/// Time between updates while fading
+const FRAME: Duration = Duration::from_millis(10);
+
+#[derive(Copy, Clone, Debug)]
+struct Backlight {
+    /// Brightness when on and not dimmed, in percent
+    brightness: u8,
+    on: bool,
+    dimmed: bool,
+    /// Level the current fade started from
+    from: u8,
+    start: Instant,
+    duration: Duration,
+}
+
+impl Backlight {
+    /// Level once the current fade is done
+    fn target(&self) -> u8 {
+        match (self.on, self.dimmed) {
+            (false, _) => 0,
+            (true, false) => self.brightness,
+            (true, true) => (self.brightness as u32 * DIM_PERCENT as u32 / 100) as u8,
+        }
+    }
+
+    /// Level at `now`, and whether it is still fading
+    fn level_at(&self, now: Instant) -> (u8, bool) {
+        let elapsed = now.saturating_duration_since(self.start);
+        let target = self.target();
+        if elapsed >= self.duration || self.from == target {
+            return (target, false);
+        }
+
+        let done = elapsed.as_ticks() * 256 / self.duration.as_ticks();
+        let level = self.from as i64 + (target as i64 - self.from as i64) * done as i64 / 256;
+        (level as u8, true)
+    }
+}
+
+static BACKLIGHT: Mutex<CriticalSectionRawMutex, Cell<Backlight>> =
+    Mutex::new(Cell::new(Backlight {
+        brightness: 100,
+        on: false,
+        dimmed: false,
+        from: 0,
+        start: Instant::from_ticks(0),
+        duration: Duration::from_ticks(0),
+    }));
+
+/// Raised whenever the backlight is told to change, wakes the backlight task
+static CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Changes the state and fades from the level shown now to the new one over `duration`
+fn update(duration: Duration, f: impl FnOnce(&mut Backlight)) {
+    BACKLIGHT.lock(|backlight| {
+        let mut state = backlight.get();
+        let now = Instant::now();
+        state.from = state.level_at(now).0;
+        state.start = now;
+        state.duration = duration;
+        f(&mut state);
+        backlight.set(state);
+    });
+    CHANGED.signal(());
+}
+
+/// Brightness in percent it shows when on, whether it is on or not
+pub fn brightness() -> u8 {
+    BACKLIGHT.lock(|backlight| backlight.get().brightness)
+}
+
+/// Sets the brightness in percent straight away, it stays off if it is off
+pub fn set_brightness(brightness: u8) {
+    start_fade(brightness, Duration::from_ticks(0));
+}
+
+/// Fades to a brightness in percent over `duration` in the background, it stays off if it is off
+pub fn start_fade(brightness: u8, duration: Duration) {
+    update(duration, |backlight| {
+        backlight.brightness = brightness.min(100)
+    });
+}
+
+/// Fades to a brightness in percent over `duration` and returns once it is there
+pub async fn fade_to(brightness: u8, duration: Duration) {
+    start_fade(brightness, duration);
+    wait_fade().await;
+}
+
+/// Whether it is on, however dim
+pub fn is_on() -> bool {
+    BACKLIGHT.lock(|backlight| backlight.get().on)
+}
+
+/// Fades in to the brightness over `duration`
+pub fn turn_on(duration: Duration) {
+    update(duration, |backlight| backlight.on = true);
+}
+
+/// Fades out over `duration`, the brightness is kept for turning it back on
+pub fn turn_off(duration: Duration) {
+    update(duration, |backlight| backlight.on = false);
+}
+
+/// Turns it off if it is on and on if it is off, straight away
+pub fn toggle() {
+    update(Duration::from_ticks(0), |backlight| {
+        backlight.on = !backlight.on
+    });
+}
+
+/// Fades down to [`DIM_PERCENT`] of the brightness, or back up, for when nobody is playing
+pub fn set_dimmed(dimmed: bool) {
+    let state = BACKLIGHT.lock(|backlight| backlight.get());
+    if state.dimmed != dimmed {
+        let duration = if dimmed { DIM_FADE } else { UNDIM_FADE };
+        update(duration, |backlight| backlight.dimmed = dimmed);
+    }
+}
+
+/// Level in percent shown at `now`, and whether a fade is still going
+pub fn level_at(now: Instant) -> (u8, bool) {
+    BACKLIGHT.lock(|backlight| backlight.get().level_at(now))
+}
+
+/// Waits until the backlight is told to change
+pub async fn wait_change() {
+    CHANGED.wait().await
+}
+
+/// Returns once the current fade is done
+pub async fn wait_fade() {
+    while level_at(Instant::now()).1 {
+        Timer::after(FRAME).await;
+    }
+}
+
+/// PWM duty out of 65535 for a level in percent, squared so steps look even to the eye
+pub fn gamma(level: u8) -> u16 {
+    let level = level.min(100) as u32;
+    (level * level * 65535 / (100 * 100)) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> Backlight {
+        BACKLIGHT.lock(|backlight| backlight.get())
+    }
+
+    #[test]
+    fn on_and_brightness_are_kept_apart() {
+        turn_off(Duration::from_ticks(0));
+        set_brightness(60);
+        assert!(!is_on());
+        assert_eq!(state().target(), 0);
+
+        turn_on(Duration::from_ticks(0));
+        assert_eq!(level_at(Instant::now()), (60, false));
+        toggle();
+        assert!(!is_on());
+        toggle();
+        assert_eq!(brightness(), 60);
+        assert_eq!(state().target(), 60);
+
+        set_brightness(100);
+        set_dimmed(true);
+        assert_eq!(state().target(), DIM_PERCENT);
+        assert_eq!(level_at(Instant::now() + DIM_FADE), (DIM_PERCENT, false));
+        set_dimmed(false);
+        assert_eq!(state().target(), 100);
+        turn_off(Duration::from_ticks(0));
+    }
+
+    #[test]
+    fn fades_from_where_it_was() {
+        let start = Instant::from_millis(1000);
+        let backlight = Backlight {
+            brightness: 80,
+            on: true,
+            dimmed: false,
+            from: 20,
+            start,
+            duration: Duration::from_millis(100),
+        };
+        assert_eq!(backlight.level_at(start), (20, true));
+        assert_eq!(
+            backlight.level_at(start + Duration::from_millis(50)),
+            (50, true)
+        );
+        assert!(backlight.level_at(start + Duration::from_millis(99)).1);
+        assert_eq!(
+            backlight.level_at(start + Duration::from_millis(100)),
+            (80, false)
+        );
+    }
+
+    #[test]
+    fn gamma_spans_the_whole_duty_range() {
+        assert_eq!(gamma(0), 0);
+        assert_eq!(gamma(50), 16383);
+        assert_eq!(gamma(100), 65535);
+        assert_eq!(gamma(150), 65535);
+    }
+}
@@ -0,0 +1,23 @@
+//! The backlight on the PicoSystem, GPIO 12 on PWM slice 6 channel A.
+
+use embassy_futures::select::select;
+use embassy_rp::pwm::{PwmOutput, SetDutyCycle};
+use embassy_time::{Instant, Timer};
+
+use super::{gamma, level_at, wait_change, FRAME};
+
+/// Shows the backlight level set through [`backlight`](super) and runs its fades, spawn it once
+#[embassy_executor::task]
+pub async fn backlight_task(mut output: PwmOutput<'static>) -> ! {
+    loop {
+        let (level, fading) = level_at(Instant::now());
+        let max = output.max_duty_cycle() as u32;
+        let _ = output.set_duty_cycle((gamma(level) as u32 * max / 65535) as u16);
+        match fading {
+            true => {
+                select(wait_change(), Timer::after(FRAME)).await;
+            }
+            false => wait_change().await,
+        }
+    }
+}
@@ -0,0 +1,508 @@
+//! A text console for debugging without a probe: log output and a small command shell.
+//! [`usb`] carries it over USB CDC-ACM on the PicoSystem, show it with any serial terminal.
+//!
+//! Logging goes through the `log` crate, so `log::info!` and friends end up here once
+//! [`init_logger`] ran. defmt output still goes to the probe.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::pipe::Pipe;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration};
+use embedded_graphics::pixelcolor::{Rgb565, Rgb888};
+use embedded_graphics::prelude::*;
+
+use crate::display::framebuffer::Framebuffer;
+use crate::engine::game;
+use crate::input::replay::{self, SLOT_CAPACITY};
+use crate::{backlight, power, WIDTH};
+
+#[cfg(not(feature = "simulator"))]
+pub mod usb;
+
+/// Bytes waiting to go out, log lines that do not fit are cut short
+pub const OUTPUT_CAPACITY: usize = 1024;
+
+/// Longest command line, longer ones are cut short
+pub const LINE_CAPACITY: usize = 64;
+
+/// Log bytes held back while binary data goes out, later ones are dropped
+pub const HELD_CAPACITY: usize = 512;
+
+/// Time a frame dump waits for a frame, or for the host to take more bytes, before giving up
+const DUMP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Everything written to the console, read by whatever carries it
+static OUTPUT: Pipe<CriticalSectionRawMutex, OUTPUT_CAPACITY> = Pipe::new();
+
+/// Log lines written while binary data goes out, sent once it is over
+static HELD: Pipe<CriticalSectionRawMutex, HELD_CAPACITY> = Pipe::new();
+
+/// Set while binary data goes out, so log lines do not end up in the middle of it
+static HOLDING: AtomicBool = AtomicBool::new(false);
+
+/// Reads the next bytes written to the console
+pub async fn read_output(buf: &mut [u8]) -> usize {
+    OUTPUT.read(buf).await
+}
+
+/// Drops everything not read yet, for when nobody is listening
+pub fn clear_output() {
+    OUTPUT.clear();
+}
+
+///
+/// Writes text to the console without waiting, dropping what does not fit.
+/// Line ends become `\r\n` for terminals.
+///
+pub struct Output;
+
+impl Write for Output {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_lines(&OUTPUT, s);
+        Ok(())
+    }
+}
+
+/// Like [`Output`], but into [`HELD`]
+struct Held;
+
+impl Write for Held {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_lines(&HELD, s);
+        Ok(())
+    }
+}
+
+/// Writes `s` to `pipe` with `\r\n` line ends, dropping what does not fit
+fn write_lines<const N: usize>(pipe: &Pipe<CriticalSectionRawMutex, N>, s: &str) {
+    for (i, line) in s.split('\n').enumerate() {
+        if i > 0 {
+            let _ = pipe.try_write(b"\r\n");
+        }
+        let _ = pipe.try_write(line.as_bytes());
+    }
+}
+
+///
+/// Keeps log lines out of the output while `send` puts binary data there,
+/// then sends the ones held back meanwhile.
+///
+async fn holding_logs<F: core::future::Future>(send: F) -> F::Output {
+    HOLDING.store(true, Ordering::Relaxed);
+    let result = send.await;
+    HOLDING.store(false, Ordering::Relaxed);
+
+    let mut held = [0; HELD_CAPACITY];
+    while let Ok(len) = HELD.try_read(&mut held) {
+        let _ = OUTPUT.try_write(&held[..len]);
+    }
+    result
+}
+
+struct Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let _ = match HOLDING.load(Ordering::Relaxed) {
+            true => writeln!(Held, "[{}] {}", record.level(), record.args()),
+            false => writeln!(Output, "[{}] {}", record.level(), record.args()),
+        };
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Logger = Logger;
+
+/// Sends `log` records at or above `level` to the console, call it once at startup
+pub fn init_logger(level: log::LevelFilter) {
+    // Safety: called once before anything logs, thumbv6m has no atomics for `set_logger`
+    unsafe {
+        let _ = log::set_logger_racy(&LOGGER);
+        log::set_max_level_racy(level);
+    }
+}
+
+///
+/// Collects typed characters into a line, echoing them back and handling backspace.
+///
+#[derive(Default)]
+pub struct LineEditor {
+    line: heapless::String<LINE_CAPACITY>,
+}
+
+impl LineEditor {
+    pub const fn new() -> Self {
+        Self {
+            line: heapless::String::new(),
+        }
+    }
+
+    ///
+    /// Takes one received byte, returns the line once enter is pressed
+    ///
+    /// # Arguments
+    ///
+    /// * `byte` - the byte received
+    /// * `echo` - where to show what was typed
+    ///
+    pub fn push(&mut self, byte: u8, echo: &mut impl Write) -> Option<&str> {
+        match byte {
+            b'\r' | b'\n' => {
+                let _ = echo.write_str("\n");
+                return Some(&self.line);
+            }
+            // Backspace and delete both rub out the last character
+            0x08 | 0x7f if self.line.pop().is_some() => {
+                let _ = echo.write_str("\x08 \x08");
+            }
+            // Anything typed past the end of a full line is dropped
+            byte if (byte.is_ascii_graphic() || byte == b' ')
+                && self.line.push(byte as char).is_ok() =>
+            {
+                let _ = echo.write_char(byte as char);
+            }
+            _ => {}
+        }
+        None
+    }
+
+    /// Starts the next line
+    pub fn clear(&mut self) {
+        self.line.clear();
+    }
+}
+
+/// Shown before each command
+pub const PROMPT: &str = "> ";
+
+/// A command typed into the console.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Command {
+    Help,
+    /// Frame timing of the running game
+    Fps,
+    Battery,
+    /// Shows the backlight brightness, or sets it in percent
+    Brightness(Option<u8>),
+    /// Reboots into the USB bootloader, to flash new firmware
+    Bootsel,
+    /// Sends the frame on screen as a binary PPM image
+    Dump,
+    /// Sends the kept button recording
+    Save,
+    /// Takes this many bytes that follow as the kept button recording
+    Load(usize),
+}
+
+impl Command {
+    /// Reads a command line, None for a blank one
+    pub fn parse(line: &str) -> Result<Option<Self>, &'static str> {
+        let mut words = line.split_whitespace();
+        let Some(name) = words.next() else {
+            return Ok(None);
+        };
+
+        let command = match name {
+            "help" | "?" => Command::Help,
+            "fps" => Command::Fps,
+            "battery" => Command::Battery,
+            "brightness" => match words.next() {
+                None => Command::Brightness(None),
+                Some(level) => match level.parse::<u8>() {
+                    Ok(level) if level <= 100 => Command::Brightness(Some(level)),
+                    _ => return Err("brightness takes a percentage, 0 to 100"),
+                },
+            },
+            "bootsel" => Command::Bootsel,
+            "dump" => Command::Dump,
+            "save" => Command::Save,
+            "load" => match words.next().map(str::parse::<usize>) {
+                Some(Ok(len)) if (1..=SLOT_CAPACITY).contains(&len) => Command::Load(len),
+                _ => return Err("load takes the recording's length in bytes"),
+            },
+            _ => return Err("unknown command, try help"),
+        };
+
+        match words.next() {
+            Some(_) => Err("too many arguments"),
+            None => Ok(Some(command)),
+        }
+    }
+}
+
+///
+/// Runs the commands that only report or change state, replying to `out`.
+/// [`Command::Bootsel`], [`Command::Dump`], [`Command::Save`] and [`Command::Load`] need the
+/// hardware or the connection and are left to the caller.
+///
+pub fn run(command: Command, out: &mut impl Write) -> fmt::Result {
+    match command {
+        Command::Help => writeln!(
+            out,
+            "help                 this list\n\
+             fps                  frame timing\n\
+             battery              battery and charging\n\
+             brightness [0-100]   show or set the backlight\n\
+             bootsel              reboot into the USB bootloader\n\
+             dump                 send the screen as a PPM image\n\
+             save                 send the kept button recording\n\
+             load <bytes>         receive a button recording"
+        ),
+        Command::Fps => {
+            let stats = game::stats();
+            writeln!(
+                out,
+                "{:.1} fps, last {}ms, worst {}ms, {} frames, {} updates dropped",
+                stats.fps(),
+                stats.last.as_millis(),
+                stats.worst.as_millis(),
+                stats.frames,
+                stats.dropped
+            )
+        }
+        Command::Battery => {
+            let status = power::current();
+            writeln!(
+                out,
+                "{}% ({}mV), {:?}{}",
+                status.percent,
+                status.millivolts,
+                status.charge,
+                if status.usb { ", on USB power" } else { "" }
+            )
+        }
+        Command::Brightness(Some(level)) => {
+            backlight::set_brightness(level);
+            writeln!(out, "brightness {}%", level)
+        }
+        Command::Brightness(None) => writeln!(
+            out,
+            "brightness {}%, {}",
+            backlight::brightness(),
+            if backlight::is_on() { "on" } else { "off" }
+        ),
+        Command::Bootsel | Command::Dump | Command::Save | Command::Load(_) => Ok(()),
+    }
+}
+
+/// Asked for by the dump command, taken by whoever sends frames to the screen
+static DUMP: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// The frame shown after a dump was asked for, lent to the console to send
+static FRAMES_TO_DUMP: Channel<CriticalSectionRawMutex, Framebuffer<'static>, 1> = Channel::new();
+
+/// Lent frames given back once they are sent
+static DUMPED_FRAMES: Channel<CriticalSectionRawMutex, Framebuffer<'static>, 1> = Channel::new();
+
+/// True once after a dump was asked for, the next frame shown then goes to [`lend_for_dump`]
+pub fn take_dump_request() -> bool {
+    DUMP.try_take().is_some()
+}
+
+/// Lends the frame just shown to the console for a dump, [`dumped_frame`] gives it back
+pub async fn lend_for_dump(frame: Framebuffer<'static>) {
+    FRAMES_TO_DUMP.send(frame).await;
+}
+
+/// Waits for the frame lent by [`lend_for_dump`] to be sent
+pub async fn dumped_frame() -> Framebuffer<'static> {
+    DUMPED_FRAMES.receive().await
+}
+
+///
+/// Asks for the next frame shown and sends it as a binary PPM image, after a line
+/// saying how many bytes follow. Returns false if no frame was shown or the host
+/// stopped taking bytes.
+///
+pub async fn dump() -> bool {
+    DUMP.signal(());
+    let frame = match with_timeout(DUMP_TIMEOUT, FRAMES_TO_DUMP.receive()).await {
+        Ok(frame) => frame,
+        Err(_) => {
+            DUMP.reset();
+            // The frame may have been lent just as the wait ran out
+            match FRAMES_TO_DUMP.try_receive() {
+                Ok(frame) => frame,
+                Err(_) => {
+                    let _ = writeln!(Output, "no frame shown, is the screen asleep?");
+                    return false;
+                }
+            }
+        }
+    };
+
+    let sent = holding_logs(send_frame(&frame)).await;
+    DUMPED_FRAMES.send(frame).await;
+    if !sent {
+        log::warn!("frame dump stalled, the terminal stopped reading");
+    }
+    sent
+}
+
+/// Sends `frame` as a PPM image, false if the host stopped taking bytes
+async fn send_frame(frame: &Framebuffer<'_>) -> bool {
+    let Size { width, height } = frame.size();
+    let mut header = heapless::String::<32>::new();
+    let _ = write!(header, "P6\n{} {}\n255\n", width, height);
+    let _ = writeln!(
+        Output,
+        "frame: {} bytes of PPM image follow",
+        header.len() + (width * height * 3) as usize
+    );
+
+    if !write_all(header.as_bytes()).await {
+        return false;
+    }
+    let mut row = [0u8; WIDTH * 3];
+    for y in 0..height as i32 {
+        for (x, rgb) in row.chunks_exact_mut(3).take(width as usize).enumerate() {
+            let color = frame
+                .pixel(Point::new(x as i32, y))
+                .unwrap_or(Rgb565::BLACK);
+            let color = Rgb888::from(color);
+            rgb.copy_from_slice(&[color.r(), color.g(), color.b()]);
+        }
+        if !write_all(&row[..width as usize * 3]).await {
+            return false;
+        }
+    }
+    let _ = writeln!(Output);
+    true
+}
+
+/// Sends the recording kept by [`replay::store`], after a line saying how many bytes follow.
+/// Returns false if there is none or the host stopped taking bytes.
+pub async fn send_recording() -> bool {
+    let mut recording = [0u8; SLOT_CAPACITY];
+    let Some(len) = replay::load(&mut recording) else {
+        let _ = writeln!(Output, "no recording kept");
+        return false;
+    };
+    let _ = writeln!(Output, "recording: {} bytes follow", len);
+    let sent = holding_logs(write_all(&recording[..len])).await;
+    let _ = writeln!(Output);
+    sent
+}
+
+///
+/// Collects the raw bytes of a recording sent after a load command.
+///
+pub struct Upload {
+    expected: usize,
+    data: heapless::Vec<u8, SLOT_CAPACITY>,
+}
+
+impl Upload {
+    /// Waits for `expected` bytes, at most [`SLOT_CAPACITY`]
+    pub fn new(expected: usize) -> Self {
+        Self {
+            expected: expected.min(SLOT_CAPACITY),
+            data: heapless::Vec::new(),
+        }
+    }
+
+    /// Takes the next byte received, returns true once all of them are in
+    pub fn push(&mut self, byte: u8) -> bool {
+        let _ = self.data.push(byte);
+        self.data.len() >= self.expected
+    }
+
+    /// Keeps what was received as the recording, replying whether it was one
+    pub fn finish(&self, out: &mut impl Write) -> fmt::Result {
+        match replay::store(&self.data) {
+            true => writeln!(out, "recording of {} bytes loaded", self.data.len()),
+            false => writeln!(out, "not a recording"),
+        }
+    }
+}
+
+/// Waits for room for all of `bytes`, false if it took longer than [`DUMP_TIMEOUT`]
+async fn write_all(bytes: &[u8]) -> bool {
+    with_timeout(DUMP_TIMEOUT, OUTPUT.write_all(bytes))
+        .await
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::replay::Recorder;
+    use crate::input::Buttons;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(Command::parse("  "), Ok(None));
+        assert_eq!(
+            Command::parse("brightness 40"),
+            Ok(Some(Command::Brightness(Some(40))))
+        );
+        assert!(Command::parse("brightness 140").is_err());
+        assert_eq!(Command::parse("load 20"), Ok(Some(Command::Load(20))));
+        assert!(Command::parse("load").is_err());
+        assert!(Command::parse("load 0").is_err());
+        assert!(Command::parse("save now").is_err());
+    }
+
+    #[test]
+    fn uploads_a_recording() {
+        let mut buffer = [0; 32];
+        let mut recorder = Recorder::new(&mut buffer, 7).unwrap();
+        recorder.record(Buttons(1));
+        let recording = recorder.finish();
+
+        let mut upload = Upload::new(recording.len());
+        let (last, rest) = recording.split_last().unwrap();
+        assert!(rest.iter().all(|&byte| !upload.push(byte)));
+        assert!(upload.push(*last));
+        let mut reply = String::new();
+        upload.finish(&mut reply).unwrap();
+        assert_eq!(
+            reply,
+            format!("recording of {} bytes loaded\n", recording.len())
+        );
+
+        let mut loaded = [0; SLOT_CAPACITY];
+        let len = replay::load(&mut loaded).unwrap();
+        assert_eq!(&loaded[..len], recording);
+
+        let mut junk = Upload::new(3);
+        for byte in [1, 2, 3] {
+            junk.push(byte);
+        }
+        reply.clear();
+        junk.finish(&mut reply).unwrap();
+        assert_eq!(reply, "not a recording\n");
+    }
+
+    #[test]
+    fn holds_log_lines_until_binary_data_is_out() {
+        use log::Log;
+
+        clear_output();
+        embassy_futures::block_on(holding_logs(async {
+            LOGGER.log(
+                &log::Record::builder()
+                    .level(log::Level::Info)
+                    .args(format_args!("held"))
+                    .build(),
+            );
+            OUTPUT.write_all(b"P6").await;
+        }));
+
+        let mut output = Vec::new();
+        let mut buf = [0; 16];
+        while let Ok(len) = OUTPUT.try_read(&mut buf) {
+            output.extend_from_slice(&buf[..len]);
+        }
+        assert_eq!(output, b"P6[INFO] held\r\n");
+    }
+}
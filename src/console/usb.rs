@@ -0,0 +1,161 @@
+//! The console over USB: a CDC-ACM serial port, so it shows up as /dev/ttyACM0 or a COM port.
+//!
+//! This is not `embassy-usb-logger`: that only carries log lines, and it is built against
+//! an older `embassy-usb` than the one here, so its class cannot share this USB device
+//! with the command shell.
+
+use core::fmt::Write;
+
+use embassy_executor::Spawner;
+use embassy_futures::select::select;
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::{Driver, InterruptHandler};
+use embassy_rp::{bind_interrupts, rom_data};
+use embassy_time::{Duration, Timer};
+use embassy_usb::class::cdc_acm::{CdcAcmClass, Receiver, Sender, State};
+use embassy_usb::driver::EndpointError;
+use embassy_usb::{Builder, Config, UsbDevice};
+use static_cell::StaticCell;
+
+use super::{dump, read_output, run, send_recording, Command, LineEditor, Output, Upload, PROMPT};
+
+bind_interrupts!(struct Irqs {
+    USBCTRL_IRQ => InterruptHandler<USB>;
+});
+
+type UsbDriver = Driver<'static, USB>;
+
+/// Largest packet on the CDC endpoints, the most full speed USB allows
+const PACKET_SIZE: u16 = 64;
+
+///
+/// Starts the USB device and the console on it
+///
+/// # Arguments
+///
+/// * `spawner` - spawns the USB and console tasks
+/// * `usb` - the USB peripheral
+///
+pub fn init(spawner: Spawner, usb: USB) {
+    static CONFIG_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static BOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
+    static STATE: StaticCell<State> = StaticCell::new();
+
+    // One of pid.codes' test IDs, for development only. Firmware handed out to others needs
+    // a PID of its own requested from pid.codes.
+    let mut config = Config::new(0x1209, 0x0001);
+    config.manufacturer = Some("PicoSystem-rs");
+    config.product = Some("PicoSystem");
+    config.max_power = 100;
+    config.max_packet_size_0 = 64;
+    // Interface association descriptors, without them Windows does not load its CDC driver
+    config.device_class = 0xef;
+    config.device_sub_class = 0x02;
+    config.device_protocol = 0x01;
+    config.composite_with_iads = true;
+
+    let mut builder = Builder::new(
+        Driver::new(usb, Irqs),
+        config,
+        CONFIG_DESCRIPTOR.init([0; 256]),
+        BOS_DESCRIPTOR.init([0; 256]),
+        &mut [],
+        CONTROL_BUF.init([0; 64]),
+    );
+    let class = CdcAcmClass::new(&mut builder, STATE.init(State::new()), PACKET_SIZE);
+
+    spawner.spawn(usb_task(builder.build())).unwrap();
+    spawner.spawn(console_task(class)).unwrap();
+}
+
+/// Answers the host, everything on USB stops without it
+#[embassy_executor::task]
+async fn usb_task(mut device: UsbDevice<'static, UsbDriver>) -> ! {
+    device.run().await
+}
+
+/// Runs the console whenever a terminal has the port open
+#[embassy_executor::task]
+async fn console_task(class: CdcAcmClass<'static, UsbDriver>) -> ! {
+    let (mut sender, mut receiver) = class.split();
+    loop {
+        // Output piles up with nobody reading it, so throw it away until somebody is
+        let mut discard = [0; PACKET_SIZE as usize];
+        let discard_output = async {
+            loop {
+                read_output(&mut discard).await;
+            }
+        };
+        select(receiver.wait_connection(), discard_output).await;
+
+        let _ = writeln!(Output, "PicoSystem console, type help for the commands");
+        let _ = write!(Output, "{}", PROMPT);
+        // Both only return once the terminal goes away
+        let _ = select(send(&mut sender), receive(&mut receiver)).await;
+    }
+}
+
+/// Sends console output until the terminal goes away
+async fn send(sender: &mut Sender<'static, UsbDriver>) -> Result<(), EndpointError> {
+    let mut packet = [0; PACKET_SIZE as usize];
+    loop {
+        let len = read_output(&mut packet).await;
+        sender.write_packet(&packet[..len]).await?;
+    }
+}
+
+/// Reads typed lines and runs them until the terminal goes away
+async fn receive(receiver: &mut Receiver<'static, UsbDriver>) -> Result<(), EndpointError> {
+    let mut packet = [0; PACKET_SIZE as usize];
+    let mut editor = LineEditor::new();
+    // Bytes after a load command are the recording, not typing
+    let mut upload: Option<Upload> = None;
+    loop {
+        let len = receiver.read_packet(&mut packet).await?;
+        for &byte in &packet[..len] {
+            if let Some(receiving) = &mut upload {
+                if receiving.push(byte) {
+                    let _ = receiving.finish(&mut Output);
+                    let _ = write!(Output, "{}", PROMPT);
+                    upload = None;
+                }
+                continue;
+            }
+
+            let Some(line) = editor.push(byte, &mut Output) else {
+                continue;
+            };
+            let command = Command::parse(line);
+            editor.clear();
+
+            match command {
+                Ok(Some(Command::Bootsel)) => {
+                    let _ = writeln!(Output, "rebooting into the bootloader");
+                    // Gives the reply a moment to go out
+                    Timer::after(Duration::from_millis(100)).await;
+                    rom_data::reset_to_usb_boot(0, 0);
+                }
+                Ok(Some(Command::Dump)) => {
+                    dump().await;
+                }
+                Ok(Some(Command::Save)) => {
+                    send_recording().await;
+                }
+                Ok(Some(Command::Load(len))) => {
+                    let _ = writeln!(Output, "send {} bytes", len);
+                    upload = Some(Upload::new(len));
+                    continue;
+                }
+                Ok(Some(command)) => {
+                    let _ = run(command, &mut Output);
+                }
+                Ok(None) => {}
+                Err(message) => {
+                    let _ = writeln!(Output, "{}", message);
+                }
+            }
+            let _ = write!(Output, "{}", PROMPT);
+        }
+    }
+}
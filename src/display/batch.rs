@@ -10,6 +10,7 @@ use embedded_graphics_core::{
 };
 use embedded_hal_1::digital::OutputPin;
 
+#[allow(async_fn_in_trait)]
 pub trait DrawBatch<DI, RST, T, PinE>
 where
     DI: AsyncWriteOnlyDataCommand,
@@ -0,0 +1,77 @@
+//! How a drawn color combines with the pixel already in the framebuffer.
+
+use embedded_graphics_core::pixelcolor::raw::{RawData, RawU16};
+use embedded_graphics_core::pixelcolor::{Rgb565, RgbColor};
+
+///
+/// Blend mode used by the framebuffer for every draw.
+///
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum BlendMode {
+    /// Drawn color replaces the pixel
+    #[default]
+    Replace,
+    /// Drawn color is XORed into the pixel, drawing it twice restores the original
+    Xor,
+    /// Channels are added together, clamped at full brightness
+    Add,
+    /// Drawn color is mixed over the pixel, 255 is opaque and 0 leaves the pixel as is
+    Alpha(u8),
+    /// Like Replace, except pixels of this color are transparent and leave the pixel as is
+    ColorKey(Rgb565),
+}
+
+///
+/// Draw targets that can blend what is drawn with the pixels they already hold.
+///
+pub trait Blend {
+    /// How drawn colors combine with what is already there
+    fn blend_mode(&self) -> BlendMode;
+
+    /// Sets how everything drawn from now on combines with what is already there
+    fn set_blend_mode(&mut self, blend_mode: BlendMode);
+}
+
+impl BlendMode {
+    ///
+    /// Blends `src` onto `dst`, both in the framebuffer's wire byte order.
+    /// Returns None when the pixel should be left alone.
+    ///
+    /// # Arguments
+    ///
+    /// * `dst` - Pixel currently in the framebuffer
+    /// * `src` - Color being drawn
+    ///
+    pub fn apply(self, dst: u16, src: Rgb565) -> Option<u16> {
+        let raw = |color: Rgb565| RawU16::from(color).into_inner().to_be();
+        let dst_color = || Rgb565::from(RawU16::new(u16::from_be(dst)));
+
+        match self {
+            BlendMode::Replace => Some(raw(src)),
+            BlendMode::Xor => Some(dst ^ raw(src)),
+            BlendMode::Add => {
+                let dst = dst_color();
+                Some(raw(Rgb565::new(
+                    (dst.r() + src.r()).min(Rgb565::MAX_R),
+                    (dst.g() + src.g()).min(Rgb565::MAX_G),
+                    (dst.b() + src.b()).min(Rgb565::MAX_B),
+                )))
+            }
+            BlendMode::Alpha(0) => None,
+            BlendMode::Alpha(255) => Some(raw(src)),
+            BlendMode::Alpha(alpha) => {
+                let dst = dst_color();
+                let mix = |s: u8, d: u8| {
+                    ((s as u16 * alpha as u16 + d as u16 * (255 - alpha) as u16) / 255) as u8
+                };
+                Some(raw(Rgb565::new(
+                    mix(src.r(), dst.r()),
+                    mix(src.g(), dst.g()),
+                    mix(src.b(), dst.b()),
+                )))
+            }
+            BlendMode::ColorKey(key) if src == key => None,
+            BlendMode::ColorKey(_) => Some(raw(src)),
+        }
+    }
+}
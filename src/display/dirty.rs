@@ -0,0 +1,188 @@
+//! Tracks which parts of a frame changed since it was last presented, so only those get sent.
+
+use embedded_graphics_core::prelude::Point;
+use embedded_graphics_core::primitives::Rectangle;
+
+/// Max number of separate regions, any more get merged together
+const MAX_REGIONS: usize = 8;
+
+/// Non overlapping rectangles covering every pixel drawn since the last present
+#[derive(Debug, Clone, Default)]
+pub struct DirtyRegions {
+    regions: heapless::Vec<Rectangle, MAX_REGIONS>,
+}
+
+impl DirtyRegions {
+    pub const fn new() -> Self {
+        Self {
+            regions: heapless::Vec::new(),
+        }
+    }
+
+    /// Marks `area` as changed, merging it with the regions it touches
+    pub fn add(&mut self, area: Rectangle) {
+        if area.is_zero_sized() {
+            return;
+        }
+
+        let mut area = area;
+        loop {
+            // Swallow every region the new one overlaps or borders on
+            while let Some(index) = self.regions.iter().position(|r| touches(r, &area)) {
+                area = envelope(&self.regions.swap_remove(index), &area);
+            }
+
+            match self.regions.push(area) {
+                Ok(()) => return,
+                // Full, so merge with the region that grows the least and try again
+                Err(full) => {
+                    let index = (0..self.regions.len())
+                        .min_by_key(|&i| {
+                            let region = &self.regions[i];
+                            area_of(&envelope(region, &full)) - area_of(region)
+                        })
+                        .unwrap_or_default();
+                    area = envelope(&self.regions.swap_remove(index), &full);
+                }
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.regions.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Rectangle> {
+        self.regions.iter()
+    }
+}
+
+/// True if the rectangles overlap or share an edge
+fn touches(a: &Rectangle, b: &Rectangle) -> bool {
+    !a.offset(1).intersection(b).is_zero_sized()
+}
+
+/// Smallest rectangle containing both, neither may be zero sized
+fn envelope(a: &Rectangle, b: &Rectangle) -> Rectangle {
+    let bottom_right = |r: &Rectangle| r.bottom_right().unwrap_or(r.top_left);
+    Rectangle::with_corners(
+        a.top_left.component_min(b.top_left),
+        bottom_right(a).component_max(bottom_right(b)),
+    )
+}
+
+fn area_of(r: &Rectangle) -> u32 {
+    r.size.width * r.size.height
+}
+
+/// Grows a bounding box one point at a time, for tracking what scattered pixel draws touched
+#[derive(Default)]
+pub struct Bounds(Option<(Point, Point)>);
+
+impl Bounds {
+    pub fn include(&mut self, point: Point) {
+        self.0 = Some(match self.0 {
+            None => (point, point),
+            Some((min, max)) => (min.component_min(point), max.component_max(point)),
+        });
+    }
+
+    pub fn rectangle(&self) -> Option<Rectangle> {
+        self.0.map(|(min, max)| Rectangle::with_corners(min, max))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_graphics_core::prelude::Size;
+
+    fn regions(dirty: &DirtyRegions) -> Vec<Rectangle> {
+        dirty.iter().copied().collect()
+    }
+
+    #[test]
+    fn ignores_zero_sized_areas() {
+        let mut dirty = DirtyRegions::new();
+        dirty.add(Rectangle::new(Point::new(3, 3), Size::new(0, 5)));
+        assert!(dirty.is_empty());
+    }
+
+    #[test]
+    fn merges_touching_areas_into_their_envelope() {
+        let mut dirty = DirtyRegions::new();
+        dirty.add(Rectangle::new(Point::new(0, 0), Size::new(4, 4)));
+        dirty.add(Rectangle::new(Point::new(4, 2), Size::new(4, 4)));
+        assert_eq!(
+            regions(&dirty),
+            [Rectangle::new(Point::new(0, 0), Size::new(8, 6))]
+        );
+    }
+
+    #[test]
+    fn keeps_disjoint_areas_apart() {
+        let mut dirty = DirtyRegions::new();
+        let a = Rectangle::new(Point::new(0, 0), Size::new(4, 4));
+        let b = Rectangle::new(Point::new(10, 0), Size::new(4, 4));
+        dirty.add(a);
+        dirty.add(b);
+        assert_eq!(regions(&dirty), [a, b]);
+
+        dirty.clear();
+        assert!(dirty.is_empty());
+    }
+
+    #[test]
+    fn merges_where_it_grows_least_once_full() {
+        let mut dirty = DirtyRegions::new();
+        // Eight pixels spread along a row, the ninth lands right next to the last one
+        let mut points: Vec<Point> = (0..8).map(|i| Point::new(i * 10, 0)).collect();
+        points.push(Point::new(72, 0));
+        for &point in &points {
+            dirty.add(Rectangle::new(point, Size::new(1, 1)));
+        }
+
+        let regions = regions(&dirty);
+        assert_eq!(regions.len(), MAX_REGIONS);
+        assert!(regions.contains(&Rectangle::new(Point::new(70, 0), Size::new(3, 1))));
+        for (i, a) in regions.iter().enumerate() {
+            for b in &regions[i + 1..] {
+                assert!(
+                    a.intersection(b).is_zero_sized(),
+                    "{:?} overlaps {:?}",
+                    a,
+                    b
+                );
+            }
+        }
+        for point in points {
+            assert!(
+                regions.iter().any(|r| r.contains(point)),
+                "{:?} lost",
+                point
+            );
+        }
+    }
+
+    #[test]
+    fn bounds_grow_to_cover_every_point() {
+        let mut bounds = Bounds::default();
+        assert_eq!(bounds.rectangle(), None);
+
+        bounds.include(Point::new(5, 2));
+        assert_eq!(
+            bounds.rectangle(),
+            Some(Rectangle::new(Point::new(5, 2), Size::new(1, 1)))
+        );
+        bounds.include(Point::new(1, 7));
+        bounds.include(Point::new(3, 4));
+        assert_eq!(
+            bounds.rectangle(),
+            Some(Rectangle::new(Point::new(1, 2), Size::new(5, 6)))
+        );
+    }
+}
@@ -0,0 +1,506 @@
+use core::convert::Infallible;
+
+use byte_slice_cast::AsByteSlice;
+use embedded_graphics_core::pixelcolor::Rgb565;
+use embedded_graphics_core::prelude::{Dimensions, DrawTarget, OriginDimensions, Point, Size};
+use embedded_graphics_core::{pixelcolor::raw::RawU16, primitives::Rectangle, Pixel};
+
+use crate::display::blend::{Blend, BlendMode};
+use crate::display::dirty::{Bounds, DirtyRegions};
+use crate::display::graphics::{HEIGHT, WIDTH};
+
+/// Storage for one frame, each pixel already in the byte order the display expects
+pub type Pixels = [u16; WIDTH * HEIGHT];
+
+/// Storage for one pixel doubled frame
+pub type LowResPixels = [u16; Resolution::Doubled.pixels()];
+
+/// Largest number of bytes one scaled row pair takes, see [`Framebuffer::doubled_row`]
+pub const DOUBLED_LINE_BYTES: usize = WIDTH * 2 * 2;
+
+///
+/// How many pixels a frame has, the panel always shows 240x240.
+///
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Resolution {
+    /// 240x240, one framebuffer pixel per panel pixel
+    #[default]
+    Full,
+    /// 120x120, each framebuffer pixel is shown as 2x2 on the panel. Like the C++ SDK's
+    /// pixel doubled mode, it takes a quarter of the memory and drawing time.
+    Doubled,
+}
+
+impl Resolution {
+    /// Width and height of the frame in pixels
+    pub const fn side(self) -> usize {
+        match self {
+            Resolution::Full => WIDTH,
+            Resolution::Doubled => WIDTH / 2,
+        }
+    }
+
+    /// Number of pixels in the frame
+    pub const fn pixels(self) -> usize {
+        self.side() * self.side()
+    }
+}
+
+/// Frames of different resolutions cannot be copied into each other
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ResolutionMismatch;
+
+///
+/// A frame to draw into and present.
+/// It holds its storage exclusively, so nothing can draw into a frame while the display sends it.
+///
+pub struct Framebuffer<'a> {
+    pixels: &'a mut [u16],
+    resolution: Resolution,
+    dirty: DirtyRegions,
+    blend_mode: BlendMode,
+}
+
+impl<'a> Framebuffer<'a> {
+    /// Wraps `pixels`, the whole frame counts as changed until it is first presented
+    pub fn new(pixels: &'a mut Pixels) -> Self {
+        Self::with_resolution(pixels, Resolution::Full)
+    }
+
+    /// Wraps `pixels` as a 120x120 frame shown pixel doubled
+    pub fn doubled(pixels: &'a mut LowResPixels) -> Self {
+        Self::with_resolution(pixels, Resolution::Doubled)
+    }
+
+    ///
+    /// Wraps `pixels` as a frame of the given resolution
+    ///
+    /// # Arguments
+    ///
+    /// * `pixels` - storage, panics unless it holds exactly `resolution.pixels()` pixels
+    /// * `resolution` - size of the frame games draw into
+    ///
+    pub fn with_resolution(pixels: &'a mut [u16], resolution: Resolution) -> Self {
+        assert_eq!(pixels.len(), resolution.pixels());
+        let mut framebuffer = Self {
+            pixels,
+            resolution,
+            dirty: DirtyRegions::new(),
+            blend_mode: BlendMode::default(),
+        };
+        framebuffer.mark_all_dirty();
+        framebuffer
+    }
+
+    pub fn resolution(&self) -> Resolution {
+        self.resolution
+    }
+
+    /// The frame as it goes over the wire to the display, when not pixel doubled
+    pub fn as_bytes(&self) -> &[u8] {
+        self.pixels.as_byte_slice()
+    }
+
+    /// Color of the pixel at `point`, if it is in the frame
+    pub fn pixel(&self, point: Point) -> Option<Rgb565> {
+        let side = self.resolution.side();
+        let (x, y) = (
+            usize::try_from(point.x).ok()?,
+            usize::try_from(point.y).ok()?,
+        );
+        (x < side && y < side).then(|| RawU16::new(u16::from_be(self.pixels[x + y * side])).into())
+    }
+
+    ///
+    /// The pixels inside `area` as runs of bytes in wire order.
+    /// Areas spanning whole rows come out as one run, anything narrower as one run per row.
+    ///
+    /// # Arguments
+    ///
+    /// * `area` - Part of the frame to send, must be on the screen
+    ///
+    pub fn area_bytes(&self, area: &Rectangle) -> impl Iterator<Item = &[u8]> {
+        let bytes = self.as_bytes();
+        let side = self.resolution.side();
+        let (x, y) = (area.top_left.x as usize, area.top_left.y as usize);
+        let (runs, run_len) = match area.size.width as usize {
+            width if width == side => (1, side * area.size.height as usize),
+            width => (area.size.height as usize, width),
+        };
+
+        (0..runs).map(move |row| {
+            let start = (x + (y + row) * side) * 2;
+            &bytes[start..start + run_len * 2]
+        })
+    }
+
+    ///
+    /// Scales one row of the pixels inside `area` 2x, as the two panel rows it covers.
+    ///
+    /// # Arguments
+    ///
+    /// * `area` - Part of the frame to send, must be in the frame
+    /// * `row` - Row within `area`, from 0 at its top
+    /// * `line` - Scratch space the row is scaled into, the scaled bytes are returned from it
+    ///
+    pub fn doubled_row<'l>(
+        &self,
+        area: &Rectangle,
+        row: usize,
+        line: &'l mut [u8; DOUBLED_LINE_BYTES],
+    ) -> &'l [u8] {
+        let side = self.resolution.side();
+        let (x, y) = (area.top_left.x as usize, area.top_left.y as usize);
+        let width = area.size.width as usize;
+        let start = (x + (y + row) * side) * 2;
+        let pixels = &self.as_bytes()[start..start + width * 2];
+
+        let line = &mut line[..width * 8];
+        let (top, bottom) = line.split_at_mut(width * 4);
+        for (pixel, doubled) in pixels.chunks_exact(2).zip(top.chunks_exact_mut(4)) {
+            doubled[..2].copy_from_slice(pixel);
+            doubled[2..].copy_from_slice(pixel);
+        }
+        bottom.copy_from_slice(top);
+        line
+    }
+
+    /// Regions drawn into since the frame was last presented
+    pub fn dirty(&self) -> &DirtyRegions {
+        &self.dirty
+    }
+
+    /// Counts the whole frame as changed, so all of it is sent next time
+    pub fn mark_all_dirty(&mut self) {
+        self.dirty.add(self.bounding_box());
+    }
+
+    /// Forgets the changed regions, once they are on the screen
+    pub fn clear_dirty(&mut self) {
+        self.dirty.clear();
+    }
+
+    /// Copies `other` into this frame, so drawing can carry on from where it left off.
+    /// The copy is what the screen will show, so nothing counts as changed afterwards.
+    pub fn copy_from(&mut self, other: &Framebuffer<'_>) -> Result<(), ResolutionMismatch> {
+        if self.resolution != other.resolution {
+            return Err(ResolutionMismatch);
+        }
+        self.pixels.copy_from_slice(other.pixels);
+        self.dirty.clear();
+        Ok(())
+    }
+
+    ///
+    /// Copies the regions of `other` that changed since it was last presented, which catches
+    /// this frame up when it holds the frame `other` was drawn on top of. Swapping between two
+    /// frames this way only copies what was drawn rather than the whole frame.
+    /// The copy is what the screen will show, so nothing counts as changed afterwards.
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - frame about to be presented, with its dirty regions not yet cleared
+    ///
+    pub fn copy_dirty_from(&mut self, other: &Framebuffer<'_>) -> Result<(), ResolutionMismatch> {
+        if self.resolution != other.resolution {
+            return Err(ResolutionMismatch);
+        }
+        let side = self.resolution.side();
+        for area in other.dirty.iter() {
+            let (x, y) = (area.top_left.x as usize, area.top_left.y as usize);
+            let width = area.size.width as usize;
+            for row in y..y + area.size.height as usize {
+                let start = x + row * side;
+                self.pixels[start..start + width]
+                    .copy_from_slice(&other.pixels[start..start + width]);
+            }
+        }
+        self.dirty.clear();
+        Ok(())
+    }
+}
+
+impl Blend for Framebuffer<'_> {
+    fn blend_mode(&self) -> BlendMode {
+        self.blend_mode
+    }
+
+    fn set_blend_mode(&mut self, blend_mode: BlendMode) {
+        self.blend_mode = blend_mode;
+    }
+}
+
+impl DrawTarget for Framebuffer<'_> {
+    type Error = Infallible;
+    type Color = Rgb565;
+
+    fn draw_iter<T>(&mut self, pixels: T) -> Result<(), Self::Error>
+    where
+        T: IntoIterator<Item = Pixel<Rgb565>>,
+    {
+        let side = self.resolution.side() as u32;
+        let fb = &mut self.pixels;
+        let mut bounds = Bounds::default();
+        for Pixel(coord, color) in pixels.into_iter() {
+            if let Ok((x, y)) = <(u32, u32)>::try_from(coord) {
+                if x >= side || y >= side {
+                    continue;
+                }
+                let index = (x + y * side) as usize;
+                if let Some(color) = self.blend_mode.apply(fb[index], color) {
+                    fb[index] = color;
+                    bounds.include(coord);
+                }
+            }
+        }
+
+        if let Some(area) = bounds.rectangle() {
+            self.dirty.add(area);
+        }
+
+        Ok(())
+    }
+
+    fn fill_contiguous<I>(&mut self, area: &Rectangle, colors: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Self::Color>,
+    {
+        let clipped_area = area.intersection(&self.bounding_box());
+        if area.bottom_right().is_none() || clipped_area.bottom_right().is_none() {
+            return Ok(());
+        }
+
+        let skip_top_left = clipped_area.top_left - area.top_left;
+        let skip_bottom_right = area.bottom_right().unwrap() - clipped_area.bottom_right().unwrap();
+
+        let side = self.resolution.side() as i32;
+        let fb = &mut self.pixels;
+        let mut colors = colors.into_iter();
+        // Only what the blend mode let through needs sending again
+        let mut bounds = Bounds::default();
+
+        for _ in 0..skip_top_left.y {
+            for _ in 0..area.size.width {
+                colors.next();
+            }
+        }
+
+        'rows: for y in 0..clipped_area.size.height as i32 {
+            for _ in 0..skip_top_left.x {
+                colors.next();
+            }
+
+            let row = clipped_area.top_left.y + y;
+            let start = (clipped_area.top_left.x + row * side) as usize;
+            let pixels = &mut fb[start..start + clipped_area.size.width as usize];
+            for (x, pixel) in (clipped_area.top_left.x..).zip(pixels) {
+                // Running out of colors early leaves the rest of the area as it was
+                let Some(color) = colors.next() else {
+                    break 'rows;
+                };
+                if let Some(color) = self.blend_mode.apply(*pixel, color) {
+                    *pixel = color;
+                    bounds.include(Point::new(x, row));
+                }
+            }
+
+            for _ in 0..skip_bottom_right.x {
+                colors.next();
+            }
+        }
+
+        if let Some(area) = bounds.rectangle() {
+            self.dirty.add(area);
+        }
+
+        Ok(())
+    }
+}
+
+impl OriginDimensions for Framebuffer<'_> {
+    fn size(&self) -> Size {
+        let side = self.resolution.side() as u32;
+        Size::new(side, side)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_graphics_core::pixelcolor::{RgbColor, WebColors};
+    use embedded_graphics_core::primitives::PointsIter;
+
+    use super::*;
+
+    #[test]
+    fn copy_dirty_from_catches_up_on_what_was_drawn() {
+        let (mut front, mut back) = ([0; WIDTH * HEIGHT], [0; WIDTH * HEIGHT]);
+        let mut front = Framebuffer::new(&mut front);
+        let mut back = Framebuffer::new(&mut back);
+        let _ = front.fill_solid(&front.bounding_box(), Rgb565::BLUE);
+        back.copy_from(&front).unwrap();
+        front.clear_dirty();
+
+        let area = Rectangle::new(Point::new(10, 20), Size::new(3, 2));
+        let _ = front.fill_solid(&area, Rgb565::RED);
+        back.copy_dirty_from(&front).unwrap();
+
+        assert_eq!(back.pixel(Point::new(10, 20)), Some(Rgb565::RED));
+        assert_eq!(back.pixel(Point::new(12, 21)), Some(Rgb565::RED));
+        assert_eq!(back.pixel(Point::new(13, 21)), Some(Rgb565::BLUE));
+        assert_eq!(back.pixel(Point::new(10, 22)), Some(Rgb565::BLUE));
+        assert!(back.dirty().is_empty());
+    }
+
+    #[test]
+    fn copies_need_the_same_resolution() {
+        let (mut full, mut doubled) = ([0; WIDTH * HEIGHT], [0; Resolution::Doubled.pixels()]);
+        let mut full = Framebuffer::new(&mut full);
+        let doubled = Framebuffer::doubled(&mut doubled);
+
+        assert_eq!(full.copy_from(&doubled), Err(ResolutionMismatch));
+        assert_eq!(full.copy_dirty_from(&doubled), Err(ResolutionMismatch));
+    }
+
+    /// Draws `color` over a frame filled with `under`, through `draw_iter` and through
+    /// `fill_contiguous`, and returns the pixel each of them left
+    fn draw_both_ways(mode: BlendMode, under: Rgb565, color: Rgb565) -> [Option<Rgb565>; 2] {
+        let mut pixels = [0; WIDTH * HEIGHT];
+        let mut framebuffer = Framebuffer::new(&mut pixels);
+        let _ = framebuffer.fill_solid(&framebuffer.bounding_box(), under);
+        framebuffer.set_blend_mode(mode);
+
+        let _ = framebuffer.draw_iter([Pixel(Point::new(1, 1), color)]);
+        let area = Rectangle::new(Point::new(2, 1), Size::new(1, 1));
+        let _ = framebuffer.fill_contiguous(&area, [color]);
+        [
+            framebuffer.pixel(Point::new(1, 1)),
+            framebuffer.pixel(Point::new(2, 1)),
+        ]
+    }
+
+    #[test]
+    fn replace_overwrites() {
+        let drawn = draw_both_ways(BlendMode::Replace, Rgb565::BLUE, Rgb565::RED);
+        assert_eq!(drawn, [Some(Rgb565::RED); 2]);
+    }
+
+    #[test]
+    fn xor_combines_and_erases_when_drawn_twice() {
+        let drawn = draw_both_ways(BlendMode::Xor, Rgb565::BLUE, Rgb565::RED);
+        assert_eq!(drawn, [Some(Rgb565::MAGENTA); 2]);
+
+        let mut pixels = [0; WIDTH * HEIGHT];
+        let mut framebuffer = Framebuffer::new(&mut pixels);
+        let _ = framebuffer.fill_solid(&framebuffer.bounding_box(), Rgb565::CSS_TEAL);
+        framebuffer.set_blend_mode(BlendMode::Xor);
+        let area = Rectangle::new(Point::new(4, 4), Size::new(2, 2));
+        let colors = [
+            Rgb565::RED,
+            Rgb565::GREEN,
+            Rgb565::WHITE,
+            Rgb565::CSS_ORANGE,
+        ];
+        for _ in 0..2 {
+            let _ = framebuffer.draw_iter([Pixel(Point::new(0, 0), Rgb565::YELLOW)]);
+            let _ = framebuffer.fill_contiguous(&area, colors);
+        }
+
+        for point in area.points().chain([Point::new(0, 0)]) {
+            assert_eq!(framebuffer.pixel(point), Some(Rgb565::CSS_TEAL));
+        }
+    }
+
+    #[test]
+    fn add_clamps_each_channel() {
+        let drawn = draw_both_ways(BlendMode::Add, Rgb565::new(1, 2, 3), Rgb565::new(4, 5, 6));
+        assert_eq!(drawn, [Some(Rgb565::new(5, 7, 9)); 2]);
+
+        let drawn = draw_both_ways(
+            BlendMode::Add,
+            Rgb565::new(20, 40, 10),
+            Rgb565::new(20, 40, 30),
+        );
+        assert_eq!(drawn, [Some(Rgb565::WHITE); 2]);
+    }
+
+    #[test]
+    fn alpha_mixes_by_weight() {
+        let opaque = draw_both_ways(BlendMode::Alpha(255), Rgb565::WHITE, Rgb565::BLACK);
+        assert_eq!(opaque, [Some(Rgb565::BLACK); 2]);
+
+        let invisible = draw_both_ways(BlendMode::Alpha(0), Rgb565::WHITE, Rgb565::BLACK);
+        assert_eq!(invisible, [Some(Rgb565::WHITE); 2]);
+
+        let half = draw_both_ways(BlendMode::Alpha(128), Rgb565::WHITE, Rgb565::BLACK);
+        assert_eq!(half, [Some(Rgb565::new(15, 31, 15)); 2]);
+    }
+
+    #[test]
+    fn color_key_skips_the_key() {
+        let mode = BlendMode::ColorKey(Rgb565::GREEN);
+        let key = draw_both_ways(mode, Rgb565::BLUE, Rgb565::GREEN);
+        assert_eq!(key, [Some(Rgb565::BLUE); 2]);
+
+        let other = draw_both_ways(mode, Rgb565::BLUE, Rgb565::RED);
+        assert_eq!(other, [Some(Rgb565::RED); 2]);
+
+        // Only the pixels a fill changes get sent again
+        let mut pixels = [0; WIDTH * HEIGHT];
+        let mut framebuffer = Framebuffer::new(&mut pixels);
+        framebuffer.set_blend_mode(mode);
+        framebuffer.clear_dirty();
+        let area = Rectangle::new(Point::new(4, 4), Size::new(3, 3));
+        let _ = framebuffer.fill_contiguous(&area, [Rgb565::GREEN; 9]);
+        assert!(framebuffer.dirty().is_empty());
+
+        let mut colors = [Rgb565::GREEN; 9];
+        colors[5] = Rgb565::RED;
+        let _ = framebuffer.fill_contiguous(&area, colors);
+        let dirty: Vec<_> = framebuffer.dirty().iter().copied().collect();
+        assert_eq!(dirty, [Rectangle::new(Point::new(6, 5), Size::new(1, 1))]);
+    }
+
+    #[test]
+    fn fill_contiguous_stops_when_colors_run_out() {
+        let mut pixels = [0; WIDTH * HEIGHT];
+        let mut framebuffer = Framebuffer::new(&mut pixels);
+        let area = Rectangle::new(Point::new(0, 0), Size::new(3, 2));
+
+        let _ = framebuffer.fill_contiguous(&area, [Rgb565::RED; 4]);
+
+        let red = area
+            .points()
+            .filter(|point| framebuffer.pixel(*point) == Some(Rgb565::RED))
+            .count();
+        assert_eq!(red, 4);
+        assert_eq!(framebuffer.pixel(Point::new(2, 1)), Some(Rgb565::BLACK));
+
+        // The rows never reached do not count as changed
+        framebuffer.clear_dirty();
+        let tall = Rectangle::new(Point::new(10, 10), Size::new(3, 3));
+        let _ = framebuffer.fill_contiguous(&tall, [Rgb565::RED; 2]);
+        let dirty: Vec<_> = framebuffer.dirty().iter().copied().collect();
+        assert_eq!(dirty, [Rectangle::new(Point::new(10, 10), Size::new(2, 1))]);
+    }
+
+    #[test]
+    fn fill_contiguous_skips_colors_off_the_frame() {
+        let mut pixels = [0; WIDTH * HEIGHT];
+        let mut framebuffer = Framebuffer::new(&mut pixels);
+        let area = Rectangle::new(Point::new(-1, -1), Size::new(3, 2));
+        let colors = [
+            Rgb565::RED,
+            Rgb565::RED,
+            Rgb565::RED,
+            Rgb565::RED,
+            Rgb565::GREEN,
+            Rgb565::BLUE,
+        ];
+
+        let _ = framebuffer.fill_contiguous(&area, colors);
+
+        assert_eq!(framebuffer.pixel(Point::new(0, 0)), Some(Rgb565::GREEN));
+        assert_eq!(framebuffer.pixel(Point::new(1, 0)), Some(Rgb565::BLUE));
+        assert_eq!(framebuffer.pixel(Point::new(0, 1)), Some(Rgb565::BLACK));
+    }
+}
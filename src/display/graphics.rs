@@ -1,40 +1,37 @@
 // use crate::ST7789::batch::DrawBatch;
-use crate::display::{Error, Orientation, ST7789};
+use crate::display::blend::{Blend, BlendMode};
+use crate::display::framebuffer::{Framebuffer, Resolution};
+use crate::display::{Error, ST7789};
 
-use defmt::info;
 use display_interface::AsyncWriteOnlyDataCommand;
 use embedded_graphics_core::pixelcolor::Rgb565;
-use embedded_graphics_core::prelude::{DrawTarget, IntoStorage, Point, Size};
-use embedded_graphics_core::{
-    pixelcolor::raw::{RawData, RawU16},
-    primitives::Rectangle,
-};
+use embedded_graphics_core::prelude::{DrawTarget, Size};
+use embedded_graphics_core::primitives::Rectangle;
 use embedded_graphics_core::{prelude::OriginDimensions, Pixel};
 use embedded_hal_1::digital::OutputPin;
+use static_cell::ConstStaticCell;
 
 pub const WIDTH: usize = 240;
 pub const HEIGHT: usize = 240;
 
-pub static mut FRAMEBUFFER: [u16; WIDTH * HEIGHT] = [0; WIDTH * HEIGHT];
-
-pub fn framebuffer() -> &'static mut [u16; WIDTH * HEIGHT] {
-    unsafe { &mut FRAMEBUFFER }
-}
-
-impl<DI, RST, PinE> ST7789<DI, RST>
-where
-    DI: AsyncWriteOnlyDataCommand,
-    RST: OutputPin<Error = PinE>,
-{
-    /// Returns the bounding box for the entire framebuffer.
-    fn framebuffer_bounding_box(&self) -> Rectangle {
-        let size = match self.orientation {
-            Orientation::Portrait | Orientation::PortraitSwapped => Size::new(240, 320),
-            Orientation::Landscape | Orientation::LandscapeSwapped => Size::new(320, 240),
-        };
-
-        Rectangle::new(Point::zero(), size)
-    }
+/// Resolution games draw at, the `pixel-double` feature picks the 120x120 mode
+#[cfg(not(feature = "pixel-double"))]
+pub const RESOLUTION: Resolution = Resolution::Full;
+#[cfg(feature = "pixel-double")]
+pub const RESOLUTION: Resolution = Resolution::Doubled;
+
+/// Storage for the two framebuffers, handed out by [`framebuffers`]
+static FRAMEBUFFERS: ConstStaticCell<[[u16; RESOLUTION.pixels()]; 2]> =
+    ConstStaticCell::new([[0; RESOLUTION.pixels()]; 2]);
+
+/// Takes the two framebuffers, one to draw into while the display sends the other.
+/// Panics if called more than once.
+pub fn framebuffers() -> [Framebuffer<'static>; 2] {
+    let [a, b] = FRAMEBUFFERS.take();
+    [
+        Framebuffer::with_resolution(a, RESOLUTION),
+        Framebuffer::with_resolution(b, RESOLUTION),
+    ]
 }
 
 impl<DI, RST, PinE> DrawTarget for ST7789<DI, RST>
@@ -49,59 +46,22 @@ where
     where
         T: IntoIterator<Item = Pixel<Rgb565>>,
     {
-        const M: u32 = WIDTH as u32 - 1;
-        let fb = framebuffer();
-        for Pixel(coord, color) in pixels.into_iter() {
-            if let Ok((x @ 0..=M, y @ 0..=M)) = coord.try_into() {
-                let index: u32 = x + y * WIDTH as u32;
-                let color = RawU16::from(color).into_inner();
-                fb[index as usize] ^= color.to_be();
-            }
+        match &mut self.framebuffer {
+            Some(framebuffer) => framebuffer.draw_iter(pixels).map_err(|e| match e {}),
+            None => Err(Error::NoFramebuffer),
         }
-
-        Ok(())
     }
+
     fn fill_contiguous<I>(&mut self, area: &Rectangle, colors: I) -> Result<(), Self::Error>
     where
         I: IntoIterator<Item = Self::Color>,
     {
-        let clipped_area = area.intersection(&self.framebuffer_bounding_box().intersection(area));
-        if area.bottom_right().is_none() || clipped_area.bottom_right().is_none() {
-            return Ok(());
-        }
-
-        let skip_top_left = clipped_area.top_left - area.top_left;
-        let skip_bottom_right = area.bottom_right().unwrap() - clipped_area.bottom_right().unwrap();
-
-        let fb = framebuffer();
-        let mut colors = colors.into_iter();
-
-        for _ in 0..skip_top_left.y {
-            for _ in 0..area.size.width {
-                colors.next();
-            }
+        match &mut self.framebuffer {
+            Some(framebuffer) => framebuffer
+                .fill_contiguous(area, colors)
+                .map_err(|e| match e {}),
+            None => Err(Error::NoFramebuffer),
         }
-
-        for y in 0..clipped_area.size.height as i32 {
-            for _ in 0..skip_top_left.x {
-                colors.next();
-            }
-
-            let mut index = clipped_area.top_left.x + (clipped_area.top_left.y + y) * WIDTH as i32;
-            for _ in 0..clipped_area.size.width {
-                let color = colors.next().unwrap();
-                let color = RawU16::from(color).into_inner();
-
-                fb[index as usize] = color.to_be();
-                index += 1;
-            }
-
-            for _ in 0..skip_bottom_right.x {
-                colors.next();
-            }
-        }
-
-        Ok(())
     }
 
     // fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
@@ -117,12 +77,35 @@ where
     // }
 }
 
+impl<DI, RST> Blend for ST7789<DI, RST>
+where
+    DI: AsyncWriteOnlyDataCommand,
+    RST: OutputPin,
+{
+    fn blend_mode(&self) -> BlendMode {
+        self.framebuffer
+            .as_ref()
+            .map(Blend::blend_mode)
+            .unwrap_or_default()
+    }
+
+    fn set_blend_mode(&mut self, blend_mode: BlendMode) {
+        if let Some(framebuffer) = self.framebuffer.as_mut() {
+            framebuffer.set_blend_mode(blend_mode);
+        }
+    }
+}
+
 impl<DI, RST, PinE> OriginDimensions for ST7789<DI, RST>
 where
     DI: AsyncWriteOnlyDataCommand,
     RST: OutputPin<Error = PinE>,
 {
     fn size(&self) -> Size {
-        Size::new(self.size_x.into(), self.size_y.into()) // visible area, not RAM-pixel size
+        match &self.framebuffer {
+            // what games draw into, smaller than the panel when pixel doubled
+            Some(framebuffer) => framebuffer.size(),
+            None => Size::new(self.size_x.into(), self.size_y.into()), // visible area, not RAM-pixel size
+        }
     }
 }
@@ -0,0 +1,469 @@
+//! Indexed colour framebuffers, each pixel picks one of 256 (8bpp) or 16 (4bpp) palette colors.
+//! Changing the palette recolors the whole frame without redrawing it, which makes fades,
+//! flashes and day/night cycles cheap.
+
+use core::convert::Infallible;
+
+use embedded_graphics_core::pixelcolor::raw::{RawData, RawU16, RawU8};
+use embedded_graphics_core::pixelcolor::{PixelColor, Rgb565};
+use embedded_graphics_core::prelude::{Dimensions, DrawTarget, OriginDimensions, Point, Size};
+use embedded_graphics_core::primitives::{PointsIter, Rectangle};
+use embedded_graphics_core::Pixel;
+
+use crate::display::blend::BlendMode;
+use crate::display::dirty::{Bounds, DirtyRegions};
+use crate::display::framebuffer::{Resolution, DOUBLED_LINE_BYTES};
+
+/// Storage for one 240x240 8bpp frame
+pub type IndexedPixels8 = [u8; Depth::Bpp8.bytes(Resolution::Full)];
+/// Storage for one 240x240 4bpp frame
+pub type IndexedPixels4 = [u8; Depth::Bpp4.bytes(Resolution::Full)];
+
+/// Largest number of bytes one expanded row takes, see [`IndexedFramebuffer::expand_row`]
+pub const LINE_BYTES: usize = DOUBLED_LINE_BYTES;
+
+/// Color of an indexed framebuffer, a position in its palette
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PaletteIndex(pub u8);
+
+impl PixelColor for PaletteIndex {
+    type Raw = RawU8;
+}
+
+impl From<RawU8> for PaletteIndex {
+    fn from(raw: RawU8) -> Self {
+        Self(raw.into_inner())
+    }
+}
+
+///
+/// Bits per pixel of an indexed framebuffer.
+///
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Depth {
+    /// 256 colors, a byte per pixel
+    Bpp8,
+    /// 16 colors, two pixels per byte with the left one in the high nibble.
+    /// Indexes above 15 are drawn as their low nibble.
+    Bpp4,
+}
+
+impl Depth {
+    /// Bytes a frame of `resolution` takes at this depth
+    pub const fn bytes(self, resolution: Resolution) -> usize {
+        match self {
+            Depth::Bpp8 => resolution.pixels(),
+            Depth::Bpp4 => resolution.pixels() / 2,
+        }
+    }
+}
+
+///
+/// The colors palette indexes stand for, kept in the display's byte order.
+///
+#[derive(Clone)]
+pub struct Palette {
+    colors: [u16; 256],
+}
+
+impl Palette {
+    /// All black palette
+    pub const fn new() -> Self {
+        Self { colors: [0; 256] }
+    }
+
+    /// Palette starting with `colors`, the rest black
+    pub fn from_colors(colors: &[Rgb565]) -> Self {
+        let mut palette = Self::new();
+        for (index, color) in colors.iter().take(256).enumerate() {
+            palette.set(index as u8, *color);
+        }
+        palette
+    }
+
+    pub fn get(&self, index: u8) -> Rgb565 {
+        RawU16::new(u16::from_be(self.colors[index as usize])).into()
+    }
+
+    pub fn set(&mut self, index: u8, color: Rgb565) {
+        self.colors[index as usize] = RawU16::from(color).into_inner().to_be();
+    }
+
+    ///
+    /// Mixes every color towards the same entry of `target`, for fades and day/night cycles
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - palette to move towards
+    /// * `amount` - 0 keeps this palette, 255 gives `target`
+    ///
+    pub fn blend(&self, target: &Palette, amount: u8) -> Palette {
+        let mut blended = self.clone();
+        for (index, color) in blended.colors.iter_mut().enumerate() {
+            if let Some(mixed) = BlendMode::Alpha(amount).apply(*color, target.get(index as u8)) {
+                *color = mixed;
+            }
+        }
+        blended
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+///
+/// A frame of palette indexes, 240x240 or pixel doubled 120x120. Games running on a [`PicoSystemHal`] show it by
+/// expanding it into the HAL's display with [`expand_into`](Self::expand_into) from
+/// [`Game::draw`], code driving an [`ST7789`] itself can send it as it is with
+/// [`ST7789::shotgun_indexed`].
+///
+/// [`PicoSystemHal`]: crate::hal::PicoSystemHal
+/// [`Game::draw`]: crate::engine::game::Game::draw
+/// [`ST7789`]: crate::display::ST7789
+/// [`ST7789::shotgun_indexed`]: crate::display::ST7789::shotgun_indexed
+///
+pub struct IndexedFramebuffer<'a> {
+    pixels: &'a mut [u8],
+    depth: Depth,
+    resolution: Resolution,
+    palette: Palette,
+    dirty: DirtyRegions,
+}
+
+impl<'a> IndexedFramebuffer<'a> {
+    /// Wraps `pixels` as a 240x240 frame, see [`with_resolution`](Self::with_resolution)
+    pub fn new(pixels: &'a mut [u8], depth: Depth) -> Self {
+        Self::with_resolution(pixels, depth, Resolution::Full)
+    }
+
+    ///
+    /// Wraps `pixels` with an all black palette, the whole frame counts as changed
+    ///
+    /// # Arguments
+    ///
+    /// * `pixels` - storage, panics unless it holds exactly `depth.bytes(resolution)` bytes
+    /// * `depth` - bits per pixel
+    /// * `resolution` - size of the frame, a doubled one is shown at 2x like [`Framebuffer`]
+    ///
+    /// [`Framebuffer`]: crate::display::framebuffer::Framebuffer
+    ///
+    pub fn with_resolution(pixels: &'a mut [u8], depth: Depth, resolution: Resolution) -> Self {
+        assert_eq!(pixels.len(), depth.bytes(resolution));
+        let mut framebuffer = Self {
+            pixels,
+            depth,
+            resolution,
+            palette: Palette::new(),
+            dirty: DirtyRegions::new(),
+        };
+        framebuffer.mark_all_dirty();
+        framebuffer
+    }
+
+    pub fn depth(&self) -> Depth {
+        self.depth
+    }
+
+    pub fn resolution(&self) -> Resolution {
+        self.resolution
+    }
+
+    pub fn palette(&self) -> &Palette {
+        &self.palette
+    }
+
+    /// Swaps in a new palette, every pixel may change color so all of the frame is sent again
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+        self.mark_all_dirty();
+    }
+
+    /// Changes one palette color, all of the frame is sent again
+    pub fn set_palette_entry(&mut self, index: u8, color: Rgb565) {
+        self.palette.set(index, color);
+        self.mark_all_dirty();
+    }
+
+    /// Palette index of the pixel at `point`, if it is in the frame
+    pub fn index(&self, point: Point) -> Option<u8> {
+        let side = self.resolution.side();
+        let (x, y) = (
+            usize::try_from(point.x).ok()?,
+            usize::try_from(point.y).ok()?,
+        );
+        (x < side && y < side).then(|| self.index_at(x + y * side))
+    }
+
+    fn index_at(&self, pixel: usize) -> u8 {
+        match self.depth {
+            Depth::Bpp8 => self.pixels[pixel],
+            Depth::Bpp4 => match pixel % 2 {
+                0 => self.pixels[pixel / 2] >> 4,
+                _ => self.pixels[pixel / 2] & 0x0f,
+            },
+        }
+    }
+
+    fn set_index_at(&mut self, pixel: usize, index: u8) {
+        match self.depth {
+            Depth::Bpp8 => self.pixels[pixel] = index,
+            Depth::Bpp4 => {
+                let byte = &mut self.pixels[pixel / 2];
+                *byte = match pixel % 2 {
+                    0 => (*byte & 0x0f) | (index << 4),
+                    _ => (*byte & 0xf0) | (index & 0x0f),
+                };
+            }
+        }
+    }
+
+    ///
+    /// Looks up one row of the pixels inside `area` in the palette. Pixel doubled frames
+    /// come out scaled 2x, as the two panel rows the row covers.
+    ///
+    /// # Arguments
+    ///
+    /// * `area` - Part of the frame to send, must be in the frame
+    /// * `row` - Row within `area`, from 0 at its top
+    /// * `line` - Scratch space the colors are written to in wire order, returned from it
+    ///
+    pub fn expand_row<'l>(
+        &self,
+        area: &Rectangle,
+        row: usize,
+        line: &'l mut [u8; LINE_BYTES],
+    ) -> &'l [u8] {
+        let (x, y) = (area.top_left.x as usize, area.top_left.y as usize);
+        let width = area.size.width as usize;
+        let start = x + (y + row) * self.resolution.side();
+        let pixels = start..start + width;
+        let color = |pixel| self.palette.colors[self.index_at(pixel) as usize].to_ne_bytes();
+
+        match self.resolution {
+            Resolution::Full => {
+                let line = &mut line[..width * 2];
+                for (pixel, bytes) in pixels.zip(line.chunks_exact_mut(2)) {
+                    bytes.copy_from_slice(&color(pixel));
+                }
+                line
+            }
+            Resolution::Doubled => {
+                let line = &mut line[..width * 8];
+                let (top, bottom) = line.split_at_mut(width * 4);
+                for (pixel, bytes) in pixels.zip(top.chunks_exact_mut(4)) {
+                    let color = color(pixel);
+                    bytes[..2].copy_from_slice(&color);
+                    bytes[2..].copy_from_slice(&color);
+                }
+                bottom.copy_from_slice(top);
+                line
+            }
+        }
+    }
+
+    ///
+    /// Draws the regions changed since they were last presented into a true color frame,
+    /// looking each pixel up in the palette, and counts them as presented.
+    /// Frames the HAL keeps up to date between presents, like its display, only ever get
+    /// the changes.
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - frame to draw into, usually the HAL's display
+    ///
+    pub fn expand_into<D>(&mut self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = Rgb565>,
+    {
+        let side = self.resolution.side();
+        for area in self.dirty.iter() {
+            let colors = area.points().map(|point| {
+                let index = self.index_at(point.x as usize + point.y as usize * side);
+                self.palette.get(index)
+            });
+            target.fill_contiguous(area, colors)?;
+        }
+        self.dirty.clear();
+        Ok(())
+    }
+
+    /// Regions drawn into since the frame was last presented
+    pub fn dirty(&self) -> &DirtyRegions {
+        &self.dirty
+    }
+
+    /// Counts the whole frame as changed, so all of it is sent next time
+    pub fn mark_all_dirty(&mut self) {
+        self.dirty.add(self.bounding_box());
+    }
+
+    /// Forgets the changed regions, once they are on the screen
+    pub fn clear_dirty(&mut self) {
+        self.dirty.clear();
+    }
+}
+
+impl DrawTarget for IndexedFramebuffer<'_> {
+    type Error = Infallible;
+    type Color = PaletteIndex;
+
+    fn draw_iter<T>(&mut self, pixels: T) -> Result<(), Self::Error>
+    where
+        T: IntoIterator<Item = Pixel<PaletteIndex>>,
+    {
+        let side = self.resolution.side() as u32;
+        let mut bounds = Bounds::default();
+        for Pixel(coord, PaletteIndex(index)) in pixels.into_iter() {
+            if let Ok((x, y)) = <(u32, u32)>::try_from(coord) {
+                if x >= side || y >= side {
+                    continue;
+                }
+                self.set_index_at((x + y * side) as usize, index);
+                bounds.include(coord);
+            }
+        }
+
+        if let Some(area) = bounds.rectangle() {
+            self.dirty.add(area);
+        }
+
+        Ok(())
+    }
+}
+
+impl OriginDimensions for IndexedFramebuffer<'_> {
+    fn size(&self) -> Size {
+        let side = self.resolution.side() as u32;
+        Size::new(side, side)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_graphics_core::pixelcolor::RgbColor;
+
+    use super::*;
+    use crate::display::framebuffer::Framebuffer;
+    use crate::{HEIGHT, WIDTH};
+
+    fn palette() -> Palette {
+        Palette::from_colors(&[Rgb565::BLACK, Rgb565::RED, Rgb565::GREEN, Rgb565::BLUE])
+    }
+
+    #[test]
+    fn stores_a_byte_per_pixel_at_8bpp() {
+        let mut pixels = [0; Depth::Bpp8.bytes(Resolution::Full)];
+        let mut frame = IndexedFramebuffer::new(&mut pixels, Depth::Bpp8);
+
+        let _ = frame.draw_iter([
+            Pixel(Point::new(0, 0), PaletteIndex(200)),
+            Pixel(Point::new(239, 239), PaletteIndex(3)),
+            Pixel(Point::new(240, 0), PaletteIndex(1)),
+        ]);
+
+        assert_eq!(frame.index(Point::new(0, 0)), Some(200));
+        assert_eq!(frame.index(Point::new(239, 239)), Some(3));
+        assert_eq!(frame.index(Point::new(1, 0)), Some(0));
+        assert_eq!(frame.index(Point::new(240, 0)), None);
+    }
+
+    #[test]
+    fn packs_two_pixels_a_byte_at_4bpp() {
+        let mut pixels = [0; Depth::Bpp4.bytes(Resolution::Full)];
+        let mut frame = IndexedFramebuffer::new(&mut pixels, Depth::Bpp4);
+
+        let _ = frame.draw_iter([
+            Pixel(Point::new(0, 0), PaletteIndex(0x3)),
+            Pixel(Point::new(1, 0), PaletteIndex(0xa)),
+            Pixel(Point::new(3, 0), PaletteIndex(0x1f)),
+        ]);
+
+        assert_eq!(frame.index(Point::new(0, 0)), Some(0x3));
+        assert_eq!(frame.index(Point::new(1, 0)), Some(0xa));
+        assert_eq!(frame.index(Point::new(2, 0)), Some(0));
+        // Only the low nibble fits
+        assert_eq!(frame.index(Point::new(3, 0)), Some(0xf));
+        assert_eq!(pixels[0], 0x3a);
+    }
+
+    #[test]
+    fn palette_blends_towards_a_target() {
+        let from = palette();
+        let to = Palette::from_colors(&[Rgb565::WHITE; 4]);
+
+        assert_eq!(from.blend(&to, 0).get(1), Rgb565::RED);
+        assert_eq!(from.blend(&to, 255).get(1), Rgb565::WHITE);
+        assert_eq!(from.blend(&to, 128).get(0), Rgb565::new(15, 31, 15));
+    }
+
+    #[test]
+    fn expands_changes_through_the_palette() {
+        let mut indexes = [0; Depth::Bpp8.bytes(Resolution::Full)];
+        let mut frame = IndexedFramebuffer::new(&mut indexes, Depth::Bpp8);
+        frame.set_palette(palette());
+        let mut pixels = [0; WIDTH * HEIGHT];
+        let mut display = Framebuffer::new(&mut pixels);
+
+        let _ = frame.draw_iter([Pixel(Point::new(5, 5), PaletteIndex(1))]);
+        let _ = frame.expand_into(&mut display);
+        assert_eq!(display.pixel(Point::new(5, 5)), Some(Rgb565::RED));
+        assert_eq!(display.pixel(Point::new(6, 5)), Some(Rgb565::BLACK));
+        assert!(frame.dirty().is_empty());
+
+        // Nothing changed, so the display is left alone
+        let _ = display.draw_iter([Pixel(Point::new(6, 5), Rgb565::WHITE)]);
+        let _ = frame.expand_into(&mut display);
+        assert_eq!(display.pixel(Point::new(6, 5)), Some(Rgb565::WHITE));
+
+        // A palette change recolors the whole frame
+        frame.set_palette_entry(0, Rgb565::BLUE);
+        let _ = frame.expand_into(&mut display);
+        assert_eq!(display.pixel(Point::new(5, 5)), Some(Rgb565::RED));
+        assert_eq!(display.pixel(Point::new(6, 5)), Some(Rgb565::BLUE));
+        assert_eq!(display.pixel(Point::new(239, 239)), Some(Rgb565::BLUE));
+    }
+
+    #[test]
+    fn doubled_frames_expand_into_doubled_displays() {
+        let mut indexes = [0; Depth::Bpp4.bytes(Resolution::Doubled)];
+        let mut frame =
+            IndexedFramebuffer::with_resolution(&mut indexes, Depth::Bpp4, Resolution::Doubled);
+        frame.set_palette(palette());
+        let mut pixels = [0; Resolution::Doubled.pixels()];
+        let mut display = Framebuffer::doubled(&mut pixels);
+
+        let _ = frame.draw_iter([
+            Pixel(Point::new(119, 119), PaletteIndex(2)),
+            Pixel(Point::new(120, 0), PaletteIndex(2)),
+        ]);
+        let _ = frame.expand_into(&mut display);
+
+        assert_eq!(frame.size(), Size::new(120, 120));
+        assert_eq!(display.pixel(Point::new(119, 119)), Some(Rgb565::GREEN));
+        assert_eq!(display.pixel(Point::new(0, 0)), Some(Rgb565::BLACK));
+    }
+
+    #[test]
+    fn expands_doubled_rows_to_both_panel_rows() {
+        let mut indexes = [0; Depth::Bpp8.bytes(Resolution::Doubled)];
+        let mut frame =
+            IndexedFramebuffer::with_resolution(&mut indexes, Depth::Bpp8, Resolution::Doubled);
+        frame.set_palette(palette());
+        let _ = frame.draw_iter([Pixel(Point::new(1, 0), PaletteIndex(3))]);
+        let mut line = [0; LINE_BYTES];
+
+        let area = Rectangle::new(Point::zero(), Size::new(2, 1));
+        let rows = frame.expand_row(&area, 0, &mut line);
+
+        let blue = RawU16::from(Rgb565::BLUE)
+            .into_inner()
+            .to_be()
+            .to_ne_bytes();
+        let black = [0, 0];
+        let row = [black, black, blue, blue].concat();
+        assert_eq!(rows, [row.clone(), row].concat());
+    }
+}
@@ -1,5 +1,6 @@
 /// ST7789 instructions.
 #[repr(u8)]
+#[allow(clippy::upper_case_acronyms)] // named as in the datasheet
 pub enum Instruction {
     NOP = 0x00,
     SWRESET = 0x01,
@@ -3,25 +3,38 @@
 
 //! This crate provides a ST7789 driver to connect to TFT displays.
 
-mod instruction;
+pub mod instruction;
 
 use core::iter::once;
 // use instruction::Instruction;
 
-use defmt::info;
-use display_interface::DataFormat::{U16BEIter, U16LEIter, U8Iter};
+use display_interface::DataFormat::{U16BEIter, U16LEIter, U8Iter, U8};
 use display_interface::{AsyncWriteOnlyDataCommand, DisplayError};
+use embassy_time::{Duration, Timer};
 use embedded_graphics::pixelcolor::raw::RawU16;
 use embedded_graphics::prelude::RawData;
 use embedded_hal_1::delay::DelayNs;
 use embedded_hal_1::digital::OutputPin;
-use graphics::{framebuffer, HEIGHT, WIDTH};
+use framebuffer::{Framebuffer, Resolution, DOUBLED_LINE_BYTES};
+use graphics::{HEIGHT, WIDTH};
+use indexed::IndexedFramebuffer;
 use instruction::Instruction;
 
+pub mod framebuffer;
+
+pub mod dirty;
+
+pub mod blend;
+
+pub mod indexed;
+
 pub mod graphics;
 
 pub mod batch;
 
+#[cfg(feature = "simulator")]
+pub mod simulator;
+
 ///
 /// ST7789 driver to connect to TFT displays.
 ///
@@ -40,23 +53,40 @@ where
     size_y: u16,
     // Current orientation
     orientation: Orientation,
+    // Frame drawn into and sent by `shotgun`
+    framebuffer: Option<Framebuffer<'static>>,
 }
 
 ///
 /// Display orientation.
 ///
 #[repr(u8)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Default)]
 pub enum Orientation {
-    Portrait = 0b0000_0000,         // no inverting
-    Landscape = 0b0110_0000,        // invert column and page/column order
-    PortraitSwapped = 0b1100_0000,  // invert page and column order
-    LandscapeSwapped = 0b1010_0000, // invert page and page/column order
+    /// No inverting
+    #[default]
+    Portrait = 0b0000_0000,
+    /// Invert column and page/column order
+    Landscape = 0b0110_0000,
+    /// Invert page and column order
+    PortraitSwapped = 0b1100_0000,
+    /// Invert page and page/column order
+    LandscapeSwapped = 0b1010_0000,
 }
 
-impl Default for Orientation {
-    fn default() -> Self {
-        Self::Portrait
+impl Orientation {
+    ///
+    /// Offset (x, y) from drawing coordinates to controller RAM addresses.
+    /// The controller has 240x320 RAM but the panel only shows its first 240 rows,
+    /// so orientations that mirror rows have to skip the 80 rows that are not visible.
+    ///
+    pub fn address_offset(self) -> (u16, u16) {
+        const HIDDEN_ROWS: u16 = 80;
+        match self {
+            Orientation::Portrait | Orientation::Landscape => (0, 0),
+            Orientation::PortraitSwapped => (0, HIDDEN_ROWS),
+            Orientation::LandscapeSwapped => (HIDDEN_ROWS, 0),
+        }
     }
 }
 
@@ -80,6 +110,7 @@ pub enum TearingEffect {
 pub enum Error<PinE> {
     DisplayError,
     Pin(PinE),
+    NoFramebuffer,
 }
 
 impl<DI, RST, PinE> ST7789<DI, RST>
@@ -105,6 +136,7 @@ where
             size_x,
             size_y,
             orientation: Orientation::default(),
+            framebuffer: None,
         }
     }
 
@@ -166,6 +198,30 @@ where
         Ok(())
     }
 
+    ///
+    /// Turns the panel off and puts the controller to sleep, its memory is kept.
+    /// It has to stay asleep for 120ms before [`wake`](Self::wake).
+    /// The waits are timers, so other tasks keep running meanwhile.
+    ///
+    pub async fn sleep(&mut self) -> Result<(), Error<PinE>> {
+        self.write_command(Instruction::DISPOFF).await?;
+        self.write_command(Instruction::SLPIN).await?;
+        // The controller takes commands again after 5ms
+        Timer::after(Duration::from_millis(5)).await;
+        Ok(())
+    }
+
+    ///
+    /// Wakes the controller and turns the panel back on, showing what it showed before sleeping
+    ///
+    pub async fn wake(&mut self) -> Result<(), Error<PinE>> {
+        self.write_command(Instruction::SLPOUT).await?;
+        // The supply voltages settle before the panel goes on
+        Timer::after(Duration::from_millis(120)).await;
+        self.write_command(Instruction::DISPON).await?;
+        Ok(())
+    }
+
     ///
     /// Returns currently set orientation
     ///
@@ -174,12 +230,17 @@ where
     }
 
     ///
-    /// Sets display orientation
+    /// Sets display orientation.
+    /// Drawing coordinates stay the same 240x240, the controller rotates them onto the panel.
+    /// The whole framebuffer is sent again by the next `shotgun_dirty` as it is now shown rotated.
     ///
     pub async fn set_orientation(&mut self, orientation: Orientation) -> Result<(), Error<PinE>> {
         self.write_command(Instruction::MADCTL).await?;
         self.write_data(&[orientation as u8]).await?;
         self.orientation = orientation;
+        if let Some(framebuffer) = self.framebuffer.as_mut() {
+            framebuffer.mark_all_dirty();
+        }
         Ok(())
     }
 
@@ -233,12 +294,147 @@ where
             .map_err(|_| Error::DisplayError)
     }
 
+    ///
+    /// Hands the display a framebuffer to draw into and send with `shotgun`.
+    /// Returns the framebuffer it had before.
+    ///
+    pub fn set_framebuffer(
+        &mut self,
+        framebuffer: Framebuffer<'static>,
+    ) -> Option<Framebuffer<'static>> {
+        self.framebuffer.replace(framebuffer)
+    }
+
+    ///
+    /// Takes the framebuffer back from the display
+    ///
+    pub fn take_framebuffer(&mut self) -> Option<Framebuffer<'static>> {
+        self.framebuffer.take()
+    }
+
+    ///
+    /// Sends the framebuffer to the display.
+    /// The buffer already holds the pixels in wire order so it goes out as one SPI write,
+    /// which embassy-rp does with DMA. Pixel doubled frames are scaled row by row instead.
+    ///
     pub async fn shotgun(&mut self) -> Result<(), Error<PinE>> {
-        let pixels = framebuffer();
+        let framebuffer = self.framebuffer.as_mut().ok_or(Error::NoFramebuffer)?;
+        if framebuffer.resolution() == Resolution::Doubled {
+            framebuffer.mark_all_dirty();
+            return self.shotgun_dirty().await;
+        }
+
+        self.set_address_window(0, 0, WIDTH as u16 - 1, HEIGHT as u16 - 1)
+            .await?;
+        self.write_command(Instruction::RAMWR).await?;
+        let framebuffer = self.framebuffer.as_mut().ok_or(Error::NoFramebuffer)?;
         self.di
-            .send_data(U16LEIter(&mut pixels.iter().cloned()))
+            .send_data(U8(framebuffer.as_bytes()))
             .await
-            .map_err(|_| Error::DisplayError)
+            .map_err(|_| Error::DisplayError)?;
+        framebuffer.clear_dirty();
+        Ok(())
+    }
+
+    ///
+    /// Sends only the regions of the framebuffer drawn into since it was last sent.
+    /// Each region gets its own address window, full width regions still go out as one write.
+    ///
+    pub async fn shotgun_dirty(&mut self) -> Result<(), Error<PinE>> {
+        // Taken out while sending, the regions borrow it across the display writes
+        let mut framebuffer = self.framebuffer.take().ok_or(Error::NoFramebuffer)?;
+        let result = self.send_regions(&framebuffer).await;
+        if result.is_ok() {
+            framebuffer.clear_dirty();
+        }
+        self.framebuffer = Some(framebuffer);
+        result
+    }
+
+    async fn send_regions(&mut self, framebuffer: &Framebuffer<'_>) -> Result<(), Error<PinE>> {
+        let scale = match framebuffer.resolution() {
+            Resolution::Full => 1,
+            Resolution::Doubled => 2,
+        };
+        let mut line = [0u8; DOUBLED_LINE_BYTES];
+
+        for area in framebuffer.dirty().iter() {
+            let Some(bottom_right) = area.bottom_right() else {
+                continue;
+            };
+            self.set_address_window(
+                area.top_left.x as u16 * scale,
+                area.top_left.y as u16 * scale,
+                (bottom_right.x as u16 + 1) * scale - 1,
+                (bottom_right.y as u16 + 1) * scale - 1,
+            )
+            .await?;
+            self.write_command(Instruction::RAMWR).await?;
+
+            match framebuffer.resolution() {
+                Resolution::Full => {
+                    for run in framebuffer.area_bytes(area) {
+                        self.di
+                            .send_data(U8(run))
+                            .await
+                            .map_err(|_| Error::DisplayError)?;
+                    }
+                }
+                Resolution::Doubled => {
+                    for row in 0..area.size.height as usize {
+                        let rows = framebuffer.doubled_row(area, row, &mut line);
+                        self.di
+                            .send_data(U8(rows))
+                            .await
+                            .map_err(|_| Error::DisplayError)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Sends the changed regions of an indexed frame, looking each pixel up in its palette
+    /// one row at a time on the way out.
+    ///
+    /// # Arguments
+    ///
+    /// * `frame` - frame to send, its dirty regions are cleared once they are sent
+    ///
+    pub async fn shotgun_indexed(
+        &mut self,
+        frame: &mut IndexedFramebuffer<'_>,
+    ) -> Result<(), Error<PinE>> {
+        let scale = match frame.resolution() {
+            Resolution::Full => 1,
+            Resolution::Doubled => 2,
+        };
+        let mut line = [0u8; indexed::LINE_BYTES];
+        for area in frame.dirty().iter() {
+            let Some(bottom_right) = area.bottom_right() else {
+                continue;
+            };
+            self.set_address_window(
+                area.top_left.x as u16 * scale,
+                area.top_left.y as u16 * scale,
+                (bottom_right.x as u16 + 1) * scale - 1,
+                (bottom_right.y as u16 + 1) * scale - 1,
+            )
+            .await?;
+            self.write_command(Instruction::RAMWR).await?;
+            for row in 0..area.size.height as usize {
+                let colors = frame.expand_row(area, row, &mut line);
+                self.di
+                    .send_data(U8(colors))
+                    .await
+                    .map_err(|_| Error::DisplayError)?;
+            }
+        }
+
+        frame.clear_dirty();
+        Ok(())
     }
 
     ///
@@ -276,7 +472,7 @@ where
             .map_err(|_| Error::DisplayError)
     }
 
-    // Sets the address window for the display.
+    // Sets the address window for the display, in drawing coordinates for the current orientation.
     async fn set_address_window(
         &mut self,
         sx: u16,
@@ -284,6 +480,8 @@ where
         ex: u16,
         ey: u16,
     ) -> Result<(), Error<PinE>> {
+        let (ox, oy) = self.orientation.address_offset();
+        let (sx, ex, sy, ey) = (sx + ox, ex + ox, sy + oy, ey + oy);
         self.write_command(Instruction::CASET).await?;
         self.write_data(&sx.to_be_bytes()).await?;
         self.write_data(&ex.to_be_bytes()).await?;
@@ -318,7 +516,7 @@ where
     ) -> Result<(), DisplayError> {
         let color = RawU16::from(color).into_inner().to_be();
         for _ in 0..2 {
-            let colors = core::iter::repeat(RawU16::from(color).into_inner()).take(WIDTH * HEIGHT);
+            let colors = core::iter::repeat_n(RawU16::from(color).into_inner(), WIDTH * HEIGHT);
             let _ = self
                 .set_pixels(0, 0, (WIDTH - 1) as u16, (HEIGHT - 1) as u16, colors)
                 .await;
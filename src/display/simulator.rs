@@ -0,0 +1,498 @@
+//! Desktop stand-in for the PicoSystem display.
+//! The ST7789 driver talks to an emulated controller instead of the SPI bus, so the same
+//! `DrawTarget` and `shotgun()` used on the device end up in a window or a PNG dump.
+
+use std::{cell::RefCell, convert::Infallible, path::PathBuf, rc::Rc};
+
+use display_interface::{AsyncWriteOnlyDataCommand, DataFormat, DisplayError};
+use embassy_time::{Delay, Duration, Instant, Timer};
+use embedded_graphics::{
+    pixelcolor::{raw::RawU16, Rgb565, Rgb888},
+    prelude::*,
+    primitives::Rectangle,
+};
+use embedded_graphics_simulator::{
+    sdl2::Keycode, OutputSettings, OutputSettingsBuilder, SimulatorDisplay, SimulatorEvent, Window,
+};
+use embedded_hal_1::digital::{ErrorType, OutputPin};
+
+use super::framebuffer::Framebuffer;
+use super::graphics::{HEIGHT, RESOLUTION, WIDTH};
+use super::instruction::Instruction;
+use super::ST7789;
+use crate::audio;
+use crate::backlight;
+use crate::hal::PicoSystemHal;
+use crate::input::{events, ButtonId, Buttons};
+use crate::led;
+use crate::power::{self, PowerStatus};
+
+/// Frame memory of the ST7789, the PicoSystem panel only shows the top 240 rows
+const RAM_WIDTH: usize = 240;
+const RAM_HEIGHT: usize = 320;
+
+const CASET: u8 = Instruction::CASET as u8;
+const RASET: u8 = Instruction::RASET as u8;
+const RAMWR: u8 = Instruction::RAMWR as u8;
+const MADCTL: u8 = Instruction::MADCTL as u8;
+const DISPOFF: u8 = Instruction::DISPOFF as u8;
+const DISPON: u8 = Instruction::DISPON as u8;
+
+// MADCTL bits
+const MY: u8 = 0b1000_0000;
+const MX: u8 = 0b0100_0000;
+const MV: u8 = 0b0010_0000;
+
+/// The PicoSystem panel refreshes at about 60Hz, which paces the window like `wait_vsync`
+const FRAME_TIME: Duration = Duration::from_micros(16_667);
+
+/// The display driver wired up to the simulator
+pub type SimulatorST7789 = ST7789<SimulatorInterface, NoPin>;
+
+/// Decodes the command stream sent by the driver into frame memory, like the real controller.
+struct Controller {
+    ram: Vec<u16>,
+    command: u8,
+    params: heapless::Vec<u8, 4>,
+    columns: (u16, u16),
+    rows: (u16, u16),
+    cursor: (u16, u16),
+    high_byte: Option<u8>,
+    madctl: u8,
+    /// The panel shows frame memory, DISPOFF blanks it
+    display_on: bool,
+}
+
+impl Controller {
+    fn new() -> Self {
+        Self {
+            ram: vec![0; RAM_WIDTH * RAM_HEIGHT],
+            command: 0,
+            params: heapless::Vec::new(),
+            columns: (0, RAM_WIDTH as u16 - 1),
+            rows: (0, RAM_HEIGHT as u16 - 1),
+            cursor: (0, 0),
+            high_byte: None,
+            madctl: 0,
+            display_on: true,
+        }
+    }
+
+    fn command(&mut self, command: u8) {
+        self.command = command;
+        self.params.clear();
+        self.high_byte = None;
+        match command {
+            RAMWR => self.cursor = (self.columns.0, self.rows.0),
+            DISPOFF => self.display_on = false,
+            DISPON => self.display_on = true,
+            _ => {}
+        }
+    }
+
+    fn data(&mut self, byte: u8) {
+        match self.command {
+            RAMWR => match self.high_byte.take() {
+                None => self.high_byte = Some(byte),
+                Some(high) => self.write_pixel(u16::from_be_bytes([high, byte])),
+            },
+            CASET | RASET => {
+                let _ = self.params.push(byte);
+                if let [sh, sl, eh, el] = self.params[..] {
+                    let range = (u16::from_be_bytes([sh, sl]), u16::from_be_bytes([eh, el]));
+                    match self.command {
+                        CASET => self.columns = range,
+                        _ => self.rows = range,
+                    }
+                }
+            }
+            MADCTL => self.madctl = byte,
+            // INVON is needed for correct colours on the PicoSystem panel, so the simulator
+            // treats inverted as normal and ignores everything else.
+            _ => {}
+        }
+    }
+
+    fn write_pixel(&mut self, color: u16) {
+        let (column, row) = self.cursor;
+        if let Some(index) = self.ram_index(column, row) {
+            self.ram[index] = color;
+        }
+
+        self.cursor = if column < self.columns.1 {
+            (column + 1, row)
+        } else if row < self.rows.1 {
+            (self.columns.0, row + 1)
+        } else {
+            (self.columns.0, self.rows.0)
+        };
+    }
+
+    /// Maps a memory write address to frame memory following MADCTL
+    fn ram_index(&self, column: u16, row: u16) -> Option<usize> {
+        let (mut x, mut y) = match self.madctl & MV != 0 {
+            true => (row as usize, column as usize),
+            false => (column as usize, row as usize),
+        };
+        if self.madctl & MX != 0 {
+            x = (RAM_WIDTH - 1).checked_sub(x)?;
+        }
+        if self.madctl & MY != 0 {
+            y = (RAM_HEIGHT - 1).checked_sub(y)?;
+        }
+
+        (x < RAM_WIDTH && y < RAM_HEIGHT).then_some(x + y * RAM_WIDTH)
+    }
+
+    /// Color of a visible pixel, as it is shown on the panel
+    fn pixel(&self, x: usize, y: usize) -> Rgb565 {
+        if !self.display_on {
+            return Rgb565::BLACK;
+        }
+        RawU16::new(self.ram[x + y * RAM_WIDTH]).into()
+    }
+}
+
+/// Feeds every byte of `format` to `f` in the order it would go over the SPI bus
+fn for_each_byte(format: DataFormat<'_>, mut f: impl FnMut(u8)) -> Result<(), DisplayError> {
+    match format {
+        DataFormat::U8(bytes) => bytes.iter().for_each(|byte| f(*byte)),
+        DataFormat::U16(words) => words.iter().flat_map(|w| w.to_ne_bytes()).for_each(f),
+        DataFormat::U16BE(words) => words.iter().flat_map(|w| w.to_be_bytes()).for_each(f),
+        DataFormat::U16LE(words) => words.iter().flat_map(|w| w.to_le_bytes()).for_each(f),
+        DataFormat::U8Iter(bytes) => bytes.for_each(f),
+        DataFormat::U16BEIter(words) => words.flat_map(u16::to_be_bytes).for_each(f),
+        DataFormat::U16LEIter(words) => words.flat_map(u16::to_le_bytes).for_each(f),
+        _ => return Err(DisplayError::DataFormatNotImplemented),
+    }
+    Ok(())
+}
+
+///
+/// Display interface writing into the simulated controller instead of the SPI bus.
+///
+pub struct SimulatorInterface {
+    controller: Rc<RefCell<Controller>>,
+}
+
+impl AsyncWriteOnlyDataCommand for SimulatorInterface {
+    async fn send_commands(&mut self, cmd: DataFormat<'_>) -> Result<(), DisplayError> {
+        let mut controller = self.controller.borrow_mut();
+        for_each_byte(cmd, |byte| controller.command(byte))
+    }
+
+    async fn send_data(&mut self, buf: DataFormat<'_>) -> Result<(), DisplayError> {
+        let mut controller = self.controller.borrow_mut();
+        for_each_byte(buf, |byte| controller.data(byte))
+    }
+}
+
+///
+/// Reset pin for the simulated display, which has nothing to reset.
+///
+pub struct NoPin;
+
+impl ErrorType for NoPin {
+    type Error = Infallible;
+}
+
+impl OutputPin for NoPin {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+struct Capture {
+    dir: PathBuf,
+    frames: u32,
+}
+
+///
+/// Shows the simulated panel in a window or dumps it to PNGs, and turns the keyboard into
+/// PicoSystem buttons.
+///
+/// Keys: arrows for the d-pad, `Z` for A, `X` for B, `A` for X and `S` for Y.
+///
+pub struct Simulator {
+    display: SimulatorST7789,
+    controller: Rc<RefCell<Controller>>,
+    screen: SimulatorDisplay<Rgb565>,
+    output_settings: OutputSettings,
+    window: Option<Window>,
+    capture: Option<Capture>,
+    frame: u32,
+    last_update: Instant,
+    buttons: Buttons,
+    /// The window was closed or all frames were captured
+    quit: bool,
+}
+
+impl Simulator {
+    ///
+    /// Opens a desktop window for the panel
+    ///
+    /// # Arguments
+    ///
+    /// * `title` - window title
+    /// * `scale` - size of a panel pixel on the desktop
+    ///
+    pub fn windowed(title: &str, scale: u32) -> Self {
+        let output_settings = OutputSettingsBuilder::new().scale(scale).build();
+        let window = Window::new(title, &output_settings);
+        Self::new(output_settings, Some(window), None)
+    }
+
+    ///
+    /// Runs without a window, saving every presented frame as `frame_NNNN.png`
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - directory the frames are written to
+    /// * `frames` - number of frames to capture before `update` returns false
+    ///
+    pub fn headless(dir: impl Into<PathBuf>, frames: u32) -> Self {
+        let output_settings = OutputSettingsBuilder::new().build();
+        let capture = Capture {
+            dir: dir.into(),
+            frames,
+        };
+        Self::new(output_settings, None, Some(capture))
+    }
+
+    fn new(
+        output_settings: OutputSettings,
+        window: Option<Window>,
+        capture: Option<Capture>,
+    ) -> Self {
+        let controller = Rc::new(RefCell::new(Controller::new()));
+        let di = SimulatorInterface {
+            controller: controller.clone(),
+        };
+        let mut display = ST7789::new(di, None, WIDTH as u16, HEIGHT as u16);
+        let pixels = vec![0; RESOLUTION.pixels()].leak();
+        display.set_framebuffer(Framebuffer::with_resolution(pixels, RESOLUTION));
+        embassy_futures::block_on(async {
+            let _ = display.init(&mut Delay).await;
+            let _ = display.clear_screen(Rgb565::BLACK).await;
+        });
+
+        Self {
+            display,
+            controller,
+            screen: SimulatorDisplay::new(Size::new(WIDTH as u32, HEIGHT as u32)),
+            output_settings,
+            window,
+            capture,
+            frame: 0,
+            last_update: Instant::now(),
+            buttons: Buttons::NONE,
+            quit: false,
+        }
+    }
+
+    ///
+    /// Presents what the panel shows, call it after `shotgun()` when not using [`PicoSystemHal`].
+    /// Returns false once the window is closed or all frames have been captured.
+    ///
+    pub fn update(&mut self) -> bool {
+        {
+            let controller = self.controller.borrow();
+            let colors = (0..HEIGHT).flat_map(|y| (0..WIDTH).map(move |x| (x, y)));
+            let area = Rectangle::new(Point::zero(), self.screen.size());
+            let _ = self
+                .screen
+                .fill_contiguous(&area, colors.map(|(x, y)| controller.pixel(x, y)));
+        }
+        self.frame += 1;
+
+        if let Some(capture) = &self.capture {
+            let path = capture.dir.join(format!("frame_{:04}.png", self.frame));
+            self.screen
+                .to_rgb_output_image(&self.output_settings)
+                .save_png(path)
+                .expect("failed to write simulator frame");
+            if self.frame >= capture.frames {
+                return false;
+            }
+        }
+
+        if let Some(window) = &mut self.window {
+            window.update(&self.screen);
+            for event in window.events() {
+                match event {
+                    SimulatorEvent::Quit => return false,
+                    SimulatorEvent::KeyDown { keycode, .. } => {
+                        if let Some(button) = key_button(keycode) {
+                            self.buttons.set(button, true);
+                        }
+                    }
+                    SimulatorEvent::KeyUp { keycode, .. } => {
+                        if let Some(button) = key_button(keycode) {
+                            self.buttons.set(button, false);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            events::publish(self.buttons, Instant::now());
+        }
+
+        true
+    }
+
+    /// Backlight level and the colour the led shows right now
+    pub fn lights(&self) -> (u8, Rgb888) {
+        let now = Instant::now();
+        (
+            backlight::level_at(now).0,
+            led::color_at(now, &power::current()).0,
+        )
+    }
+
+    /// Color shown by the panel at `point`, for checking frames in tests
+    pub fn pixel(&self, point: Point) -> Option<Rgb565> {
+        let (x, y) = (point.x as usize, point.y as usize);
+        (point.x >= 0 && point.y >= 0 && x < WIDTH && y < HEIGHT)
+            .then(|| self.controller.borrow().pixel(x, y))
+    }
+}
+
+impl PicoSystemHal for Simulator {
+    type Display = SimulatorST7789;
+
+    fn display(&mut self) -> &mut Self::Display {
+        &mut self.display
+    }
+
+    /// Paces a window at the panel's refresh rate without holding up other tasks, captures
+    /// run as fast as they can
+    async fn wait_vsync(&mut self) {
+        if self.window.is_some() {
+            Timer::at(self.last_update + FRAME_TIME).await;
+            self.last_update = Instant::now();
+        }
+    }
+
+    async fn present(&mut self) {
+        self.wait_vsync().await;
+        let _ = self.display.shotgun_dirty().await;
+        // Closing the window ends the game, like switching the PicoSystem off
+        if !self.update() {
+            self.quit = true;
+        }
+    }
+
+    fn quit_requested(&self) -> bool {
+        self.quit
+    }
+
+    /// Buttons currently held on the keyboard
+    fn buttons(&self) -> Buttons {
+        self.buttons
+    }
+
+    fn set_backlight(&mut self, brightness: u8) {
+        backlight::set_brightness(brightness);
+    }
+
+    fn set_backlight_dimmed(&mut self, dimmed: bool) {
+        backlight::set_dimmed(dimmed);
+    }
+
+    fn set_led(&mut self, color: Rgb888) {
+        led::set_color(color);
+    }
+
+    fn pulse_led(&mut self, color: Rgb888, duration: Duration) {
+        led::pulse(color, duration);
+    }
+
+    async fn play_tone(&mut self, frequency: u32, duration: Duration) {
+        audio::play(audio::Tone::new(frequency, duration, 100)).await;
+        audio::wait_idle().await;
+    }
+
+    /// Nothing samples a battery on the desktop, it is full unless something publishes another
+    fn power(&mut self) -> PowerStatus {
+        power::current()
+    }
+
+    /// Blanks the window until a key is pressed or it is closed, captures carry on straight away
+    async fn sleep(&mut self) {
+        let _ = self.display.sleep().await;
+        if self.window.is_some() {
+            // Like the buttons on the PicoSystem, keys held down have to be pressed again
+            let mut held = self.buttons;
+            loop {
+                self.wait_vsync().await;
+                if !self.update() {
+                    self.quit = true;
+                    break;
+                }
+                if self.buttons.bits() & !held.bits() != 0 {
+                    break;
+                }
+                held = Buttons(held.bits() & self.buttons.bits());
+            }
+        }
+        let _ = self.display.wake().await;
+    }
+}
+
+fn key_button(keycode: Keycode) -> Option<ButtonId> {
+    match keycode {
+        Keycode::Up => Some(ButtonId::Up),
+        Keycode::Down => Some(ButtonId::Down),
+        Keycode::Left => Some(ButtonId::Left),
+        Keycode::Right => Some(ButtonId::Right),
+        Keycode::Z => Some(ButtonId::A),
+        Keycode::X => Some(ButtonId::B),
+        Keycode::A => Some(ButtonId::X),
+        Keycode::S => Some(ButtonId::Y),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::Orientation;
+
+    /// Where a pixel drawn at `point` ends up in frame memory, the panel shows x and y below 240
+    fn shown_at(orientation: Orientation, point: (u16, u16)) -> (usize, usize) {
+        let controller = Rc::new(RefCell::new(Controller::new()));
+        let di = SimulatorInterface {
+            controller: controller.clone(),
+        };
+        let mut display: SimulatorST7789 = ST7789::new(di, None, WIDTH as u16, HEIGHT as u16);
+        embassy_futures::block_on(async {
+            display.set_orientation(orientation).await.unwrap();
+            display.set_pixel(point.0, point.1, 0xf800).await.unwrap();
+        });
+
+        let ram = &controller.borrow().ram;
+        let written: Vec<usize> = (0..ram.len()).filter(|&i| ram[i] != 0).collect();
+        assert_eq!(written.len(), 1);
+        (written[0] % RAM_WIDTH, written[0] / RAM_WIDTH)
+    }
+
+    #[test]
+    fn every_orientation_rotates_onto_the_visible_rows() {
+        // Near the top left and the top right corner, rotated clockwise for landscape
+        let cases = [
+            (Orientation::Portrait, (2, 1), (237, 1)),
+            (Orientation::Landscape, (238, 2), (238, 237)),
+            (Orientation::PortraitSwapped, (237, 238), (2, 238)),
+            (Orientation::LandscapeSwapped, (1, 237), (1, 2)),
+        ];
+        for (orientation, top_left, top_right) in cases {
+            let name = orientation as u8;
+            assert_eq!(shown_at(orientation, (2, 1)), top_left, "{:#010b}", name);
+            assert_eq!(shown_at(orientation, (237, 1)), top_right, "{:#010b}", name);
+        }
+    }
+}
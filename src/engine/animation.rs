@@ -0,0 +1,356 @@
+//! Sprite sheets and frame animations.
+//! Animations are plain data, usually consts, and an [`Animator`] works out from the time
+//! which of their frames to show, so skipped or slow game frames never put them out of step.
+
+use embassy_time::{Duration, Instant};
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::Rectangle;
+use tinybmp::Bmp;
+
+use crate::engine::sprite::Sprite;
+
+///
+/// Frames of the same size laid out left to right, top to bottom in a bitmap.
+///
+#[derive(Copy, Clone)]
+pub struct SpriteSheet<'a> {
+    image: Bmp<'a, Rgb565>,
+    frame_size: Size,
+    columns: u32,
+}
+
+impl<'a> SpriteSheet<'a> {
+    ///
+    /// Slices `image` into frames, leftover pixels on the right and bottom are ignored
+    ///
+    /// # Arguments
+    ///
+    /// * `image` - bitmap holding the frames
+    /// * `frame_size` - size of one frame in pixels
+    ///
+    pub fn new(image: Bmp<'a, Rgb565>, frame_size: Size) -> Self {
+        let columns = image.size().width / frame_size.width.max(1);
+        Self {
+            image,
+            frame_size,
+            columns,
+        }
+    }
+
+    pub fn image(&self) -> Bmp<'a, Rgb565> {
+        self.image
+    }
+
+    pub fn frame_size(&self) -> Size {
+        self.frame_size
+    }
+
+    /// Number of frames on the sheet
+    pub fn len(&self) -> u16 {
+        let rows = self.image.size().height / self.frame_size.height.max(1);
+        (self.columns * rows).min(u16::MAX as u32) as u16
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Area of the image holding frame `index`, if the sheet has it
+    pub fn frame(&self, index: u16) -> Option<Rectangle> {
+        (index < self.len()).then(|| {
+            let (column, row) = (index as u32 % self.columns, index as u32 / self.columns);
+            Rectangle::new(
+                Point::new(
+                    (column * self.frame_size.width) as i32,
+                    (row * self.frame_size.height) as i32,
+                ),
+                self.frame_size,
+            )
+        })
+    }
+
+    /// A sprite showing frame `index`
+    pub fn sprite(&self, index: u16, position: Point) -> Sprite<'a> {
+        let mut sprite = Sprite::new(self.image, position);
+        if let Some(frame) = self.frame(index) {
+            sprite.set_source(frame);
+        }
+        sprite
+    }
+}
+
+/// One step of an animation
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    /// Frame on the sprite sheet
+    pub index: u16,
+    /// How long it is shown
+    pub duration: Duration,
+}
+
+impl Frame {
+    pub const fn new(index: u16, millis: u64) -> Self {
+        Self {
+            index,
+            duration: Duration::from_millis(millis),
+        }
+    }
+}
+
+/// What an animation does once it reaches its last frame
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum PlayMode {
+    /// Starts over from the first frame
+    #[default]
+    Loop,
+    /// Plays backwards to the first frame, then forwards again
+    PingPong,
+    /// Stays on the last frame
+    Once,
+}
+
+///
+/// A named sequence of frames.
+///
+#[derive(Copy, Clone, Debug)]
+pub struct Animation<'a> {
+    pub name: &'a str,
+    pub frames: &'a [Frame],
+    pub mode: PlayMode,
+}
+
+impl<'a> Animation<'a> {
+    pub const fn new(name: &'a str, frames: &'a [Frame], mode: PlayMode) -> Self {
+        Self { name, frames, mode }
+    }
+
+    /// Number of steps before the animation repeats, ping-pong does not repeat its ends
+    fn steps(&self) -> usize {
+        match (self.mode, self.frames.len()) {
+            (PlayMode::PingPong, frames) if frames > 2 => frames * 2 - 2,
+            (_, frames) => frames,
+        }
+    }
+
+    /// Frame shown at `step`, counting from 0
+    fn step(&self, step: usize) -> &Frame {
+        let last = self.frames.len() - 1;
+        match step > last {
+            true => &self.frames[2 * last - step],
+            false => &self.frames[step],
+        }
+    }
+
+    ///
+    /// Frame shown `elapsed` after the animation started, with whether it has finished.
+    /// Only `Once` animations finish.
+    ///
+    pub fn frame_at(&self, elapsed: Duration) -> Option<(&Frame, bool)> {
+        if self.frames.is_empty() {
+            return None;
+        }
+
+        let steps = self.steps();
+        let total: u64 = (0..steps)
+            .map(|step| self.step(step).duration.as_ticks())
+            .sum();
+        let mut ticks = elapsed.as_ticks();
+        if total == 0 || (self.mode == PlayMode::Once && ticks >= total) {
+            return Some((self.step(steps - 1), self.mode == PlayMode::Once));
+        }
+
+        ticks %= total;
+        for step in 0..steps {
+            let frame = self.step(step);
+            match ticks.checked_sub(frame.duration.as_ticks()) {
+                Some(left) => ticks = left,
+                None => return Some((frame, false)),
+            }
+        }
+        Some((self.step(steps - 1), false))
+    }
+}
+
+///
+/// Plays one of a set of animations at a time.
+///
+pub struct Animator<'a> {
+    animations: &'a [Animation<'a>],
+    current: usize,
+    started: Instant,
+}
+
+impl<'a> Animator<'a> {
+    /// Starts playing the first of `animations` at `now`
+    pub fn new(animations: &'a [Animation<'a>], now: Instant) -> Self {
+        Self {
+            animations,
+            current: 0,
+            started: now,
+        }
+    }
+
+    /// Switches to the animation called `name` from its first frame.
+    /// Returns false if there is none, asking for the one already playing carries on with it.
+    pub fn play(&mut self, name: &str, now: Instant) -> bool {
+        let Some(index) = self.animations.iter().position(|a| a.name == name) else {
+            return false;
+        };
+        if index != self.current {
+            self.current = index;
+            self.started = now;
+        }
+        true
+    }
+
+    /// Plays the current animation again from its first frame
+    pub fn restart(&mut self, now: Instant) {
+        self.started = now;
+    }
+
+    pub fn animation(&self) -> Option<&Animation<'a>> {
+        self.animations.get(self.current)
+    }
+
+    /// Sprite sheet index of the frame shown at `now`
+    pub fn frame(&self, now: Instant) -> Option<u16> {
+        let (frame, _) = self
+            .animation()?
+            .frame_at(now.saturating_duration_since(self.started))?;
+        Some(frame.index)
+    }
+
+    /// True once a `Once` animation is showing its last frame for good
+    pub fn finished(&self, now: Instant) -> bool {
+        self.animation()
+            .and_then(|animation| animation.frame_at(now.saturating_duration_since(self.started)))
+            .is_some_and(|(_, finished)| finished)
+    }
+
+    ///
+    /// Shows the current frame on `sprite`, which only redraws if the frame changed
+    ///
+    /// # Arguments
+    ///
+    /// * `sprite` - sprite made from `sheet`
+    /// * `sheet` - where the frames are
+    /// * `now` - current time
+    ///
+    pub fn update(&self, sprite: &mut Sprite<'_>, sheet: &SpriteSheet<'_>, now: Instant) {
+        if let Some(frame) = self.frame(now).and_then(|index| sheet.frame(index)) {
+            sprite.set_source(frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_graphics::pixelcolor::Rgb888;
+
+    use super::*;
+    use crate::engine::test_bmp;
+
+    const FRAMES: [Frame; 3] = [Frame::new(0, 10), Frame::new(1, 20), Frame::new(2, 10)];
+
+    fn index_at(animation: &Animation<'_>, millis: u64) -> (u16, bool) {
+        let (frame, finished) = animation.frame_at(Duration::from_millis(millis)).unwrap();
+        (frame.index, finished)
+    }
+
+    #[test]
+    fn slices_frames_out_of_the_sheet() {
+        // 3x2 frames of 2x2 pixels, with a leftover column on the right
+        let bmp = test_bmp(7, &[Rgb888::RED; 7 * 4]);
+        let sheet = SpriteSheet::new(Bmp::from_slice(&bmp).unwrap(), Size::new(2, 2));
+        assert_eq!(sheet.len(), 6);
+        assert!(!sheet.is_empty());
+        assert_eq!(
+            sheet.frame(4),
+            Some(Rectangle::new(Point::new(2, 2), Size::new(2, 2)))
+        );
+        assert_eq!(sheet.frame(6), None);
+        assert_eq!(
+            sheet.sprite(5, Point::zero()).source().top_left,
+            Point::new(4, 2)
+        );
+
+        let small = SpriteSheet::new(Bmp::from_slice(&bmp).unwrap(), Size::new(8, 8));
+        assert!(small.is_empty());
+    }
+
+    #[test]
+    fn loops_through_the_frames() {
+        let animation = Animation::new("walk", &FRAMES, PlayMode::Loop);
+        assert_eq!(index_at(&animation, 0), (0, false));
+        assert_eq!(index_at(&animation, 10), (1, false));
+        assert_eq!(index_at(&animation, 29), (1, false));
+        assert_eq!(index_at(&animation, 30), (2, false));
+        assert_eq!(index_at(&animation, 40), (0, false));
+        assert_eq!(index_at(&animation, 405), (0, false));
+    }
+
+    #[test]
+    fn ping_pong_does_not_repeat_its_ends() {
+        let animation = Animation::new("bob", &FRAMES, PlayMode::PingPong);
+        let indexes: Vec<u16> = (0..6)
+            .map(|step| index_at(&animation, step * 10).0)
+            .collect();
+        // Frame 1 lasts two steps each way
+        assert_eq!(indexes, [0, 1, 1, 2, 1, 1]);
+        assert_eq!(index_at(&animation, 60), (0, false));
+    }
+
+    #[test]
+    fn once_stays_on_the_last_frame() {
+        let animation = Animation::new("die", &FRAMES, PlayMode::Once);
+        assert_eq!(index_at(&animation, 39), (2, false));
+        assert_eq!(index_at(&animation, 40), (2, true));
+        assert_eq!(index_at(&animation, 1000), (2, true));
+        assert!(Animation::new("none", &[], PlayMode::Once)
+            .frame_at(Duration::from_millis(0))
+            .is_none());
+    }
+
+    #[test]
+    fn animator_switches_and_restarts() {
+        let animations = [
+            Animation::new("walk", &FRAMES, PlayMode::Loop),
+            Animation::new("die", &FRAMES[1..], PlayMode::Once),
+        ];
+        let start = Instant::from_millis(1000);
+        let mut animator = Animator::new(&animations, start);
+        assert_eq!(animator.frame(start + Duration::from_millis(15)), Some(1));
+
+        // Asking for the animation already playing carries on with it
+        assert!(animator.play("walk", start + Duration::from_millis(15)));
+        assert_eq!(animator.frame(start + Duration::from_millis(30)), Some(2));
+        assert!(!animator.play("jump", start));
+
+        let later = start + Duration::from_millis(100);
+        assert!(animator.play("die", later));
+        assert_eq!(animator.frame(later), Some(1));
+        assert!(!animator.finished(later));
+        assert!(animator.finished(later + Duration::from_millis(30)));
+
+        animator.restart(later + Duration::from_millis(50));
+        assert_eq!(animator.frame(later + Duration::from_millis(50)), Some(1));
+        assert!(!animator.finished(later + Duration::from_millis(50)));
+    }
+
+    #[test]
+    fn update_moves_the_sprite_to_the_frame() {
+        let bmp = test_bmp(6, &[Rgb888::RED; 6 * 2]);
+        let sheet = SpriteSheet::new(Bmp::from_slice(&bmp).unwrap(), Size::new(2, 2));
+        let animations = [Animation::new("walk", &FRAMES, PlayMode::Loop)];
+        let start = Instant::from_millis(0);
+        let animator = Animator::new(&animations, start);
+        let mut sprite = sheet.sprite(0, Point::zero());
+
+        animator.update(&mut sprite, &sheet, start + Duration::from_millis(35));
+        assert_eq!(
+            sprite.source(),
+            Rectangle::new(Point::new(4, 0), Size::new(2, 2))
+        );
+    }
+}
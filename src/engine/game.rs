@@ -0,0 +1,427 @@
+//! The game loop. A game implements [`Game`] and hands itself to [`run`], which updates it at
+//! a fixed rate however long frames take to draw, so games play at the same speed everywhere.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::DrawTarget;
+
+use crate::display::blend::Blend;
+use crate::hal::PicoSystemHal;
+use crate::input::replay::Replayer;
+use crate::input::state::InputState;
+use crate::input::Buttons;
+
+/// Time between updates by default, 60 per second to match the display
+pub const DEFAULT_STEP: Duration = Duration::from_micros(16_667);
+
+/// Most updates run to catch up before a frame is drawn, time past that is dropped
+/// so a long stall slows the game down instead of freezing it while it catches up.
+const MAX_CATCH_UP: u32 = 5;
+
+/// Time without touching a button before the runner puts the PicoSystem to sleep by default
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Time without touching a button before the runner dims the backlight by default
+pub const DEFAULT_DIM_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time the game keeps running after a button woke it with the battery nearly flat, long
+/// enough to save or plug in before it goes back to sleep
+pub const CRITICAL_GRACE: Duration = Duration::from_secs(30);
+
+///
+/// A game run by [`run`].
+///
+pub trait Game {
+    /// Called once before the first update, to draw the initial screen and set up the hardware
+    fn init(&mut self, _hal: &mut impl PicoSystemHal) {}
+
+    /// The input state the runner keeps, override to change debouncing or turn on auto-repeat
+    fn input(&self) -> InputState {
+        InputState::new()
+    }
+
+    /// Buttons for the next update given the ones held down, override to record or replay
+    /// them through an [`InputSource`](crate::input::replay::InputSource)
+    fn buttons(&mut self, live: Buttons) -> Buttons {
+        live
+    }
+
+    /// Time without a button held before the runner puts the PicoSystem to sleep, checked
+    /// every frame. None keeps it awake, for attract modes and cutscenes.
+    fn idle_timeout(&self) -> Option<Duration> {
+        Some(DEFAULT_IDLE_TIMEOUT)
+    }
+
+    /// Time without a button held before the runner dims the backlight, it lights up again on
+    /// the next press. None keeps it bright.
+    fn dim_timeout(&self) -> Option<Duration> {
+        Some(DEFAULT_DIM_TIMEOUT)
+    }
+
+    /// Called just before sleeping, to pause music or save progress
+    fn sleep(&mut self, _hal: &mut impl PicoSystemHal) {}
+
+    /// Called after a button woke the PicoSystem up, before the next update
+    fn wake(&mut self, _hal: &mut impl PicoSystemHal) {}
+
+    ///
+    /// Moves the game on by one fixed step
+    ///
+    /// # Arguments
+    ///
+    /// * `hal` - for the LED, the backlight and so on
+    /// * `input` - the buttons, sampled just before this update
+    /// * `dt` - time the step covers, always the runner's step
+    ///
+    fn update(&mut self, hal: &mut impl PicoSystemHal, input: &InputState, dt: Duration);
+
+    ///
+    /// Draws the next frame, it is presented once this returns
+    ///
+    /// # Arguments
+    ///
+    /// * `display` - frame to draw into, it still holds the previous frame
+    /// * `stats` - timing of the frames so far
+    ///
+    fn draw(&mut self, display: &mut (impl DrawTarget<Color = Rgb565> + Blend), stats: &FrameStats);
+}
+
+/// Stats of the game [`run`] is running, as of its last frame
+static STATS: Mutex<CriticalSectionRawMutex, Cell<Option<FrameStats>>> =
+    Mutex::new(Cell::new(None));
+
+/// Timing of the frames the running game presented so far, for debugging from outside the game
+pub fn stats() -> FrameStats {
+    STATS.lock(|stats| stats.get()).unwrap_or_default()
+}
+
+///
+/// Timing of the frames presented so far.
+///
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FrameStats {
+    /// Frames presented
+    pub frames: u32,
+    /// Time between the last two frames
+    pub last: Duration,
+    /// Frame time averaged over roughly the last 16 frames
+    pub average: Duration,
+    /// Longest frame time seen
+    pub worst: Duration,
+    /// Updates run before the last frame
+    pub updates: u32,
+    /// Updates skipped since the start because the game could not keep up
+    pub dropped: u32,
+}
+
+impl FrameStats {
+    /// Frames per second going by the average frame time
+    pub fn fps(&self) -> f32 {
+        match self.average.as_micros() {
+            0 => 0.0,
+            micros => 1_000_000.0 / micros as f32,
+        }
+    }
+
+    fn record(&mut self, frame: Duration, updates: u32) {
+        self.average = match self.frames {
+            0 => frame,
+            _ => Duration::from_ticks((self.average.as_ticks() * 15 + frame.as_ticks()) / 16),
+        };
+        self.frames += 1;
+        self.last = frame;
+        self.worst = self.worst.max(frame);
+        self.updates = updates;
+    }
+}
+
+/// Runs `game` until the HAL asks to quit, updating it 60 times a second
+pub async fn run(game: &mut impl Game, hal: &mut impl PicoSystemHal) {
+    run_with_step(game, hal, DEFAULT_STEP).await
+}
+
+///
+/// Runs `game` until [`PicoSystemHal::quit_requested`], which is forever on the PicoSystem:
+/// updates it at a fixed rate, draws it and presents every frame.
+/// Presenting waits for the display's vsync, so frames never tear.
+/// It dims the backlight and then sleeps as the game's idle timeouts pass, or sleeps straight
+/// away once the battery is about to run flat. Woken with a flat battery it gives the player
+/// [`CRITICAL_GRACE`] before sleeping again.
+///
+/// # Arguments
+///
+/// * `game` - the game to run
+/// * `hal` - the hardware to run it on
+/// * `step` - time between updates
+///
+pub async fn run_with_step(game: &mut impl Game, hal: &mut impl PicoSystemHal, step: Duration) {
+    let mut stats = FrameStats::default();
+    let mut input = game.input();
+    game.init(hal);
+
+    let mut behind = Duration::from_ticks(0);
+    let mut last_frame = Instant::now();
+    // Input is timed by updates rather than the clock, so a replay holds buttons just as long
+    let mut game_time = last_frame;
+    let mut last_input = last_frame;
+    let mut woke: Option<Instant> = None;
+    loop {
+        let mut updates = 0;
+        while behind >= step && updates < MAX_CATCH_UP {
+            game_time += step;
+            let live = hal.buttons();
+            if !live.is_empty() {
+                last_input = Instant::now();
+            }
+            let buttons = game.buttons(live);
+            input.update(buttons, game_time);
+            game.update(hal, &input, step);
+            behind -= step;
+            updates += 1;
+        }
+        if behind >= step {
+            stats.dropped += (behind.as_ticks() / step.as_ticks().max(1)) as u32;
+            behind = Duration::from_ticks(behind.as_ticks() % step.as_ticks().max(1));
+        }
+
+        game.draw(hal.display(), &stats);
+        hal.present().await;
+        if hal.quit_requested() {
+            return;
+        }
+
+        let now = Instant::now();
+        let frame = now - last_frame;
+        last_frame = now;
+        behind += frame;
+        stats.record(frame, updates);
+        STATS.lock(|published| published.set(Some(stats)));
+
+        let idle_for = now - last_input;
+        let dim = game
+            .dim_timeout()
+            .is_some_and(|timeout| idle_for >= timeout);
+        hal.set_backlight_dimmed(dim);
+
+        let idle = game
+            .idle_timeout()
+            .is_some_and(|timeout| idle_for >= timeout);
+        let critical =
+            hal.power().is_critical() && woke.is_none_or(|woke| now - woke >= CRITICAL_GRACE);
+        if idle || critical {
+            hal.set_backlight_dimmed(false);
+            game.sleep(hal);
+            hal.sleep().await;
+            if hal.quit_requested() {
+                return;
+            }
+            game.wake(hal);
+            // The game carries on where it was instead of catching up on the time asleep
+            last_frame = Instant::now();
+            last_input = last_frame;
+            woke = Some(last_frame);
+            behind = Duration::from_ticks(0);
+        }
+    }
+}
+
+///
+/// Plays a recording through `game` as fast as possible, drawing every update without
+/// presenting, and returns the frame stats. Meant for regression tests on the host against
+/// the mock HAL, where the result can be compared with a known frame.
+///
+/// # Arguments
+///
+/// * `game` - the game to run, seeded the same as when it was recorded
+/// * `hal` - the hardware to run it on, its buttons are ignored
+/// * `replay` - the recording
+/// * `step` - time between updates, the step the recording was made with
+///
+pub fn replay(
+    game: &mut impl Game,
+    hal: &mut impl PicoSystemHal,
+    replay: Replayer,
+    step: Duration,
+) -> FrameStats {
+    let mut stats = FrameStats::default();
+    let mut input = game.input();
+    game.init(hal);
+
+    let mut game_time = Instant::from_ticks(0);
+    for buttons in replay {
+        game_time += step;
+        input.update(buttons, game_time);
+        game.update(hal, &input, step);
+        game.draw(hal.display(), &stats);
+        stats.record(step, 1);
+    }
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_graphics::pixelcolor::Rgb888;
+    use embedded_graphics::prelude::*;
+
+    use super::*;
+    use crate::hal::mock::MockHal;
+    use crate::input::replay::Recorder;
+    use crate::input::ButtonId;
+    use crate::power::PowerStatus;
+    use crate::{HEIGHT, WIDTH};
+
+    /// Counts what the runner does with it and draws a dot per frame
+    #[derive(Default)]
+    struct Counter {
+        idle_timeout: Option<Duration>,
+        dim_timeout: Option<Duration>,
+        updates: u32,
+        draws: u32,
+        presses: u32,
+        sleeps: u32,
+        wakes: u32,
+    }
+
+    impl Game for Counter {
+        fn init(&mut self, hal: &mut impl PicoSystemHal) {
+            hal.set_led(Rgb888::BLUE);
+        }
+
+        fn idle_timeout(&self) -> Option<Duration> {
+            self.idle_timeout
+        }
+
+        fn dim_timeout(&self) -> Option<Duration> {
+            self.dim_timeout
+        }
+
+        fn sleep(&mut self, _hal: &mut impl PicoSystemHal) {
+            self.sleeps += 1;
+        }
+
+        fn wake(&mut self, _hal: &mut impl PicoSystemHal) {
+            self.wakes += 1;
+        }
+
+        fn update(&mut self, hal: &mut impl PicoSystemHal, input: &InputState, _dt: Duration) {
+            self.updates += 1;
+            if input.just_pressed(ButtonId::A) {
+                self.presses += 1;
+                hal.set_led(Rgb888::RED);
+            }
+        }
+
+        fn draw(
+            &mut self,
+            display: &mut (impl DrawTarget<Color = Rgb565> + Blend),
+            _stats: &FrameStats,
+        ) {
+            let _ = Pixel(Point::new(self.draws as i32, 0), Rgb565::WHITE).draw(display);
+            self.draws += 1;
+        }
+    }
+
+    #[test]
+    fn runs_the_game_until_the_hal_quits() {
+        let mut pixels = [0; WIDTH * HEIGHT];
+        let mut hal = MockHal::new(&mut pixels);
+        hal.buttons.set(ButtonId::A, true);
+        hal.quit_after = Some(10);
+        let mut game = Counter::default();
+
+        embassy_futures::block_on(run(&mut game, &mut hal));
+
+        assert_eq!(hal.frames, 10);
+        assert_eq!(game.draws, 10);
+        // Nothing has to catch up before the first frame, then it is about one update a frame
+        assert!((5..=15).contains(&game.updates), "{} updates", game.updates);
+        assert_eq!(game.presses, 1);
+        assert_eq!(hal.led, Rgb888::RED);
+        assert_eq!(hal.display.pixel(Point::new(9, 0)), Some(Rgb565::WHITE));
+        assert_eq!(hal.display.pixel(Point::new(10, 0)), Some(Rgb565::BLACK));
+        assert_eq!(hal.sleeps, 0);
+    }
+
+    #[test]
+    fn sleeps_after_the_idle_timeout() {
+        let mut pixels = [0; WIDTH * HEIGHT];
+        let mut hal = MockHal::new(&mut pixels);
+        hal.frame_time = Duration::from_millis(5);
+        hal.quit_after = Some(30);
+        let mut game = Counter {
+            idle_timeout: Some(Duration::from_millis(40)),
+            ..Counter::default()
+        };
+
+        embassy_futures::block_on(run(&mut game, &mut hal));
+
+        assert!(hal.sleeps >= 2, "slept {} times", hal.sleeps);
+        assert_eq!(game.sleeps, hal.sleeps);
+        assert_eq!(game.wakes, hal.sleeps);
+    }
+
+    #[test]
+    fn dims_after_the_dim_timeout() {
+        let mut pixels = [0; WIDTH * HEIGHT];
+        let mut hal = MockHal::new(&mut pixels);
+        hal.frame_time = Duration::from_millis(5);
+        hal.quit_after = Some(3);
+        let mut game = Counter {
+            dim_timeout: Some(Duration::from_millis(40)),
+            ..Counter::default()
+        };
+
+        embassy_futures::block_on(run(&mut game, &mut hal));
+        assert!(!hal.backlight_dimmed);
+
+        hal.quit_after = Some(hal.frames + 20);
+        embassy_futures::block_on(run(&mut game, &mut hal));
+        assert!(hal.backlight_dimmed);
+        assert_eq!(hal.sleeps, 0);
+    }
+
+    #[test]
+    fn replays_a_recording_one_update_at_a_time() {
+        let mut buffer = [0; 32];
+        let mut recorder = Recorder::new(&mut buffer, 0).unwrap();
+        let mut a = Buttons::NONE;
+        a.set(ButtonId::A, true);
+        for buttons in [Buttons::NONE, a, a, Buttons::NONE, a] {
+            recorder.record(buttons);
+        }
+        let mut pixels = [0; WIDTH * HEIGHT];
+        let mut hal = MockHal::new(&mut pixels);
+        let mut game = Counter::default();
+
+        let replayer = Replayer::new(recorder.finish()).unwrap();
+        let stats = replay(&mut game, &mut hal, replayer, DEFAULT_STEP);
+
+        // Debouncing holds each press back by a sample, so the last one is never seen
+        assert_eq!((game.updates, game.draws, stats.frames), (5, 5, 5));
+        assert_eq!(game.presses, 1);
+        assert_eq!(hal.frames, 0);
+    }
+
+    #[test]
+    fn a_flat_battery_sleeps_once_then_lets_the_game_run() {
+        let mut pixels = [0; WIDTH * HEIGHT];
+        let mut hal = MockHal::new(&mut pixels);
+        hal.frame_time = Duration::from_millis(5);
+        hal.quit_after = Some(30);
+        hal.power = PowerStatus::new(3400, false, false);
+        assert!(hal.power.is_critical());
+        let mut game = Counter {
+            idle_timeout: None,
+            ..Counter::default()
+        };
+
+        embassy_futures::block_on(run(&mut game, &mut hal));
+
+        assert_eq!(hal.sleeps, 1);
+        assert_eq!(hal.frames, 30);
+    }
+}
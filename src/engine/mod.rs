@@ -0,0 +1,46 @@
+//! Building blocks for games, drawn into any `DrawTarget` so they work on the device,
+//! in the simulator and against a host framebuffer alike.
+
+pub mod animation;
+pub mod game;
+pub mod rng;
+pub mod sprite;
+pub mod tilemap;
+
+///
+/// Encodes a 24 bit BMP for tests, so images can be built with known pixels.
+///
+/// # Arguments
+///
+/// * `width` - width of the image, its height follows from the number of colors
+/// * `colors` - every pixel, row by row from the top
+///
+#[cfg(test)]
+pub(crate) fn test_bmp(width: u32, colors: &[embedded_graphics::pixelcolor::Rgb888]) -> Vec<u8> {
+    use embedded_graphics::pixelcolor::RgbColor;
+
+    let height = colors.len() as u32 / width;
+    let stride = (width * 3).div_ceil(4) * 4;
+    let file_size = 54 + stride * height;
+
+    let mut bmp = Vec::new();
+    bmp.extend_from_slice(b"BM");
+    for field in [file_size, 0, 54, 40, width, height] {
+        bmp.extend_from_slice(&field.to_le_bytes());
+    }
+    bmp.extend_from_slice(&1u16.to_le_bytes());
+    bmp.extend_from_slice(&24u16.to_le_bytes());
+    for field in [0, stride * height, 2835, 2835, 0, 0] {
+        bmp.extend_from_slice(&field.to_le_bytes());
+    }
+
+    // Rows are stored bottom first, each padded to 4 bytes
+    for row in colors.chunks(width as usize).rev() {
+        let start = bmp.len();
+        for color in row {
+            bmp.extend_from_slice(&[color.b(), color.g(), color.r()]);
+        }
+        bmp.resize(start + stride as usize, 0);
+    }
+    bmp
+}
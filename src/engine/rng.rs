@@ -0,0 +1,46 @@
+//! A small seeded random number generator. Games that take all their randomness from one
+//! seeded [`Rng`] play out the same way every time for the same input, which replays rely on.
+
+///
+/// xorshift32, fast and good enough for games, not for anything secret.
+///
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u32,
+}
+
+impl Rng {
+    /// Seeds the generator, a seed of 0 is swapped for another as xorshift would only give 0s
+    pub const fn new(seed: u32) -> Self {
+        Self {
+            state: if seed == 0 { 0x9e37_79b9 } else { seed },
+        }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    /// A number in `0..bound`, 0 when `bound` is 0
+    pub fn below(&mut self, bound: u32) -> u32 {
+        ((self.next_u32() as u64 * bound as u64) >> 32) as u32
+    }
+
+    /// A number in `min..max`, `min` when the range is empty
+    pub fn range(&mut self, min: i32, max: i32) -> i32 {
+        match max > min {
+            true => min.wrapping_add(self.below(max.wrapping_sub(min) as u32) as i32),
+            false => min,
+        }
+    }
+
+    /// True with the given chance, out of 256
+    pub fn chance(&mut self, in_256: u8) -> bool {
+        (self.next_u32() >> 24) < in_256 as u32
+    }
+}
@@ -0,0 +1,495 @@
+//! Sprites, images moved around over a background.
+//! A [`SpriteList`] draws them in z order and puts the background back wherever a sprite
+//! moved away from, so only the changed parts of the screen are redrawn each frame.
+
+use embedded_graphics::image::GetPixel;
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::Rectangle;
+use tinybmp::Bmp;
+
+use crate::display::blend::{Blend, BlendMode};
+use crate::display::dirty::DirtyRegions;
+use crate::engine::tilemap::TileMap;
+
+/// Max number of sprites in a [`SpriteList`]
+pub const MAX_SPRITES: usize = 32;
+
+///
+/// What is drawn underneath the sprites, used to cover up where they have been.
+///
+pub trait Background {
+    ///
+    /// Redraws the background inside `area`
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - where to draw, usually the display
+    /// * `area` - part of the target to redraw, nothing outside it may change
+    ///
+    fn restore<D>(&self, target: &mut D, area: &Rectangle) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = Rgb565>;
+}
+
+/// A plain background of one color
+impl Background for Rgb565 {
+    fn restore<D>(&self, target: &mut D, area: &Rectangle) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = Rgb565>,
+    {
+        target.fill_solid(area, *self)
+    }
+}
+
+/// A bitmap drawn at the top left of the screen, black where it does not reach
+impl Background for Bmp<'_, Rgb565> {
+    fn restore<D>(&self, target: &mut D, area: &Rectangle) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = Rgb565>,
+    {
+        let area = area.intersection(&target.bounding_box());
+        let colors = area
+            .points()
+            .map(|point| self.pixel(point).unwrap_or(Rgb565::BLACK));
+        target.fill_contiguous(&area, colors)
+    }
+}
+
+impl Background for TileMap<'_> {
+    fn restore<D>(&self, target: &mut D, area: &Rectangle) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = Rgb565>,
+    {
+        self.draw(&mut target.clipped(area))
+    }
+}
+
+/// Mirroring applied to a sprite's image
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Flip {
+    /// Mirror left to right
+    pub horizontal: bool,
+    /// Mirror top to bottom
+    pub vertical: bool,
+}
+
+///
+/// An image placed on the screen.
+///
+pub struct Sprite<'a> {
+    image: Bmp<'a, Rgb565>,
+    source: Rectangle,
+    position: Point,
+    z: i16,
+    blend_mode: BlendMode,
+    flip: Flip,
+    visible: bool,
+    // Area covered on screen when last drawn
+    drawn: Option<Rectangle>,
+    // Needs redrawing, because something about it changed since it was drawn
+    changed: bool,
+}
+
+impl<'a> Sprite<'a> {
+    ///
+    /// Creates a sprite showing all of `image`
+    ///
+    /// # Arguments
+    ///
+    /// * `image` - bitmap to show
+    /// * `position` - screen position of the top left corner
+    ///
+    pub fn new(image: Bmp<'a, Rgb565>, position: Point) -> Self {
+        Self {
+            source: image.bounding_box(),
+            image,
+            position,
+            z: 0,
+            blend_mode: BlendMode::Replace,
+            flip: Flip::default(),
+            visible: true,
+            drawn: None,
+            changed: true,
+        }
+    }
+
+    /// Pixels of `color` are transparent
+    pub fn with_color_key(mut self, color: Rgb565) -> Self {
+        self.set_blend_mode(BlendMode::ColorKey(color));
+        self
+    }
+
+    /// Draws above sprites with a lower `z`
+    pub fn with_z(mut self, z: i16) -> Self {
+        self.set_z(z);
+        self
+    }
+
+    pub fn position(&self) -> Point {
+        self.position
+    }
+
+    pub fn set_position(&mut self, position: Point) {
+        if position != self.position {
+            self.position = position;
+            self.changed = true;
+        }
+    }
+
+    pub fn move_by(&mut self, delta: Point) {
+        self.set_position(self.position + delta);
+    }
+
+    pub fn size(&self) -> Size {
+        self.source.size
+    }
+
+    /// Part of the image that is shown
+    pub fn source(&self) -> Rectangle {
+        self.source
+    }
+
+    /// Shows only `source` of the image, clipped to the image
+    pub fn set_source(&mut self, source: Rectangle) {
+        let source = source.intersection(&self.image.bounding_box());
+        if source != self.source {
+            self.source = source;
+            self.changed = true;
+        }
+    }
+
+    pub fn z(&self) -> i16 {
+        self.z
+    }
+
+    pub fn set_z(&mut self, z: i16) {
+        if z != self.z {
+            self.z = z;
+            self.changed = true;
+        }
+    }
+
+    pub fn blend_mode(&self) -> BlendMode {
+        self.blend_mode
+    }
+
+    /// Sets how the sprite combines with what is underneath, ColorKey and Alpha make it see through
+    pub fn set_blend_mode(&mut self, blend_mode: BlendMode) {
+        if blend_mode != self.blend_mode {
+            self.blend_mode = blend_mode;
+            self.changed = true;
+        }
+    }
+
+    pub fn flip(&self) -> Flip {
+        self.flip
+    }
+
+    pub fn set_flip(&mut self, flip: Flip) {
+        if flip != self.flip {
+            self.flip = flip;
+            self.changed = true;
+        }
+    }
+
+    pub fn visible(&self) -> bool {
+        self.visible
+    }
+
+    pub fn set_visible(&mut self, visible: bool) {
+        if visible != self.visible {
+            self.visible = visible;
+            self.changed = true;
+        }
+    }
+
+    /// Screen area the sprite covers, None when hidden
+    pub fn area(&self) -> Option<Rectangle> {
+        self.visible
+            .then(|| Rectangle::new(self.position, self.source.size))
+    }
+
+    /// Draws the part of the sprite inside the target, with the target's current blend mode
+    fn draw<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = Rgb565>,
+    {
+        let Some(area) = self.area() else {
+            return Ok(());
+        };
+        let visible = area.intersection(&target.bounding_box());
+        let (width, height) = (area.size.width as i32, area.size.height as i32);
+
+        let colors = visible.points().map(|point| {
+            let mut offset = point - self.position;
+            if self.flip.horizontal {
+                offset.x = width - 1 - offset.x;
+            }
+            if self.flip.vertical {
+                offset.y = height - 1 - offset.y;
+            }
+            self.image
+                .pixel(self.source.top_left + offset)
+                .unwrap_or(Rgb565::BLACK)
+        });
+        target.fill_contiguous(&visible, colors)
+    }
+}
+
+/// Handle to a sprite in a [`SpriteList`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SpriteId(usize);
+
+///
+/// The sprites of a scene, drawn lowest z first over a background.
+///
+pub struct SpriteList<'a> {
+    sprites: heapless::Vec<Option<Sprite<'a>>, MAX_SPRITES>,
+    // Screen areas left behind by removed sprites
+    removed: DirtyRegions,
+}
+
+impl<'a> SpriteList<'a> {
+    pub fn new() -> Self {
+        Self {
+            sprites: heapless::Vec::new(),
+            removed: DirtyRegions::new(),
+        }
+    }
+
+    /// Adds a sprite, or gives it back when the list is full
+    #[allow(clippy::result_large_err)] // like heapless::Vec::push, there is no heap to box it
+    pub fn add(&mut self, sprite: Sprite<'a>) -> Result<SpriteId, Sprite<'a>> {
+        if let Some(index) = self.sprites.iter().position(Option::is_none) {
+            self.sprites[index] = Some(sprite);
+            return Ok(SpriteId(index));
+        }
+
+        match self.sprites.push(Some(sprite)) {
+            Ok(()) => Ok(SpriteId(self.sprites.len() - 1)),
+            Err(sprite) => Err(sprite.unwrap()),
+        }
+    }
+
+    /// Takes a sprite out, the background is put back where it was on the next draw
+    pub fn remove(&mut self, id: SpriteId) -> Option<Sprite<'a>> {
+        let sprite = self.sprites.get_mut(id.0)?.take()?;
+        if let Some(drawn) = sprite.drawn {
+            self.removed.add(drawn);
+        }
+        Some(sprite)
+    }
+
+    pub fn get(&self, id: SpriteId) -> Option<&Sprite<'a>> {
+        self.sprites.get(id.0)?.as_ref()
+    }
+
+    pub fn get_mut(&mut self, id: SpriteId) -> Option<&mut Sprite<'a>> {
+        self.sprites.get_mut(id.0)?.as_mut()
+    }
+
+    /// Forces everything to be drawn again, after the screen has been drawn over
+    pub fn invalidate(&mut self) {
+        for sprite in self.sprites.iter_mut().flatten() {
+            sprite.changed = true;
+        }
+    }
+
+    ///
+    /// Draws the sprites that changed since the last draw.
+    /// The background is restored where they were and where they are now, then every sprite
+    /// over those areas is drawn again in z order, clipped to them, so overlaps stay correct.
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - where to draw, usually the display
+    /// * `background` - what is under the sprites
+    ///
+    pub fn draw<D, B>(&mut self, target: &mut D, background: &B) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = Rgb565> + Blend,
+        B: Background,
+    {
+        let mut damaged = core::mem::take(&mut self.removed);
+        for sprite in self
+            .sprites
+            .iter()
+            .flatten()
+            .filter(|sprite| sprite.changed)
+        {
+            for area in sprite.drawn.into_iter().chain(sprite.area()) {
+                damaged.add(area);
+            }
+        }
+        if damaged.is_empty() {
+            return Ok(());
+        }
+
+        let mut order: heapless::Vec<usize, MAX_SPRITES> = (0..self.sprites.len())
+            .filter(|&index| self.sprites[index].is_some())
+            .collect();
+        order.sort_unstable_by_key(|&index| (self.sprites[index].as_ref().map(Sprite::z), index));
+
+        // The target's blend mode is put back even when drawing fails part way
+        let blend_mode = target.blend_mode();
+        let drawn = damaged
+            .iter()
+            .try_for_each(|area| self.redraw(target, background, area, &order));
+        target.set_blend_mode(blend_mode);
+        if drawn.is_err() {
+            // Nothing counts as drawn, the next draw tries all of it again
+            self.removed = damaged;
+            return drawn;
+        }
+
+        for sprite in self.sprites.iter_mut().flatten() {
+            sprite.drawn = sprite.area();
+            sprite.changed = false;
+        }
+
+        Ok(())
+    }
+
+    /// Restores the background inside `area` and draws the sprites over it in `order`
+    fn redraw<D, B>(
+        &self,
+        target: &mut D,
+        background: &B,
+        area: &Rectangle,
+        order: &[usize],
+    ) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = Rgb565> + Blend,
+        B: Background,
+    {
+        target.set_blend_mode(BlendMode::Replace);
+        background.restore(target, area)?;
+
+        for sprite in order
+            .iter()
+            .filter_map(|&index| self.sprites[index].as_ref())
+        {
+            let Some(sprite_area) = sprite.area() else {
+                continue;
+            };
+            if sprite_area.intersection(area).is_zero_sized() {
+                continue;
+            }
+            target.set_blend_mode(sprite.blend_mode);
+            sprite.draw(&mut target.clipped(area))?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for SpriteList<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_graphics::pixelcolor::Rgb888;
+
+    use super::*;
+    use crate::display::framebuffer::Framebuffer;
+    use crate::engine::test_bmp;
+    use crate::{HEIGHT, WIDTH};
+
+    /// A 2x2 red sprite
+    fn red() -> Vec<u8> {
+        test_bmp(2, &[Rgb888::RED; 4])
+    }
+
+    /// A target that refuses every draw
+    struct Failing {
+        blend_mode: BlendMode,
+    }
+
+    impl OriginDimensions for Failing {
+        fn size(&self) -> Size {
+            Size::new(WIDTH as u32, HEIGHT as u32)
+        }
+    }
+
+    impl DrawTarget for Failing {
+        type Color = Rgb565;
+        type Error = ();
+
+        fn draw_iter<I>(&mut self, _pixels: I) -> Result<(), ()>
+        where
+            I: IntoIterator<Item = Pixel<Rgb565>>,
+        {
+            Err(())
+        }
+    }
+
+    impl Blend for Failing {
+        fn blend_mode(&self) -> BlendMode {
+            self.blend_mode
+        }
+
+        fn set_blend_mode(&mut self, blend_mode: BlendMode) {
+            self.blend_mode = blend_mode;
+        }
+    }
+
+    #[test]
+    fn moving_a_sprite_restores_the_background() {
+        let bmp = red();
+        let mut pixels = [0; WIDTH * HEIGHT];
+        let mut framebuffer = Framebuffer::new(&mut pixels);
+        let mut sprites = SpriteList::new();
+        let id = sprites
+            .add(Sprite::new(
+                Bmp::from_slice(&bmp).unwrap(),
+                Point::new(4, 4),
+            ))
+            .ok()
+            .unwrap();
+
+        sprites.draw(&mut framebuffer, &Rgb565::BLUE).unwrap();
+        assert_eq!(framebuffer.pixel(Point::new(4, 4)), Some(Rgb565::RED));
+        // Only damaged areas are restored, the rest of the screen is left alone
+        assert_eq!(framebuffer.pixel(Point::new(6, 4)), Some(Rgb565::BLACK));
+
+        sprites.get_mut(id).unwrap().move_by(Point::new(2, 0));
+        sprites.draw(&mut framebuffer, &Rgb565::BLUE).unwrap();
+        assert_eq!(framebuffer.pixel(Point::new(4, 4)), Some(Rgb565::BLUE));
+        assert_eq!(framebuffer.pixel(Point::new(6, 4)), Some(Rgb565::RED));
+
+        sprites.remove(id);
+        sprites.draw(&mut framebuffer, &Rgb565::BLUE).unwrap();
+        assert_eq!(framebuffer.pixel(Point::new(6, 4)), Some(Rgb565::BLUE));
+    }
+
+    #[test]
+    fn a_failed_draw_keeps_the_blend_mode_and_is_retried() {
+        let bmp = red();
+        let mut sprites = SpriteList::new();
+        let id = sprites
+            .add(Sprite::new(
+                Bmp::from_slice(&bmp).unwrap(),
+                Point::new(4, 4),
+            ))
+            .ok()
+            .unwrap();
+        sprites.get_mut(id).unwrap().set_blend_mode(BlendMode::Add);
+        let mut pixels = [0; WIDTH * HEIGHT];
+        let mut framebuffer = Framebuffer::new(&mut pixels);
+        sprites.draw(&mut framebuffer, &Rgb565::BLUE).unwrap();
+        sprites.remove(id);
+
+        let mut failing = Failing {
+            blend_mode: BlendMode::Xor,
+        };
+        assert_eq!(sprites.draw(&mut failing, &Rgb565::BLUE), Err(()));
+        assert_eq!(failing.blend_mode, BlendMode::Xor);
+
+        // The area the sprite left is still owed to the next draw
+        sprites.draw(&mut framebuffer, &Rgb565::BLUE).unwrap();
+        assert_eq!(framebuffer.pixel(Point::new(4, 4)), Some(Rgb565::BLUE));
+    }
+}
@@ -0,0 +1,364 @@
+//! Tile maps, scenes built from small tiles sliced out of one bitmap.
+//! Each layer scrolls on its own, so layers drawn bottom first give parallax backgrounds.
+
+use embedded_graphics::image::GetPixel;
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::Rectangle;
+use tinybmp::Bmp;
+
+/// Max number of layers in a [`TileMap`]
+pub const MAX_LAYERS: usize = 4;
+
+///
+/// Tiles of the same size laid out left to right, top to bottom in a bitmap.
+///
+pub struct Tileset<'a> {
+    bmp: Bmp<'a, Rgb565>,
+    tile_size: Size,
+    columns: u32,
+    color_key: Option<Rgb565>,
+}
+
+impl<'a> Tileset<'a> {
+    ///
+    /// Slices `bmp` into tiles, leftover pixels on the right and bottom are ignored
+    ///
+    /// # Arguments
+    ///
+    /// * `bmp` - bitmap holding the tiles
+    /// * `tile_size` - size of one tile in pixels
+    ///
+    pub fn new(bmp: Bmp<'a, Rgb565>, tile_size: Size) -> Self {
+        let columns = bmp.size().width / tile_size.width.max(1);
+        Self {
+            bmp,
+            tile_size,
+            columns,
+            color_key: None,
+        }
+    }
+
+    /// Pixels of `color` in tiles are not drawn, so lower layers show through them
+    pub fn with_color_key(mut self, color: Rgb565) -> Self {
+        self.color_key = Some(color);
+        self
+    }
+
+    pub fn tile_size(&self) -> Size {
+        self.tile_size
+    }
+
+    /// Number of tiles in the set
+    pub fn len(&self) -> u16 {
+        let rows = self.bmp.size().height / self.tile_size.height.max(1);
+        (self.columns * rows).min(u16::MAX as u32) as u16
+    }
+
+    /// True when the bitmap is smaller than one tile
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Color at `offset` inside `tile`
+    fn pixel(&self, tile: u16, offset: Point) -> Option<Rgb565> {
+        let columns = self.columns.max(1);
+        let origin = Point::new(
+            ((tile as u32 % columns) * self.tile_size.width) as i32,
+            ((tile as u32 / columns) * self.tile_size.height) as i32,
+        );
+        self.bmp.pixel(origin + offset)
+    }
+}
+
+///
+/// A grid of tile indexes scrolled over the screen.
+///
+pub struct Layer<'a> {
+    tiles: &'a [u16],
+    columns: u32,
+    scroll: Point,
+    transparent: Option<u16>,
+    wrap: bool,
+    visible: bool,
+}
+
+impl<'a> Layer<'a> {
+    ///
+    /// Creates a layer from tile indexes stored row by row
+    ///
+    /// # Arguments
+    ///
+    /// * `tiles` - tile index of every map cell, row by row
+    /// * `columns` - width of the map in tiles
+    ///
+    pub fn new(tiles: &'a [u16], columns: u32) -> Self {
+        Self {
+            tiles,
+            columns,
+            scroll: Point::zero(),
+            transparent: None,
+            wrap: false,
+            visible: true,
+        }
+    }
+
+    /// Cells holding `index` are left empty
+    pub fn with_transparent(mut self, index: u16) -> Self {
+        self.transparent = Some(index);
+        self
+    }
+
+    /// Repeats the map endlessly in both directions instead of leaving the outside empty
+    pub fn with_wrap(mut self, wrap: bool) -> Self {
+        self.wrap = wrap;
+        self
+    }
+
+    /// Position of the map shown at the top left of the screen, in pixels
+    pub fn scroll(&self) -> Point {
+        self.scroll
+    }
+
+    pub fn set_scroll(&mut self, scroll: Point) {
+        self.scroll = scroll;
+    }
+
+    pub fn scroll_by(&mut self, delta: Point) {
+        self.scroll += delta;
+    }
+
+    pub fn set_visible(&mut self, visible: bool) {
+        self.visible = visible;
+    }
+
+    /// Tile index of a map cell, None for cells outside the map or holding the transparent index
+    pub fn tile(&self, column: i32, row: i32) -> Option<u16> {
+        let rows = (self.tiles.len() as u32 / self.columns.max(1)) as i32;
+        let columns = self.columns as i32;
+        if columns == 0 || rows == 0 {
+            return None;
+        }
+
+        let (column, row) = match self.wrap {
+            true => (column.rem_euclid(columns), row.rem_euclid(rows)),
+            false if (0..columns).contains(&column) && (0..rows).contains(&row) => (column, row),
+            false => return None,
+        };
+
+        let tile = self.tiles[(column + row * columns) as usize];
+        (Some(tile) != self.transparent).then_some(tile)
+    }
+}
+
+///
+/// Layers of tiles from one tileset, drawn bottom layer first.
+///
+pub struct TileMap<'a> {
+    tileset: Tileset<'a>,
+    layers: heapless::Vec<Layer<'a>, MAX_LAYERS>,
+}
+
+impl<'a> TileMap<'a> {
+    pub fn new(tileset: Tileset<'a>) -> Self {
+        Self {
+            tileset,
+            layers: heapless::Vec::new(),
+        }
+    }
+
+    /// Adds a layer on top of the others and returns its index, or gives it back when full
+    pub fn add_layer(&mut self, layer: Layer<'a>) -> Result<usize, Layer<'a>> {
+        self.layers.push(layer)?;
+        Ok(self.layers.len() - 1)
+    }
+
+    pub fn layer(&self, index: usize) -> Option<&Layer<'a>> {
+        self.layers.get(index)
+    }
+
+    pub fn layer_mut(&mut self, index: usize) -> Option<&mut Layer<'a>> {
+        self.layers.get_mut(index)
+    }
+
+    ///
+    /// Draws the tiles of every visible layer that fall on `target`, nothing outside it
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - where to draw, usually the display
+    ///
+    pub fn draw<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = Rgb565>,
+    {
+        for layer in self.layers.iter().filter(|layer| layer.visible) {
+            self.draw_layer(layer, target)?;
+        }
+        Ok(())
+    }
+
+    fn draw_layer<D>(&self, layer: &Layer<'_>, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = Rgb565>,
+    {
+        let viewport = target.bounding_box();
+        let Some(viewport_end) = viewport.bottom_right() else {
+            return Ok(());
+        };
+        let tile_size = self.tileset.tile_size;
+        if tile_size.width == 0 || tile_size.height == 0 {
+            return Ok(());
+        }
+        let (tile_width, tile_height) = (tile_size.width as i32, tile_size.height as i32);
+
+        // Map pixels under the viewport corners, and the cells they fall in
+        let first = layer.scroll + viewport.top_left;
+        let last = layer.scroll + viewport_end;
+        let columns = first.x.div_euclid(tile_width)..=last.x.div_euclid(tile_width);
+        let rows = first.y.div_euclid(tile_height)..=last.y.div_euclid(tile_height);
+
+        for row in rows {
+            for column in columns.clone() {
+                let Some(tile) = layer.tile(column, row) else {
+                    continue;
+                };
+
+                let position = Point::new(column * tile_width, row * tile_height) - layer.scroll;
+                let tile_area = Rectangle::new(position, tile_size);
+                self.draw_tile(tile, &tile_area, &tile_area.intersection(&viewport), target)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Draws the part of `tile` placed at `tile_area` that falls inside `visible`
+    fn draw_tile<D>(
+        &self,
+        tile: u16,
+        tile_area: &Rectangle,
+        visible: &Rectangle,
+        target: &mut D,
+    ) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = Rgb565>,
+    {
+        let color = |point: Point| {
+            self.tileset
+                .pixel(tile, point - tile_area.top_left)
+                .unwrap_or(Rgb565::BLACK)
+        };
+
+        match self.tileset.color_key {
+            None => target.fill_contiguous(visible, visible.points().map(color)),
+            Some(key) => target.draw_iter(
+                visible
+                    .points()
+                    .map(|point| Pixel(point, color(point)))
+                    .filter(|Pixel(_, color)| *color != key),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_graphics::pixelcolor::Rgb888;
+
+    use super::*;
+    use crate::display::framebuffer::Framebuffer;
+    use crate::engine::test_bmp;
+    use crate::{HEIGHT, WIDTH};
+
+    const R: Rgb888 = Rgb888::RED;
+    const B: Rgb888 = Rgb888::BLUE;
+    const G: Rgb888 = Rgb888::GREEN;
+
+    /// Two 2x2 tiles, red and blue with a green bottom right pixel
+    fn tiles() -> Vec<u8> {
+        test_bmp(4, &[R, R, B, B, R, R, B, G])
+    }
+
+    fn draw(map: &TileMap<'_>) -> Vec<Option<Rgb565>> {
+        let mut pixels = [0; WIDTH * HEIGHT];
+        let mut framebuffer = Framebuffer::new(&mut pixels);
+        let _ = map.draw(&mut framebuffer);
+        (0..6)
+            .map(|x| framebuffer.pixel(Point::new(x, 1)))
+            .collect()
+    }
+
+    #[test]
+    fn slices_tiles_out_of_the_bitmap() {
+        let bmp = tiles();
+        let tileset = Tileset::new(Bmp::from_slice(&bmp).unwrap(), Size::new(2, 2));
+        assert_eq!(tileset.len(), 2);
+        assert!(!tileset.is_empty());
+
+        let tileset = Tileset::new(Bmp::from_slice(&bmp).unwrap(), Size::new(4, 4));
+        assert!(tileset.is_empty());
+    }
+
+    #[test]
+    fn draws_tiles_where_the_map_puts_them() {
+        let bmp = tiles();
+        let tileset = Tileset::new(Bmp::from_slice(&bmp).unwrap(), Size::new(2, 2));
+        let mut map = TileMap::new(tileset);
+        let _ = map.add_layer(Layer::new(&[1, 0], 2));
+
+        let (red, blue, green) = (Some(Rgb565::RED), Some(Rgb565::BLUE), Some(Rgb565::GREEN));
+        let black = Some(Rgb565::BLACK);
+        assert_eq!(draw(&map), [blue, green, red, red, black, black]);
+    }
+
+    #[test]
+    fn scrolls_and_wraps_layers() {
+        let bmp = tiles();
+        let tileset = Tileset::new(Bmp::from_slice(&bmp).unwrap(), Size::new(2, 2));
+        let mut map = TileMap::new(tileset);
+        let layer = map.add_layer(Layer::new(&[1, 0], 2)).ok().unwrap();
+
+        map.layer_mut(layer).unwrap().set_scroll(Point::new(1, 0));
+        let (red, blue, green) = (Some(Rgb565::RED), Some(Rgb565::BLUE), Some(Rgb565::GREEN));
+        let black = Some(Rgb565::BLACK);
+        assert_eq!(draw(&map), [green, red, red, black, black, black]);
+
+        let mut map = TileMap::new(Tileset::new(
+            Bmp::from_slice(&bmp).unwrap(),
+            Size::new(2, 2),
+        ));
+        let layer = map
+            .add_layer(Layer::new(&[1, 0], 2).with_wrap(true))
+            .ok()
+            .unwrap();
+        map.layer_mut(layer).unwrap().scroll_by(Point::new(-2, 0));
+        assert_eq!(draw(&map), [red, red, blue, green, red, red]);
+        assert_eq!(map.layer(layer).unwrap().tile(-1, 5), Some(0));
+    }
+
+    #[test]
+    fn upper_layers_show_lower_ones_through_gaps() {
+        let bmp = tiles();
+        let tileset = Tileset::new(Bmp::from_slice(&bmp).unwrap(), Size::new(2, 2));
+        let mut map = TileMap::new(tileset.with_color_key(Rgb565::GREEN));
+        let _ = map.add_layer(Layer::new(&[0, 0, 0], 3));
+        let _ = map.add_layer(Layer::new(&[1, 0, 1], 3).with_transparent(0));
+
+        // The key color of tile 1 and the transparent middle cell leave the red layer below
+        let (red, blue) = (Some(Rgb565::RED), Some(Rgb565::BLUE));
+        assert_eq!(draw(&map), [blue, red, red, red, blue, red]);
+    }
+
+    #[test]
+    fn skips_hidden_layers() {
+        let bmp = tiles();
+        let tileset = Tileset::new(Bmp::from_slice(&bmp).unwrap(), Size::new(2, 2));
+        let mut map = TileMap::new(tileset);
+        let _ = map.add_layer(Layer::new(&[0], 1));
+        let top = map.add_layer(Layer::new(&[1], 1)).ok().unwrap();
+
+        map.layer_mut(top).unwrap().set_visible(false);
+        assert_eq!(draw(&map)[..2], [Some(Rgb565::RED); 2]);
+    }
+}
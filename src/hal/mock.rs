@@ -0,0 +1,107 @@
+//! A [`PicoSystemHal`] with no hardware behind it, for running game code in host tests.
+//! Tests set the buttons and battery, run frames, then look at what was drawn and played.
+
+use embassy_time::{Duration, Timer};
+use embedded_graphics::pixelcolor::Rgb888;
+use embedded_graphics::prelude::*;
+
+use super::PicoSystemHal;
+use crate::display::framebuffer::{Framebuffer, Pixels};
+use crate::input::Buttons;
+use crate::power::PowerStatus;
+
+/// Max number of tones remembered by the mock
+const MAX_TONES: usize = 32;
+
+pub struct MockHal<'a> {
+    pub display: Framebuffer<'a>,
+    pub buttons: Buttons,
+    pub backlight: u8,
+    pub backlight_dimmed: bool,
+    pub led: Rgb888,
+    /// The last led pulse as (color, duration)
+    pub led_pulse: Option<(Rgb888, Duration)>,
+    pub power: PowerStatus,
+    /// Number of frames presented so far
+    pub frames: u32,
+    /// Tones played as (frequency, duration), oldest first
+    pub tones: heapless::Vec<(u32, Duration), MAX_TONES>,
+    /// Number of times the game was put to sleep, it wakes straight away
+    pub sleeps: u32,
+    /// Time a frame takes to present, like waiting for vsync on the PicoSystem
+    pub frame_time: Duration,
+    /// Asks the runner to quit once this many frames are presented
+    pub quit_after: Option<u32>,
+}
+
+impl<'a> MockHal<'a> {
+    pub fn new(pixels: &'a mut Pixels) -> Self {
+        Self {
+            display: Framebuffer::new(pixels),
+            buttons: Buttons::NONE,
+            backlight: 0,
+            backlight_dimmed: false,
+            led: Rgb888::BLACK,
+            led_pulse: None,
+            power: PowerStatus::default(),
+            frames: 0,
+            tones: heapless::Vec::new(),
+            sleeps: 0,
+            frame_time: Duration::from_micros(16_667),
+            quit_after: None,
+        }
+    }
+}
+
+impl<'a> PicoSystemHal for MockHal<'a> {
+    type Display = Framebuffer<'a>;
+
+    fn display(&mut self) -> &mut Self::Display {
+        &mut self.display
+    }
+
+    async fn wait_vsync(&mut self) {
+        Timer::after(self.frame_time).await;
+    }
+
+    async fn present(&mut self) {
+        self.wait_vsync().await;
+        self.frames += 1;
+    }
+
+    fn quit_requested(&self) -> bool {
+        self.quit_after.is_some_and(|frames| self.frames >= frames)
+    }
+
+    fn buttons(&self) -> Buttons {
+        self.buttons
+    }
+
+    fn set_backlight(&mut self, brightness: u8) {
+        self.backlight = brightness;
+    }
+
+    fn set_backlight_dimmed(&mut self, dimmed: bool) {
+        self.backlight_dimmed = dimmed;
+    }
+
+    fn set_led(&mut self, color: Rgb888) {
+        self.led = color;
+    }
+
+    fn pulse_led(&mut self, color: Rgb888, duration: Duration) {
+        self.led_pulse = Some((color, duration));
+    }
+
+    async fn play_tone(&mut self, frequency: u32, duration: Duration) {
+        let _ = self.tones.push((frequency, duration));
+    }
+
+    fn power(&mut self) -> PowerStatus {
+        self.power
+    }
+
+    async fn sleep(&mut self) {
+        self.sleeps += 1;
+    }
+}
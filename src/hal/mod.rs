@@ -0,0 +1,65 @@
+//! The hardware a game talks to, so game code is not tied to embassy-rp.
+//! `peripherals::Peripherals` implements it on the PicoSystem, the simulator on the desktop
+//! and [`mock::MockHal`] in host tests.
+
+use embassy_time::Duration;
+use embedded_graphics::pixelcolor::{Rgb565, Rgb888};
+use embedded_graphics::prelude::DrawTarget;
+
+use crate::display::blend::Blend;
+use crate::input::Buttons;
+use crate::power::PowerStatus;
+
+#[cfg(not(target_os = "none"))]
+pub mod mock;
+
+#[allow(async_fn_in_trait)]
+pub trait PicoSystemHal {
+    type Display: DrawTarget<Color = Rgb565> + Blend;
+
+    /// The 240x240 screen to draw the next frame into
+    fn display(&mut self) -> &mut Self::Display;
+
+    /// Waits for the start of the display's vertical blanking
+    async fn wait_vsync(&mut self);
+
+    /// Sends the drawn frame to the screen
+    async fn present(&mut self);
+
+    /// True once the player asked to stop, like closing the simulator window.
+    /// [`run`](crate::engine::game::run) returns at the end of the frame, the PicoSystem never
+    /// stops.
+    fn quit_requested(&self) -> bool {
+        false
+    }
+
+    /// Buttons currently held down
+    fn buttons(&self) -> Buttons;
+
+    /// Sets the backlight brightness by percentage, [`backlight`](crate::backlight) fades it
+    fn set_backlight(&mut self, brightness: u8);
+
+    /// Fades the backlight down while nobody is playing, or back up
+    fn set_backlight_dimmed(&mut self, dimmed: bool);
+
+    /// Sets the RGB status led
+    fn set_led(&mut self, color: Rgb888);
+
+    /// Flashes the led `color` and fades it back over `duration`, for game events
+    fn pulse_led(&mut self, color: Rgb888, duration: Duration);
+
+    /// Plays a square wave on the speaker, returns once it is done
+    async fn play_tone(&mut self, frequency: u32, duration: Duration);
+
+    /// The battery and charger as of the last sample
+    fn power(&mut self) -> PowerStatus;
+
+    /// Battery charge in percent
+    fn battery(&mut self) -> u8 {
+        self.power().percent
+    }
+
+    /// Turns the screen and lights off and sleeps as deeply as it can until a button is
+    /// pressed, then turns them back on
+    async fn sleep(&mut self);
+}
@@ -0,0 +1,368 @@
+//! Button combinations and gestures on top of [`InputState`]: chords, long presses,
+//! double taps and sequences. Each detector is fed the input state once per update and
+//! reports the update its gesture completes on, so they work the same on synthetic input.
+
+use embassy_time::{Duration, Instant};
+
+use super::state::InputState;
+use super::{ButtonId, Buttons};
+
+///
+/// Something recognised from the buttons over time.
+///
+pub trait Gesture {
+    /// Looks at the latest sample, true on the update the gesture completes
+    fn update(&mut self, input: &InputState) -> bool;
+
+    /// Forgets anything in progress
+    fn reset(&mut self);
+}
+
+/// Shortest and longest time the buttons in `buttons` have been held, None unless all are
+fn held_range(input: &InputState, buttons: Buttons) -> Option<(Duration, Duration)> {
+    let mut range: Option<(Duration, Duration)> = None;
+    for button in ButtonId::ALL.into_iter().filter(|b| buttons.contains(*b)) {
+        let held = input.held_time(button)?;
+        range = Some(match range {
+            Some((shortest, longest)) => (shortest.min(held), longest.max(held)),
+            None => (held, held),
+        });
+    }
+    range
+}
+
+///
+/// Several buttons pressed together, like A+B. Fires once when the last of them goes down,
+/// if they all went down within the window, and again only after one is let go.
+///
+pub struct Chord {
+    buttons: Buttons,
+    window: Duration,
+    fired: bool,
+}
+
+impl Chord {
+    ///
+    /// # Arguments
+    ///
+    /// * `buttons` - buttons that make up the chord
+    /// * `window` - longest time between the first and the last press
+    ///
+    pub const fn new(buttons: Buttons, window: Duration) -> Self {
+        Self {
+            buttons,
+            window,
+            fired: false,
+        }
+    }
+}
+
+impl Gesture for Chord {
+    fn update(&mut self, input: &InputState) -> bool {
+        match held_range(input, self.buttons) {
+            Some((shortest, longest)) if !self.fired => {
+                self.fired = true;
+                longest - shortest <= self.window
+            }
+            Some(_) => false,
+            None => {
+                self.fired = false;
+                false
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.fired = false;
+    }
+}
+
+///
+/// One or more buttons held down for a while, like holding X+Y for the menu.
+/// Fires once per hold, as soon as all of them have been down long enough.
+///
+pub struct LongPress {
+    buttons: Buttons,
+    duration: Duration,
+    fired: bool,
+}
+
+impl LongPress {
+    ///
+    /// # Arguments
+    ///
+    /// * `buttons` - buttons to hold, all of them
+    /// * `duration` - how long they have to be held
+    ///
+    pub const fn new(buttons: Buttons, duration: Duration) -> Self {
+        Self {
+            buttons,
+            duration,
+            fired: false,
+        }
+    }
+}
+
+impl Gesture for LongPress {
+    fn update(&mut self, input: &InputState) -> bool {
+        match held_range(input, self.buttons) {
+            Some((shortest, _)) if !self.fired && shortest >= self.duration => {
+                self.fired = true;
+                true
+            }
+            Some(_) => false,
+            None => {
+                self.fired = false;
+                false
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.fired = false;
+    }
+}
+
+///
+/// A button pressed twice in quick succession. Fires on the second press, a third press
+/// starts over instead of firing again.
+///
+pub struct DoubleTap {
+    button: ButtonId,
+    window: Duration,
+    first_tap: Option<Instant>,
+}
+
+impl DoubleTap {
+    ///
+    /// # Arguments
+    ///
+    /// * `button` - button to tap
+    /// * `window` - longest time from the first press to the second
+    ///
+    pub const fn new(button: ButtonId, window: Duration) -> Self {
+        Self {
+            button,
+            window,
+            first_tap: None,
+        }
+    }
+}
+
+impl Gesture for DoubleTap {
+    fn update(&mut self, input: &InputState) -> bool {
+        if !input.just_pressed(self.button) {
+            return false;
+        }
+
+        let now = input.now();
+        match self.first_tap.take() {
+            Some(first) if now - first <= self.window => true,
+            _ => {
+                self.first_tap = Some(now);
+                false
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.first_tap = None;
+    }
+}
+
+/// The classic cheat code
+pub const KONAMI_CODE: [ButtonId; 10] = [
+    ButtonId::Up,
+    ButtonId::Up,
+    ButtonId::Down,
+    ButtonId::Down,
+    ButtonId::Left,
+    ButtonId::Right,
+    ButtonId::Left,
+    ButtonId::Right,
+    ButtonId::B,
+    ButtonId::A,
+];
+
+///
+/// Buttons pressed one after another, like a cheat code. Any other button, or too long a
+/// pause between presses, starts the sequence over. Fires on the last press.
+///
+pub struct Sequence<'a> {
+    steps: &'a [ButtonId],
+    window: Duration,
+    matched: usize,
+    last_press: Instant,
+}
+
+impl<'a> Sequence<'a> {
+    ///
+    /// # Arguments
+    ///
+    /// * `steps` - buttons to press, in order
+    /// * `window` - longest time allowed between two presses
+    ///
+    pub const fn new(steps: &'a [ButtonId], window: Duration) -> Self {
+        Self {
+            steps,
+            window,
+            matched: 0,
+            last_press: Instant::from_ticks(0),
+        }
+    }
+
+    /// Steps matched so far, for showing progress
+    pub fn progress(&self) -> usize {
+        self.matched
+    }
+
+    /// Steps matched after pressing `button`. A wrong press keeps the longest run of recent
+    /// presses that still starts the sequence, so up, up, up, down matches up, up, down.
+    fn advance(&self, button: ButtonId) -> usize {
+        let longest = (self.matched + 1).min(self.steps.len());
+        (1..=longest)
+            .rev()
+            .find(|&len| {
+                self.steps[len - 1] == button
+                    && self.steps[..len - 1] == self.steps[self.matched + 1 - len..self.matched]
+            })
+            .unwrap_or(0)
+    }
+}
+
+impl Gesture for Sequence<'_> {
+    fn update(&mut self, input: &InputState) -> bool {
+        let now = input.now();
+        if self.matched > 0 && now - self.last_press > self.window {
+            self.matched = 0;
+        }
+
+        let mut completed = false;
+        for button in ButtonId::ALL.into_iter().filter(|b| input.just_pressed(*b)) {
+            self.matched = self.advance(button);
+            self.last_press = now;
+            if !self.steps.is_empty() && self.matched == self.steps.len() {
+                self.matched = 0;
+                completed = true;
+            }
+        }
+        completed
+    }
+
+    fn reset(&mut self) {
+        self.matched = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ButtonId::*;
+
+    fn buttons(pressed: &[ButtonId]) -> Buttons {
+        Buttons(pressed.iter().fold(0, |bits, button| bits | button.mask()))
+    }
+
+    /// Feeds `gesture` the buttons held at each time in milliseconds, sampling every 10ms
+    /// until the next entry, and returns the times it fired
+    fn play(gesture: &mut impl Gesture, timeline: &[(u64, &[ButtonId])]) -> Vec<u64> {
+        let mut input = InputState::new().with_debounce(1);
+        let mut fired = Vec::new();
+        for (index, &(start, held)) in timeline.iter().enumerate() {
+            let end = timeline
+                .get(index + 1)
+                .map_or(start + 10, |&(next, _)| next);
+            for millis in (start..end).step_by(10) {
+                input.update(buttons(held), Instant::from_millis(millis));
+                if gesture.update(&input) {
+                    fired.push(millis);
+                }
+            }
+        }
+        fired
+    }
+
+    #[test]
+    fn chord_needs_the_buttons_close_together() {
+        let mut chord = Chord::new(buttons(&[A, B]), Duration::from_millis(50));
+        let together = [(0, &[A][..]), (30, &[A, B]), (100, &[]), (200, &[B, A])];
+        assert_eq!(play(&mut chord, &together), [30, 200]);
+
+        chord.reset();
+        let apart = [(0, &[A][..]), (80, &[A, B]), (200, &[B])];
+        assert!(play(&mut chord, &apart).is_empty());
+    }
+
+    #[test]
+    fn long_press_fires_once_per_hold() {
+        let mut hold = LongPress::new(buttons(&[X, Y]), Duration::from_millis(100));
+        let timeline = [
+            (0, &[X][..]),
+            (50, &[X, Y]),
+            (400, &[X]),
+            (500, &[X, Y]),
+            (700, &[]),
+        ];
+        assert_eq!(play(&mut hold, &timeline), [150, 600]);
+    }
+
+    #[test]
+    fn double_tap_needs_two_quick_presses() {
+        let mut tap = DoubleTap::new(A, Duration::from_millis(200));
+        let quick = [
+            (0, &[A][..]),
+            (50, &[]),
+            (100, &[A]),
+            (150, &[]),
+            (200, &[A]),
+            (250, &[]),
+            (300, &[A]),
+        ];
+        // The third press starts over, the fourth completes the next double tap
+        assert_eq!(play(&mut tap, &quick), [100, 300]);
+
+        tap.reset();
+        let slow = [(1000, &[A][..]), (1050, &[]), (1300, &[A])];
+        assert!(play(&mut tap, &slow).is_empty());
+    }
+
+    /// Presses each button for 20ms with 20ms gaps, starting at `start`
+    fn presses(start: u64, steps: &[ButtonId]) -> Vec<(u64, &[ButtonId])> {
+        let mut timeline = Vec::new();
+        for (index, button) in steps.iter().enumerate() {
+            let at = start + index as u64 * 40;
+            timeline.push((at, core::slice::from_ref(button)));
+            timeline.push((at + 20, &[][..]));
+        }
+        timeline
+    }
+
+    #[test]
+    fn sequence_matches_the_konami_code() {
+        let mut code = Sequence::new(&KONAMI_CODE, Duration::from_millis(100));
+        assert_eq!(play(&mut code, &presses(0, &KONAMI_CODE)), [360]);
+        assert_eq!(code.progress(), 0);
+    }
+
+    #[test]
+    fn sequence_keeps_the_run_that_still_starts_it() {
+        let mut code = Sequence::new(&KONAMI_CODE, Duration::from_millis(100));
+        // An extra up still leaves up, up matched
+        let mut steps = vec![Up];
+        steps.extend(KONAMI_CODE);
+        assert_eq!(play(&mut code, &presses(0, &steps)), [400]);
+
+        let mut code = Sequence::new(&[A, A, B], Duration::from_millis(100));
+        assert_eq!(play(&mut code, &presses(0, &[A, A, A, B])), [120]);
+        assert!(play(&mut code, &presses(1000, &[A, B, A, B])).is_empty());
+    }
+
+    #[test]
+    fn sequence_starts_over_after_a_pause() {
+        let mut code = Sequence::new(&[Up, Down], Duration::from_millis(100));
+        let mut timeline = presses(0, &[Up]);
+        timeline.extend(presses(300, &[Down]));
+        timeline.extend(presses(500, &[Up, Down]));
+        assert_eq!(play(&mut code, &timeline), [540]);
+    }
+}
@@ -0,0 +1,73 @@
+//! Button changes published by whatever watches the buttons, the scanner task on the
+//! PicoSystem or the window in the simulator. Games can poll [`current`] or consume the
+//! timestamped events in order, both without locking out the publisher.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::Instant;
+
+use super::{ButtonId, Buttons};
+
+/// Events kept until they are consumed, newer ones are dropped once it is full
+pub const EVENT_CAPACITY: usize = 32;
+
+/// A button going down or coming back up
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ButtonEvent {
+    pub button: ButtonId,
+    pub pressed: bool,
+    /// When the change was first seen, before debouncing settled it
+    pub at: Instant,
+}
+
+static EVENTS: Channel<CriticalSectionRawMutex, ButtonEvent, EVENT_CAPACITY> = Channel::new();
+
+/// Debounced buttons, as a [`Buttons`] mask
+static STATE: AtomicU8 = AtomicU8::new(0);
+
+/// Debounced buttons held down right now
+pub fn current() -> Buttons {
+    Buttons(STATE.load(Ordering::Relaxed))
+}
+
+///
+/// Records the debounced state of all buttons and sends an event for each one that changed.
+/// Only one publisher may call this.
+///
+/// # Arguments
+///
+/// * `buttons` - buttons held down
+/// * `at` - when the change happened
+///
+pub fn publish(buttons: Buttons, at: Instant) {
+    // Plain load and store, thumbv6m has no atomic swap and there is only one publisher
+    let previous = current();
+    STATE.store(buttons.bits(), Ordering::Relaxed);
+    for button in ButtonId::ALL {
+        let pressed = buttons.contains(button);
+        if pressed != previous.contains(button) {
+            let _ = EVENTS.try_send(ButtonEvent {
+                button,
+                pressed,
+                at,
+            });
+        }
+    }
+}
+
+/// Takes the oldest event that has not been consumed yet
+pub fn next_event() -> Option<ButtonEvent> {
+    EVENTS.try_receive().ok()
+}
+
+/// Waits for the next event
+pub async fn wait_event() -> ButtonEvent {
+    EVENTS.receive().await
+}
+
+/// Throws away events that have not been consumed, for example when a new screen starts
+pub fn clear_events() {
+    while EVENTS.try_receive().is_ok() {}
+}
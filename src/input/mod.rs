@@ -0,0 +1,67 @@
+//! Hardware independent button types, shared by the PicoSystem and the simulator.
+
+pub mod combos;
+pub mod events;
+pub mod replay;
+#[cfg(not(feature = "simulator"))]
+pub mod scanner;
+pub mod state;
+
+/// One of the eight PicoSystem buttons, in GPIO order starting at pin 16.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, defmt::Format)]
+#[repr(u8)]
+pub enum ButtonId {
+    Y = 0,
+    X = 1,
+    A = 2,
+    B = 3,
+    Down = 4,
+    Right = 5,
+    Left = 6,
+    Up = 7,
+}
+
+impl ButtonId {
+    pub const ALL: [ButtonId; 8] = [
+        ButtonId::Y,
+        ButtonId::X,
+        ButtonId::A,
+        ButtonId::B,
+        ButtonId::Down,
+        ButtonId::Right,
+        ButtonId::Left,
+        ButtonId::Up,
+    ];
+
+    /// The bit this button occupies in a [`Buttons`] mask
+    pub const fn mask(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// A set of buttons packed into a bitmask, bit `n` is the button on GPIO `16 + n`.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug, defmt::Format)]
+pub struct Buttons(pub u8);
+
+impl Buttons {
+    pub const NONE: Buttons = Buttons(0);
+
+    pub fn contains(self, button: ButtonId) -> bool {
+        self.0 & button.mask() != 0
+    }
+
+    pub fn set(&mut self, button: ButtonId, pressed: bool) {
+        match pressed {
+            true => self.0 |= button.mask(),
+            false => self.0 &= !button.mask(),
+        }
+    }
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
@@ -0,0 +1,358 @@
+//! Recording button input update by update and playing it back, to reproduce what a tester did.
+//! It goes by the game's fixed updates rather than presented frames, so a replay plays out the
+//! same however fast the frames were drawn.
+//!
+//! A recording is a byte buffer that can be kept in RAM, written to flash or sent over USB:
+//! a 12 byte header (`PSR1`, the RNG seed and the update count, little endian), then one entry
+//! per change of the buttons, the updates since the previous change as a LEB128 varint
+//! followed by the new [`Buttons`] mask. Updates where nothing changes take no space.
+//! [`store`] and [`load`] keep one recording where the USB console can save and load it.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+use super::Buttons;
+
+const MAGIC: &[u8; 4] = b"PSR1";
+const HEADER_LEN: usize = 12;
+
+///
+/// Records the buttons of every update into a caller provided buffer.
+///
+pub struct Recorder<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+    updates: u32,
+    last_change: u32,
+    last: Buttons,
+    full: bool,
+}
+
+impl<'a> Recorder<'a> {
+    ///
+    /// Starts a recording, returns None if `buffer` cannot even hold the header
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` - where the recording is kept
+    /// * `seed` - RNG seed the game runs with, stored for the replay
+    ///
+    pub fn new(buffer: &'a mut [u8], seed: u32) -> Option<Self> {
+        let header = buffer.get_mut(..HEADER_LEN)?;
+        header[..4].copy_from_slice(MAGIC);
+        header[4..8].copy_from_slice(&seed.to_le_bytes());
+        header[8..].copy_from_slice(&0u32.to_le_bytes());
+        Some(Self {
+            buffer,
+            len: HEADER_LEN,
+            updates: 0,
+            last_change: 0,
+            last: Buttons::NONE,
+            full: false,
+        })
+    }
+
+    /// Records the buttons of the next update, ignored once the buffer is full
+    pub fn record(&mut self, buttons: Buttons) {
+        if self.full {
+            return;
+        }
+
+        if buttons != self.last {
+            let mut entry = [0u8; 6];
+            let mut entry_len = write_varint(&mut entry, self.updates - self.last_change);
+            entry[entry_len] = buttons.bits();
+            entry_len += 1;
+
+            let Some(space) = self.buffer.get_mut(self.len..self.len + entry_len) else {
+                self.full = true;
+                return;
+            };
+            space.copy_from_slice(&entry[..entry_len]);
+            self.len += entry_len;
+            self.last_change = self.updates;
+            self.last = buttons;
+        }
+
+        self.updates += 1;
+    }
+
+    /// True once the buffer ran out, the recording stops at the update before
+    pub fn is_full(&self) -> bool {
+        self.full
+    }
+
+    pub fn updates(&self) -> u32 {
+        self.updates
+    }
+
+    /// The recording so far, ready to save or hand to a [`Replayer`]
+    pub fn finish(&mut self) -> &[u8] {
+        self.buffer[8..HEADER_LEN].copy_from_slice(&self.updates.to_le_bytes());
+        &self.buffer[..self.len]
+    }
+}
+
+///
+/// Plays a recording back one update at a time.
+///
+#[derive(Clone)]
+pub struct Replayer<'a> {
+    data: &'a [u8],
+    position: usize,
+    seed: u32,
+    updates: u32,
+    update: u32,
+    current: Buttons,
+    // Update the next entry applies to, with its buttons
+    next: Option<(u32, Buttons)>,
+}
+
+impl<'a> Replayer<'a> {
+    /// Reads a recording made by [`Recorder`], None if it is not one
+    pub fn new(data: &'a [u8]) -> Option<Self> {
+        let header = data.get(..HEADER_LEN)?;
+        if &header[..4] != MAGIC {
+            return None;
+        }
+
+        let mut replayer = Self {
+            data,
+            position: HEADER_LEN,
+            seed: u32::from_le_bytes(header[4..8].try_into().ok()?),
+            updates: u32::from_le_bytes(header[8..12].try_into().ok()?),
+            update: 0,
+            current: Buttons::NONE,
+            next: None,
+        };
+        replayer.next = replayer.read_entry(0);
+        Some(replayer)
+    }
+
+    /// RNG seed the recorded game ran with
+    pub fn seed(&self) -> u32 {
+        self.seed
+    }
+
+    /// Number of recorded updates
+    pub fn updates(&self) -> u32 {
+        self.updates
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.update >= self.updates
+    }
+
+    /// Buttons of the next update, None once all recorded updates are played
+    pub fn next_update(&mut self) -> Option<Buttons> {
+        if self.is_finished() {
+            return None;
+        }
+
+        if let Some((update, buttons)) = self.next {
+            if update == self.update {
+                self.current = buttons;
+                self.next = self.read_entry(update);
+            }
+        }
+        self.update += 1;
+        Some(self.current)
+    }
+
+    fn read_entry(&mut self, after: u32) -> Option<(u32, Buttons)> {
+        let (delta, len) = read_varint(self.data.get(self.position..)?)?;
+        let buttons = *self.data.get(self.position + len)?;
+        self.position += len + 1;
+        Some((after + delta, Buttons(buttons)))
+    }
+}
+
+impl Iterator for Replayer<'_> {
+    type Item = Buttons;
+
+    fn next(&mut self) -> Option<Buttons> {
+        self.next_update()
+    }
+}
+
+/// Writes `value` as LEB128 into `out`, returns the bytes used, at most 5
+fn write_varint(out: &mut [u8], mut value: u32) -> usize {
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out[len] = byte;
+            return len + 1;
+        }
+        out[len] = byte | 0x80;
+        len += 1;
+    }
+}
+
+/// Reads a LEB128 value, returns it with the bytes used
+fn read_varint(data: &[u8]) -> Option<(u32, usize)> {
+    let mut value = 0u32;
+    for (index, byte) in data.iter().take(5).enumerate() {
+        value |= ((byte & 0x7f) as u32) << (7 * index);
+        if byte & 0x80 == 0 {
+            return Some((value, index + 1));
+        }
+    }
+    None
+}
+
+///
+/// Where a game takes its buttons from, returned by [`Game::buttons`](crate::engine::game::Game::buttons)
+/// to record a session or play one back.
+///
+#[derive(Default)]
+pub enum InputSource<'a> {
+    /// The buttons as they are
+    #[default]
+    Live,
+    /// The buttons as they are, recording each update
+    Record(Recorder<'a>),
+    /// A recording, then the live buttons once it ends
+    Replay(Replayer<'a>),
+}
+
+impl InputSource<'_> {
+    /// Buttons for the next update, `live` being the ones held right now
+    pub fn next(&mut self, live: Buttons) -> Buttons {
+        match self {
+            InputSource::Live => live,
+            InputSource::Record(recorder) => {
+                recorder.record(live);
+                live
+            }
+            InputSource::Replay(replayer) => replayer.next_update().unwrap_or(live),
+        }
+    }
+
+    /// True while a recording is being played back
+    pub fn is_replaying(&self) -> bool {
+        matches!(self, InputSource::Replay(replayer) if !replayer.is_finished())
+    }
+}
+
+/// Largest recording [`store`] keeps, about 10 minutes of busy play
+pub const SLOT_CAPACITY: usize = 4096;
+
+/// The one recording kept for saving and loading
+static SLOT: Mutex<CriticalSectionRawMutex, RefCell<heapless::Vec<u8, SLOT_CAPACITY>>> =
+    Mutex::new(RefCell::new(heapless::Vec::new()));
+
+/// Keeps a copy of `recording` to be saved later, false if it is not one or is too long
+pub fn store(recording: &[u8]) -> bool {
+    if Replayer::new(recording).is_none() {
+        return false;
+    }
+    SLOT.lock(|slot| {
+        let mut slot = slot.borrow_mut();
+        slot.clear();
+        slot.extend_from_slice(recording).is_ok()
+    })
+}
+
+/// Copies the kept recording into `buffer` and returns its length, None if there is none
+/// or it does not fit
+pub fn load(buffer: &mut [u8]) -> Option<usize> {
+    SLOT.lock(|slot| {
+        let slot = slot.borrow();
+        let len = slot.len();
+        if len == 0 {
+            return None;
+        }
+        buffer.get_mut(..len)?.copy_from_slice(&slot);
+        Some(len)
+    })
+}
+
+/// Drops the kept recording
+pub fn clear() {
+    SLOT.lock(|slot| slot.borrow_mut().clear());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::ButtonId;
+
+    fn buttons(pressed: &[ButtonId]) -> Buttons {
+        let mut buttons = Buttons::NONE;
+        for &button in pressed {
+            buttons.set(button, true);
+        }
+        buttons
+    }
+
+    #[test]
+    fn plays_back_what_was_recorded() {
+        // Long gaps take more than one varint byte
+        let mut updates = vec![Buttons::NONE; 3];
+        updates.extend([buttons(&[ButtonId::A]); 200]);
+        updates.extend([buttons(&[ButtonId::A, ButtonId::Left]); 2]);
+        updates.extend([Buttons::NONE; 20_000]);
+        updates.push(buttons(&[ButtonId::B]));
+
+        let mut buffer = [0; 64];
+        let mut recorder = Recorder::new(&mut buffer, 1234).unwrap();
+        for &update in &updates {
+            recorder.record(update);
+        }
+        assert!(!recorder.is_full());
+        assert_eq!(recorder.updates(), updates.len() as u32);
+
+        let recording = recorder.finish();
+        // Header, then 1 + 2 + 2 + 3 + 3 bytes for the changes
+        assert_eq!(recording.len(), HEADER_LEN + 11);
+        let replayer = Replayer::new(recording).unwrap();
+        assert_eq!(replayer.seed(), 1234);
+        assert_eq!(replayer.updates(), updates.len() as u32);
+        assert_eq!(replayer.collect::<Vec<_>>(), updates);
+    }
+
+    #[test]
+    fn stops_recording_once_full() {
+        let mut buffer = [0; HEADER_LEN + 4];
+        let mut recorder = Recorder::new(&mut buffer, 0).unwrap();
+        for button in [ButtonId::A, ButtonId::B, ButtonId::X] {
+            recorder.record(buttons(&[button]));
+        }
+        assert!(recorder.is_full());
+        assert_eq!(recorder.updates(), 2);
+
+        let replayed: Vec<_> = Replayer::new(recorder.finish()).unwrap().collect();
+        assert_eq!(replayed, [buttons(&[ButtonId::A]), buttons(&[ButtonId::B])]);
+        assert!(Recorder::new(&mut [0; HEADER_LEN - 1], 0).is_none());
+    }
+
+    #[test]
+    fn rejects_what_is_not_a_recording() {
+        assert!(Replayer::new(b"PSR2\0\0\0\0\0\0\0\0").is_none());
+        assert!(Replayer::new(b"PSR1").is_none());
+    }
+
+    #[test]
+    fn replay_source_falls_back_to_live_buttons() {
+        let mut buffer = [0; 32];
+        let mut source = InputSource::Record(Recorder::new(&mut buffer, 0).unwrap());
+        let pressed = buttons(&[ButtonId::Up]);
+        assert_eq!(source.next(pressed), pressed);
+        assert_eq!(source.next(Buttons::NONE), Buttons::NONE);
+        let InputSource::Record(mut recorder) = source else {
+            unreachable!()
+        };
+
+        let mut source = InputSource::Replay(Replayer::new(recorder.finish()).unwrap());
+        let live = buttons(&[ButtonId::Y]);
+        assert!(source.is_replaying());
+        assert_eq!(source.next(live), pressed);
+        assert_eq!(source.next(live), Buttons::NONE);
+        assert!(!source.is_replaying());
+        assert_eq!(source.next(live), live);
+    }
+}
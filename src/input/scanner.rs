@@ -0,0 +1,114 @@
+//! Watches the eight button GPIOs (16-23) from one background task.
+//! It sleeps on the pin interrupts, debounces all buttons together once one changes and
+//! publishes the result through [`events`](super::events).
+//! It also puts the chip into dormant sleep, as the buttons are what wakes it up.
+
+use embassy_futures::select::{select, select_array, Either};
+use embassy_rp::clocks;
+use embassy_rp::gpio::{AnyPin, DormantWakeConfig, Input, Pull};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+
+use super::events;
+use super::{ButtonId, Buttons};
+
+/// Time between samples while a change settles
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Samples in a row that have to agree before a change counts, about 5ms of no bouncing
+const STABLE_SAMPLES: u8 = 5;
+
+/// Asks the scanner to go dormant
+static DORMANT: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Raised once a button woke the chip and the buttons were published again
+static WOKEN: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+///
+/// Stops the clocks until a button is pressed, the deepest sleep there is. Buttons held down
+/// already have to be let go and pressed again. Everything else should be switched off
+/// first, as nothing runs until then, timers included.
+///
+pub async fn dormant_until_pressed() {
+    WOKEN.reset();
+    DORMANT.signal(());
+    WOKEN.wait().await;
+}
+
+/// Arms every button to wake the chip when it is pressed and goes dormant
+fn dormant(inputs: &mut [Input<'static>; 8]) {
+    let config = DormantWakeConfig {
+        edge_high: false,
+        edge_low: true,
+        level_high: false,
+        level_low: false,
+    };
+    let _wakes = inputs.each_mut().map(|input| input.dormant_wake(config));
+    clocks::dormant_sleep();
+}
+
+/// Reads all buttons at once, the inputs are in [`ButtonId`] order and pulled up
+fn sample(inputs: &[Input<'static>; 8]) -> Buttons {
+    let mut buttons = Buttons::NONE;
+    for (button, input) in ButtonId::ALL.into_iter().zip(inputs) {
+        buttons.set(button, input.is_low());
+    }
+    buttons
+}
+
+/// Waits for a button to leave the state it was published in. It waits on the level rather
+/// than an edge, so a change made before the wait is armed still counts.
+async fn wait_for_change(input: &mut Input<'static>, held: bool) {
+    match held {
+        true => input.wait_for_high().await,
+        false => input.wait_for_low().await,
+    }
+}
+
+///
+/// Scans the buttons forever, spawn it once
+///
+/// # Arguments
+///
+/// * `pins` - GPIO 16 to 23, in [`ButtonId`] order
+///
+#[embassy_executor::task]
+pub async fn scan_task(pins: [AnyPin; 8]) -> ! {
+    let mut inputs = pins.map(|pin| Input::new(pin, Pull::Up));
+    events::publish(sample(&inputs), Instant::now());
+
+    loop {
+        let published = events::current();
+        let mut held = ButtonId::ALL
+            .map(|button| published.contains(button))
+            .into_iter();
+        let changes = inputs
+            .each_mut()
+            .map(|input| wait_for_change(input, held.next() == Some(true)));
+        let next = select(select_array(changes), DORMANT.wait()).await;
+        let woke = match next {
+            Either::First(_) => false,
+            Either::Second(()) => {
+                dormant(&mut inputs);
+                true
+            }
+        };
+        let changed_at = Instant::now();
+
+        // Bouncing contacts fire more edges, so sample until everything holds still
+        let mut buttons = sample(&inputs);
+        let mut stable = 0;
+        while stable < STABLE_SAMPLES {
+            Timer::after(SAMPLE_INTERVAL).await;
+            let now = sample(&inputs);
+            stable = if now == buttons { stable + 1 } else { 0 };
+            buttons = now;
+        }
+
+        events::publish(buttons, changed_at);
+        if woke {
+            WOKEN.signal(());
+        }
+    }
+}
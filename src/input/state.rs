@@ -0,0 +1,239 @@
+//! Button state tracked from one sample to the next, for edges, hold times and auto-repeat.
+//! Debouncing compares the last few samples instead of waiting on timers, so it never blocks.
+
+use embassy_time::{Duration, Instant};
+
+use super::{ButtonId, Buttons};
+
+/// Most samples that can be used for debouncing
+pub const MAX_DEBOUNCE_SAMPLES: usize = 8;
+
+/// Samples a change has to last for by default, two frames at 60Hz is about 17-33ms
+const DEFAULT_DEBOUNCE_SAMPLES: usize = 2;
+
+/// Auto-repeat timing for held buttons, like a keyboard
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Repeat {
+    /// Time from the press to the first repeat
+    pub delay: Duration,
+    /// Time between repeats after that
+    pub interval: Duration,
+}
+
+impl Default for Repeat {
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_millis(400),
+            interval: Duration::from_millis(100),
+        }
+    }
+}
+
+///
+/// The eight buttons as of the last sample, with what changed since the one before.
+///
+pub struct InputState {
+    history: [Buttons; MAX_DEBOUNCE_SAMPLES],
+    samples: usize,
+    next_sample: usize,
+    current: Buttons,
+    previous: Buttons,
+    repeated: Buttons,
+    repeat: Option<Repeat>,
+    now: Instant,
+    pressed_at: [Instant; 8],
+    next_repeat: [Instant; 8],
+}
+
+impl InputState {
+    pub fn new() -> Self {
+        Self {
+            history: [Buttons::NONE; MAX_DEBOUNCE_SAMPLES],
+            samples: DEFAULT_DEBOUNCE_SAMPLES,
+            next_sample: 0,
+            current: Buttons::NONE,
+            previous: Buttons::NONE,
+            repeated: Buttons::NONE,
+            repeat: None,
+            now: Instant::from_ticks(0),
+            pressed_at: [Instant::from_ticks(0); 8],
+            next_repeat: [Instant::from_ticks(0); 8],
+        }
+    }
+
+    /// A button only changes once this many samples in a row agree, 1 turns debouncing off
+    pub fn with_debounce(mut self, samples: usize) -> Self {
+        self.samples = samples.clamp(1, MAX_DEBOUNCE_SAMPLES);
+        self
+    }
+
+    /// Turns on auto-repeat, see [`InputState::triggered`]
+    pub fn with_repeat(mut self, repeat: Repeat) -> Self {
+        self.repeat = Some(repeat);
+        self
+    }
+
+    /// Changes auto-repeat, buttons already held first repeat after the new delay from now
+    pub fn set_repeat(&mut self, repeat: Option<Repeat>) {
+        self.repeat = repeat;
+        if let Some(repeat) = repeat {
+            for button in ButtonId::ALL {
+                if self.pressed(button) {
+                    self.next_repeat[button as usize] = self.now + repeat.delay;
+                }
+            }
+        }
+    }
+
+    ///
+    /// Takes a new sample of the buttons, once per game update
+    ///
+    /// # Arguments
+    ///
+    /// * `raw` - buttons held down right now, straight from the hardware
+    /// * `now` - time of the sample
+    ///
+    pub fn update(&mut self, raw: Buttons, now: Instant) {
+        self.history[self.next_sample] = raw;
+        self.next_sample = (self.next_sample + 1) % self.samples;
+
+        // Bits held in every recent sample are down, bits clear in every one are up,
+        // anything in between is still bouncing and keeps its old state
+        let recent = &self.history[..self.samples];
+        let all_down = recent
+            .iter()
+            .fold(u8::MAX, |bits, sample| bits & sample.bits());
+        let any_down = recent.iter().fold(0, |bits, sample| bits | sample.bits());
+        let stable = (self.current.bits() | all_down) & any_down;
+
+        self.previous = self.current;
+        self.current = Buttons(stable);
+        self.now = now;
+        self.repeated = Buttons::NONE;
+
+        for button in ButtonId::ALL {
+            let index = button as usize;
+            if self.just_pressed(button) {
+                self.pressed_at[index] = now;
+                if let Some(repeat) = self.repeat {
+                    self.next_repeat[index] = now + repeat.delay;
+                }
+            } else if let (true, Some(repeat)) = (self.pressed(button), self.repeat) {
+                if now >= self.next_repeat[index] {
+                    self.repeated.set(button, true);
+                    self.next_repeat[index] += repeat.interval;
+                }
+            }
+        }
+    }
+
+    /// Time of the last sample
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+
+    /// Buttons held down
+    pub fn buttons(&self) -> Buttons {
+        self.current
+    }
+
+    pub fn pressed(&self, button: ButtonId) -> bool {
+        self.current.contains(button)
+    }
+
+    /// Pressed in this sample but not the one before
+    pub fn just_pressed(&self, button: ButtonId) -> bool {
+        self.current.contains(button) && !self.previous.contains(button)
+    }
+
+    /// Let go in this sample
+    pub fn just_released(&self, button: ButtonId) -> bool {
+        !self.current.contains(button) && self.previous.contains(button)
+    }
+
+    /// How long the button has been held, None when it is up
+    pub fn held_time(&self, button: ButtonId) -> Option<Duration> {
+        self.pressed(button)
+            .then(|| self.now - self.pressed_at[button as usize])
+    }
+
+    /// True while the button has been held for at least `duration`
+    pub fn held_for(&self, button: ButtonId, duration: Duration) -> bool {
+        self.held_time(button).is_some_and(|held| held >= duration)
+    }
+
+    /// Just pressed, or auto-repeating while held. Menus move on this.
+    pub fn triggered(&self, button: ButtonId) -> bool {
+        self.just_pressed(button) || self.repeated.contains(button)
+    }
+}
+
+impl Default for InputState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REPEAT: Repeat = Repeat {
+        delay: Duration::from_millis(400),
+        interval: Duration::from_millis(100),
+    };
+
+    fn a() -> Buttons {
+        let mut buttons = Buttons::NONE;
+        buttons.set(ButtonId::A, true);
+        buttons
+    }
+
+    /// Samples `buttons` every 10ms from `from` up to `to`, returns the times it triggered
+    fn hold(state: &mut InputState, buttons: Buttons, from: u64, to: u64) -> Vec<u64> {
+        (from..to)
+            .step_by(10)
+            .filter(|&millis| {
+                state.update(buttons, Instant::from_millis(millis));
+                state.triggered(ButtonId::A)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn debounces_presses_and_releases() {
+        let mut state = InputState::new();
+        state.update(a(), Instant::from_millis(0));
+        assert!(!state.pressed(ButtonId::A));
+        state.update(a(), Instant::from_millis(10));
+        assert!(state.just_pressed(ButtonId::A));
+
+        // A one sample bounce is ignored
+        state.update(Buttons::NONE, Instant::from_millis(20));
+        assert!(state.pressed(ButtonId::A));
+        state.update(a(), Instant::from_millis(30));
+        state.update(Buttons::NONE, Instant::from_millis(40));
+        state.update(Buttons::NONE, Instant::from_millis(50));
+        assert!(state.just_released(ButtonId::A));
+    }
+
+    #[test]
+    fn repeats_held_buttons() {
+        let mut state = InputState::new().with_debounce(1).with_repeat(REPEAT);
+        let triggered = hold(&mut state, a(), 0, 610);
+        assert_eq!(triggered, [0, 400, 500, 600]);
+        assert_eq!(
+            state.held_time(ButtonId::A),
+            Some(Duration::from_millis(600))
+        );
+    }
+
+    #[test]
+    fn turning_repeat_on_while_held_waits_the_delay() {
+        let mut state = InputState::new().with_debounce(1);
+        assert_eq!(hold(&mut state, a(), 0, 1000), [0]);
+
+        state.set_repeat(Some(REPEAT));
+        assert_eq!(hold(&mut state, a(), 1000, 1510), [1390, 1490]);
+    }
+}
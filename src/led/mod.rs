@@ -0,0 +1,340 @@
+//! The RGB status LED. Games pick a colour or an effect here and it plays in the background,
+//! driven by [`rgb::led_task`] on the PicoSystem. A low battery and charging take over the
+//! LED unless [`set_status_indicators`] turns that off.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant};
+use embedded_graphics::pixelcolor::Rgb888;
+use embedded_graphics::prelude::RgbColor;
+
+use crate::power::{ChargeState, PowerStatus};
+
+#[cfg(not(feature = "simulator"))]
+pub mod rgb;
+
+/// What the LED shows.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Effect {
+    Off,
+    Solid(Rgb888),
+    /// On for `on`, then off for `off`, over and over
+    Blink {
+        color: Rgb888,
+        on: Duration,
+        off: Duration,
+    },
+    /// Fades in and back out once per `period`
+    Breathe {
+        color: Rgb888,
+        period: Duration,
+    },
+}
+
+impl Effect {
+    /// Colour `elapsed` into the effect, at full brightness
+    pub fn color_at(&self, elapsed: Duration) -> Rgb888 {
+        match *self {
+            Effect::Off => Rgb888::BLACK,
+            Effect::Solid(color) => color,
+            Effect::Blink { color, on, off } => {
+                let period = (on + off).as_ticks().max(1);
+                match elapsed.as_ticks() % period < on.as_ticks() {
+                    true => color,
+                    false => Rgb888::BLACK,
+                }
+            }
+            Effect::Breathe { color, period } => {
+                let period = period.as_ticks().max(1);
+                let phase = (elapsed.as_ticks() % period * 512 / period) as u32;
+                // Triangle up and down, eased so it lingers at both ends
+                let t = 256 - phase.abs_diff(256);
+                let level = (t * t * (768 - 2 * t)) >> 16;
+                scale(color, level.min(255) as u8)
+            }
+        }
+    }
+
+    /// Whether the colour changes over time
+    pub fn is_animated(&self) -> bool {
+        matches!(self, Effect::Blink { .. } | Effect::Breathe { .. })
+    }
+}
+
+/// A flash over the effect, fading out
+#[derive(Copy, Clone, Debug)]
+struct Pulse {
+    color: Rgb888,
+    duration: Duration,
+    start: Instant,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Led {
+    effect: Effect,
+    start: Instant,
+    brightness: u8,
+    pulse: Option<Pulse>,
+    status_indicators: bool,
+}
+
+static LED: Mutex<CriticalSectionRawMutex, Cell<Led>> = Mutex::new(Cell::new(Led {
+    effect: Effect::Off,
+    start: Instant::from_ticks(0),
+    brightness: 100,
+    pulse: None,
+    status_indicators: true,
+}));
+
+/// Raised whenever the LED is told to change, wakes the LED task
+static CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Time between updates while an effect plays
+const FRAME: Duration = Duration::from_millis(20);
+
+/// Time between checks of the battery while the status indicators are on
+const STATUS_CHECK: Duration = Duration::from_secs(1);
+
+fn update(f: impl FnOnce(&mut Led)) {
+    LED.lock(|led| {
+        let mut state = led.get();
+        f(&mut state);
+        led.set(state);
+    });
+    CHANGED.signal(());
+}
+
+/// Starts an effect from its beginning
+pub fn set(effect: Effect) {
+    update(|led| {
+        led.effect = effect;
+        led.start = Instant::now();
+    });
+}
+
+/// Shows a colour, black turns the LED off
+pub fn set_color(color: Rgb888) {
+    set(Effect::Solid(color));
+}
+
+/// Brightness set by [`set_brightness`]
+pub fn brightness() -> u8 {
+    LED.lock(|led| led.get().brightness)
+}
+
+/// Scales everything the LED shows, in percent
+pub fn set_brightness(brightness: u8) {
+    update(|led| led.brightness = brightness.min(100));
+}
+
+/// Flashes `color` over the effect and fades back to it over `duration`, for game events
+pub fn pulse(color: Rgb888, duration: Duration) {
+    update(|led| {
+        led.pulse = Some(Pulse {
+            color,
+            duration,
+            start: Instant::now(),
+        })
+    });
+}
+
+/// Lets a low battery blink the LED red and charging breathe it green, on by default
+pub fn set_status_indicators(enabled: bool) {
+    update(|led| led.status_indicators = enabled);
+}
+
+/// What the LED shows for the battery, if anything
+fn status_effect(status: &PowerStatus) -> Option<Effect> {
+    if status.is_low() {
+        return Some(Effect::Blink {
+            color: Rgb888::RED,
+            on: Duration::from_millis(100),
+            off: Duration::from_millis(1900),
+        });
+    }
+    match status.charge {
+        ChargeState::Charging => Some(Effect::Breathe {
+            color: Rgb888::GREEN,
+            period: Duration::from_secs(3),
+        }),
+        ChargeState::Charged | ChargeState::Discharging => None,
+    }
+}
+
+///
+/// Colour the LED shows at `now`, and how soon it may change without being told to
+///
+/// # Arguments
+///
+/// * `now` - the time to show
+/// * `power` - the battery, for the status indicators
+///
+pub fn color_at(now: Instant, power: &PowerStatus) -> (Rgb888, Option<Duration>) {
+    LED.lock(|led| led.get()).color_at(now, power)
+}
+
+impl Led {
+    fn color_at(&self, now: Instant, power: &PowerStatus) -> (Rgb888, Option<Duration>) {
+        let status = self
+            .status_indicators
+            .then(|| status_effect(power))
+            .flatten();
+        let (effect, start) = match status {
+            Some(effect) => (effect, Instant::from_ticks(0)),
+            None => (self.effect, self.start),
+        };
+        let mut color = effect.color_at(now.saturating_duration_since(start));
+        let mut next = match (effect.is_animated(), self.status_indicators) {
+            (true, _) => Some(FRAME),
+            (false, true) => Some(STATUS_CHECK),
+            (false, false) => None,
+        };
+
+        if let Some(pulse) = self.pulse {
+            let elapsed = now.saturating_duration_since(pulse.start);
+            if elapsed < pulse.duration {
+                let left = (pulse.duration - elapsed).as_ticks() * 255 / pulse.duration.as_ticks();
+                color = mix(color, pulse.color, left as u8);
+                next = Some(FRAME);
+            }
+        }
+
+        (
+            scale(color, (self.brightness as u32 * 255 / 100) as u8),
+            next,
+        )
+    }
+}
+
+/// Waits until the LED is told to change
+pub async fn wait_change() {
+    CHANGED.wait().await
+}
+
+/// Scales a colour by `level` out of 255
+pub fn scale(color: Rgb888, level: u8) -> Rgb888 {
+    let channel = |c: u8| (c as u32 * level as u32 / 255) as u8;
+    Rgb888::new(channel(color.r()), channel(color.g()), channel(color.b()))
+}
+
+/// Blends from `from` to `to` by `amount` out of 255
+fn mix(from: Rgb888, to: Rgb888, amount: u8) -> Rgb888 {
+    let channel =
+        |a: u8, b: u8| ((a as u32 * (255 - amount as u32) + b as u32 * amount as u32) / 255) as u8;
+    Rgb888::new(
+        channel(from.r(), to.r()),
+        channel(from.g(), to.g()),
+        channel(from.b(), to.b()),
+    )
+}
+
+/// PWM duty out of 65535 for a level out of 255, squared so steps look even to the eye
+pub fn gamma(level: u8) -> u16 {
+    (level as u32 * level as u32 * 65535 / (255 * 255)) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn led(effect: Effect) -> Led {
+        Led {
+            effect,
+            start: Instant::from_ticks(0),
+            brightness: 100,
+            pulse: None,
+            status_indicators: true,
+        }
+    }
+
+    fn at(millis: u64) -> Instant {
+        Instant::from_millis(millis)
+    }
+
+    #[test]
+    fn blinks_on_then_off() {
+        let blink = Effect::Blink {
+            color: Rgb888::BLUE,
+            on: Duration::from_millis(100),
+            off: Duration::from_millis(300),
+        };
+        let color = |millis| blink.color_at(Duration::from_millis(millis));
+        assert_eq!(color(0), Rgb888::BLUE);
+        assert_eq!(color(99), Rgb888::BLUE);
+        assert_eq!(color(100), Rgb888::BLACK);
+        assert_eq!(color(399), Rgb888::BLACK);
+        assert_eq!(color(450), Rgb888::BLUE);
+    }
+
+    #[test]
+    fn breathes_in_and_out_once_per_period() {
+        let breathe = Effect::Breathe {
+            color: Rgb888::WHITE,
+            period: Duration::from_millis(1000),
+        };
+        let color = |millis| breathe.color_at(Duration::from_millis(millis));
+        assert_eq!(color(0), Rgb888::BLACK);
+        assert_eq!(color(250), Rgb888::new(128, 128, 128));
+        assert_eq!(color(500), Rgb888::WHITE);
+        assert_eq!(color(750), Rgb888::new(128, 128, 128));
+        assert_eq!(color(1000), Rgb888::BLACK);
+    }
+
+    #[test]
+    fn a_pulse_fades_back_to_the_effect() {
+        let mut state = led(Effect::Solid(Rgb888::BLUE));
+        state.pulse = Some(Pulse {
+            color: Rgb888::WHITE,
+            duration: Duration::from_millis(100),
+            start: at(1000),
+        });
+        let battery = PowerStatus::default();
+
+        assert_eq!(
+            state.color_at(at(1000), &battery),
+            (Rgb888::WHITE, Some(FRAME))
+        );
+        assert_eq!(
+            state.color_at(at(1050), &battery),
+            (Rgb888::new(127, 127, 255), Some(FRAME))
+        );
+        assert_eq!(
+            state.color_at(at(1100), &battery),
+            (Rgb888::BLUE, Some(STATUS_CHECK))
+        );
+    }
+
+    #[test]
+    fn a_low_battery_takes_over_unless_turned_off() {
+        let low = PowerStatus::new(3500, false, false);
+        let mut state = led(Effect::Solid(Rgb888::BLUE));
+        assert_eq!(state.color_at(at(50), &low).0, Rgb888::RED);
+        assert_eq!(state.color_at(at(500), &low).0, Rgb888::BLACK);
+        assert_eq!(
+            state.color_at(at(50), &PowerStatus::default()).0,
+            Rgb888::BLUE
+        );
+
+        // Charging breathes green at its peak half way through
+        let charging = PowerStatus::new(3900, true, true);
+        assert_eq!(state.color_at(at(1500), &charging).0, Rgb888::GREEN);
+
+        state.status_indicators = false;
+        assert_eq!(state.color_at(at(50), &low), (Rgb888::BLUE, None));
+    }
+
+    #[test]
+    fn brightness_scales_everything() {
+        let mut state = led(Effect::Solid(Rgb888::WHITE));
+        state.brightness = 50;
+        assert_eq!(
+            state.color_at(at(0), &PowerStatus::default()).0,
+            Rgb888::new(127, 127, 127)
+        );
+        assert_eq!(gamma(0), 0);
+        assert_eq!(gamma(255), 65535);
+    }
+}
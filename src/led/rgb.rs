@@ -0,0 +1,55 @@
+//! The RGB LED on the PicoSystem: red on GPIO 14 and blue on GPIO 15 share PWM slice 7,
+//! green on GPIO 13 shares slice 6 with the backlight.
+
+use embassy_futures::select::select;
+use embassy_rp::pwm::{PwmOutput, SetDutyCycle};
+use embassy_time::{Instant, Timer};
+use embedded_graphics::pixelcolor::Rgb888;
+use embedded_graphics::prelude::RgbColor;
+
+use super::{color_at, gamma, wait_change};
+use crate::power;
+
+///
+/// Mixes 24 bit colours from three PWM channels.
+///
+pub struct RgbLed<'a> {
+    red: PwmOutput<'a>,
+    green: PwmOutput<'a>,
+    blue: PwmOutput<'a>,
+}
+
+impl<'a> RgbLed<'a> {
+    pub fn new(red: PwmOutput<'a>, green: PwmOutput<'a>, blue: PwmOutput<'a>) -> Self {
+        let mut led = Self { red, green, blue };
+        led.set(Rgb888::BLACK);
+        led
+    }
+
+    /// Shows a colour straight away
+    pub fn set(&mut self, color: Rgb888) {
+        for (output, level) in [
+            (&mut self.red, color.r()),
+            (&mut self.green, color.g()),
+            (&mut self.blue, color.b()),
+        ] {
+            let max = output.max_duty_cycle() as u32;
+            let _ = output.set_duty_cycle((gamma(level) as u32 * max / 65535) as u16);
+        }
+    }
+}
+
+/// Plays the colours and effects set through [`led`](super), spawn it once
+#[embassy_executor::task]
+pub async fn led_task(mut led: RgbLed<'static>) -> ! {
+    loop {
+        let (color, next) = color_at(Instant::now(), &power::current());
+        led.set(color);
+        match next {
+            Some(next) => {
+                select(wait_change(), Timer::after(next)).await;
+            }
+            None => wait_change().await,
+        }
+    }
+}
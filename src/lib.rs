@@ -0,0 +1,22 @@
+//! Game engine for the Pimoroni PicoSystem: the display, input, audio, lights and power of the
+//! RP2040 handheld behind [`hal::PicoSystemHal`], plus a game loop, sprites and tilemaps to
+//! build games with. `main.rs` is a demo game on top of it, and with the `simulator` feature
+//! everything runs on the desktop and in host tests.
+
+#![cfg_attr(not(feature = "simulator"), no_std)]
+#![cfg_attr(not(feature = "simulator"), feature(impl_trait_in_assoc_type))]
+
+pub mod audio;
+pub mod backlight;
+pub mod console;
+pub mod display;
+pub mod engine;
+pub mod hal;
+pub mod input;
+pub mod led;
+#[cfg(not(feature = "simulator"))]
+pub mod peripherals;
+pub mod power;
+
+pub const WIDTH: usize = 240;
+pub const HEIGHT: usize = 240;
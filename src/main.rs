@@ -1,248 +1,220 @@
-#![no_std]
-#![no_main]
-#![feature(impl_trait_in_assoc_type)]
+#![cfg_attr(not(feature = "simulator"), no_std)]
+#![cfg_attr(not(feature = "simulator"), no_main)]
+#![cfg_attr(not(feature = "simulator"), feature(impl_trait_in_assoc_type))]
 
-use core::cell::RefCell;
 use core::fmt::Write;
-use defmt::info;
-// use display_interface_spi::asynch::SPIInterface;
-use display_interface_spi::SPIInterface;
-use embassy_embedded_hal::shared_bus::asynch::spi::SpiDeviceWithConfig;
+#[cfg(not(feature = "simulator"))]
 use embassy_executor::Spawner;
-use embassy_rp::{
-    bind_interrupts,
-    gpio::{Input, Level, Output, Pull},
-    peripherals::{PIO0, SPI0},
-    spi::{self, Spi},
-};
-use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
-// use embedded_hal_async::spi::SpiBus::Spi;
-// use embassy_sync::blocking_mutex::{raw::NoopRawMutex, Mutex};
-use display::{
-    batch::{to_blocks, to_rows, PixelBlock},
-    graphics::framebuffer,
-    Orientation, ST7789,
-};
-use embassy_time::{Delay, Instant};
+#[cfg(not(feature = "simulator"))]
+use embassy_rp::{bind_interrupts, peripherals::PIO0};
+use embassy_rp_w_template::audio::song::Song;
+use embassy_rp_w_template::audio::{self, Tone};
+use embassy_rp_w_template::display::blend::Blend;
+use embassy_rp_w_template::engine::game::{self, FrameStats, Game};
+use embassy_rp_w_template::engine::sprite::{Sprite, SpriteId, SpriteList};
+use embassy_rp_w_template::hal::PicoSystemHal;
+use embassy_rp_w_template::input::combos::{Gesture, LongPress};
+use embassy_rp_w_template::input::state::InputState;
+use embassy_rp_w_template::input::{ButtonId, Buttons};
+use embassy_rp_w_template::power::{self, PowerStatus};
+use embassy_rp_w_template::WIDTH;
+#[cfg(not(feature = "simulator"))]
+use embassy_rp_w_template::{backlight, peripherals};
+use embassy_time::Duration;
 use embedded_graphics::image::Image;
 use embedded_graphics::mono_font::ascii::FONT_10X20;
 use embedded_graphics::mono_font::MonoTextStyleBuilder;
-use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::pixelcolor::{Rgb565, Rgb888};
 use embedded_graphics::prelude::*;
 use embedded_graphics::text::Text;
 use tinybmp::Bmp;
+#[cfg(not(feature = "simulator"))]
 use {defmt_rtt as _, panic_probe as _};
 
+#[cfg(not(feature = "simulator"))]
 bind_interrupts!(struct Irqs {
     PIO0_IRQ_0 => embassy_rp::pio::InterruptHandler<PIO0>;
 });
-mod display;
-mod peripherals;
-
-pub const WIDTH: usize = 240;
-pub const HEIGHT: usize = 240;
-
-type Spi0Bus = Mutex<NoopRawMutex, Spi<'static, SPI0, spi::Async>>;
 
+#[cfg(not(feature = "simulator"))]
 #[embassy_executor::main]
-async fn main(_spawner: Spawner) {
-    let p = peripherals::init(Default::default());
-    // let mut led_g = p.LED_G;
-    // let mut led_r = p.LED_R;
-    let mut led_b = p.LED_B;
-    led_b.set_high();
-
-    // Display pins
-    let mut back_light = p.SCREEN_BACKLIGHT;
-
-    let display_cs = p.PIN_5;
-    let mut miso = p.PIN_6;
-    let mut mosi = p.PIN_7;
-
-    let mut vsync = Input::new(p.PIN_8, Pull::Down);
-
-    let dc = p.PIN_9;
-    let rst = p.PIN_4;
-
-    //SPI Display setup
-
-    let mut spi_config = spi::Config::default();
-    spi_config.frequency = 125_000_000u32;
-    let spi = Spi::new_txonly(p.SPI0, &mut miso, &mut mosi, p.DMA_CH0, spi_config);
-
-    let spi_bus: Mutex<NoopRawMutex, _> = Mutex::new(spi);
-
-    let dcx = Output::new(dc, Level::Low);
-    let rst = Output::new(rst, Level::Low);
-
-    let mut display_config = spi::Config::default();
-    // display_config.frequency = 80_000_000;
-    display_config.frequency = 62_500_000u32;
+async fn main(spawner: Spawner) {
+    let mut p = peripherals::init(Default::default(), spawner).await;
+    backlight::set_brightness(50);
+    backlight::turn_on(Duration::from_millis(250));
 
-    display_config.phase = spi::Phase::CaptureOnSecondTransition;
-    display_config.polarity = spi::Polarity::IdleHigh;
-
-    let display_spi = SpiDeviceWithConfig::new(
-        &spi_bus,
-        Output::new(display_cs, Level::High),
-        display_config,
-    );
-
-    let di = SPIInterface::new(display_spi, dcx);
-
-    let mut display = ST7789::new(di, Some(rst), 240, 240);
-
-    let _ = display.init(&mut Delay).await;
-    let _ = display
-        .set_tearing_effect(display::TearingEffect::Vertical)
-        .await;
-    let _ = display.set_orientation(Orientation::Portrait).await;
-    let _ = display.clear_screen(Rgb565::BLACK).await;
-    back_light.set_brightness(50);
-    back_light.toggle();
+    game::run(&mut Demo::new(), &mut p).await
+}
 
-    let bmp_data = include_bytes!("../assets/issac.bmp");
-    let bmp_issac: Bmp<Rgb565> = Bmp::from_slice(bmp_data).unwrap();
+/// Runs the demo against the desktop simulator until the window is closed.
+/// Set `PICOSYSTEM_CAPTURE` to a directory to render headless frames there instead of a window.
+#[cfg(feature = "simulator")]
+fn main() {
+    use embassy_rp_w_template::display::simulator::Simulator;
+
+    let mut simulator = match std::env::var("PICOSYSTEM_CAPTURE") {
+        Ok(dir) => Simulator::headless(dir, 60),
+        Err(_) => Simulator::windowed("PicoSystem", 2),
+    };
+    run_simulator(&mut Demo::new(), &mut simulator);
+}
 
-    let background_bmp: Bmp<Rgb565> =
-        Bmp::from_slice(include_bytes!("../assets/background.bmp")).unwrap();
-    let background = Image::new(&background_bmp, Point::new(0, 0));
+/// Runs `demo` until the simulator quits.
+/// There is no speaker, the player only keeps the tone queue moving in time.
+#[cfg(feature = "simulator")]
+fn run_simulator(
+    demo: &mut Demo,
+    simulator: &mut embassy_rp_w_template::display::simulator::Simulator,
+) {
+    use embassy_futures::select::{select, Either};
+
+    embassy_futures::block_on(async {
+        let game = game::run(demo, simulator);
+        match select(game, audio::run_player(|_, _| {})).await {
+            Either::First(()) => {}
+            Either::Second(never) => never,
+        }
+    })
+}
 
-    let mut issac_sprite = Sprite::new(Point::new(5, 50), bmp_issac);
+/// Where Issac starts, and goes back to when X+Y are held
+const START: Point = Point::new(5, 50);
+
+/// Issac walking around over the background to the theme tune, moved with the d-pad
+struct Demo {
+    background: Bmp<'static, Rgb565>,
+    sprites: SpriteList<'static>,
+    issac: SpriteId,
+    /// Holding X+Y for a second puts Issac back at the start
+    go_home: LongPress,
+    fps: heapless::String<255>,
+    /// The battery as of the last update
+    power: PowerStatus,
+}
 
-    let down_button = p.DOWN_BUTTON;
-    let right_button = p.RIGHT_BUTTON;
-    let left_button = p.LEFT_BUTTON;
-    let up_button = p.UP_BUTTON;
+impl Demo {
+    fn new() -> Self {
+        let bmp_data = include_bytes!("../assets/issac.bmp");
+        let bmp_issac: Bmp<Rgb565> = Bmp::from_slice(bmp_data).unwrap();
 
-    let mut frames = 0;
+        let background: Bmp<Rgb565> =
+            Bmp::from_slice(include_bytes!("../assets/background.bmp")).unwrap();
 
-    let start = Instant::now();
+        let mut sprites = SpriteList::new();
+        let issac = sprites
+            .add(Sprite::new(bmp_issac, START))
+            .ok()
+            .expect("sprite list starts empty");
 
-    let char_style = MonoTextStyleBuilder::new()
-        .font(&FONT_10X20)
-        .text_color(Rgb565::CSS_YELLOW)
-        .background_color(Rgb565::BLACK)
-        .build();
-    let mut buf = heapless::String::<255>::new();
-    let mut issacs_new_pos = Point::new(5, 50);
-    let mut sprite_movement = true;
+        Self {
+            background,
+            sprites,
+            issac,
+            go_home: LongPress::new(
+                Buttons(ButtonId::X.mask() | ButtonId::Y.mask()),
+                Duration::from_secs(1),
+            ),
+            fps: heapless::String::new(),
+            power: PowerStatus::default(),
+        }
+    }
+}
 
-    // let test_dma = p.DMA_CH1;
+impl Game for Demo {
+    fn init(&mut self, hal: &mut impl PicoSystemHal) {
+        hal.set_led(Rgb888::BLUE);
+        let _ = Image::new(&self.background, Point::new(0, 0)).draw(hal.display());
 
-    loop {
-        wait_vsync(&mut vsync).await;
-        let draw_start = Instant::now();
+        let theme = include_bytes!(concat!(env!("OUT_DIR"), "/theme.song"));
+        // Built from assets/theme.mml, so a broken one is a bug in the compiler
+        let song = Song::parse(theme).expect("the theme did not compile to a song");
+        audio::play_music(song);
+    }
 
-        // info!("loop");
-        if right_button.is_pressed() {
-            issacs_new_pos.x += 2;
-            sprite_movement = true;
+    fn update(&mut self, hal: &mut impl PicoSystemHal, input: &InputState, _dt: Duration) {
+        let mut movement = Point::zero();
+        if input.pressed(ButtonId::Right) {
+            movement.x += 2;
         }
 
-        if left_button.is_pressed() {
-            issacs_new_pos.x -= 2;
-            sprite_movement = true;
+        if input.pressed(ButtonId::Left) {
+            movement.x -= 2;
         }
 
-        if down_button.is_pressed() {
-            issacs_new_pos.y += 2;
-            sprite_movement = true;
+        if input.pressed(ButtonId::Down) {
+            movement.y += 2;
         }
 
-        if up_button.is_pressed() {
-            issacs_new_pos.y -= 2;
-            sprite_movement = true;
+        if input.pressed(ButtonId::Up) {
+            movement.y -= 2;
         }
 
-        //background
-        if sprite_movement {
-            background.draw(&mut display).unwrap();
+        if let Some(issac) = self.sprites.get_mut(self.issac) {
+            match self.go_home.update(input) {
+                true => issac.set_position(START),
+                false => issac.move_by(movement),
+            }
         }
-        sprite_movement = false;
 
-        //Fps counter
-        buf.clear();
-        let fps = frames as f32 / start.elapsed().as_millis() as f32 * 1000.0;
-
-        core::write!(&mut buf, "fps: {:.1}", fps).unwrap();
-        Text::new(&buf, Point::new(0, 15), char_style)
-            .draw(&mut display)
-            .unwrap();
-        frames += 1;
-
-        issac_sprite.move_sprite(issacs_new_pos, &mut display);
-
-        info!("Draw: {:?}", draw_start.elapsed().as_millis());
-        let shotgun_start = Instant::now();
-        let _ = display.shotgun().await;
-        // let buffer = framebuffer();
-        // spi.blocking_write(buffer);
-        // let tx_transfer = unsafe {
-        //     // If we don't assign future to a variable, the data register pointer
-        //     // is held across an await and makes the future non-Send.
-        //     embassy_rp::dma::write(
-        //         p.DMA_CH1,
-        //         buffer,
-        //         spi.inner.regs().dr().as_ptr() as *mut _,
-        //         // self.inner.regs().dr().as_ptr() as *mut _,
-        //         T::TX_DREQ,
-        //     )
-        // };
-        // tx_transfer.await;
-
-        info!("Shotgun: {:?}", shotgun_start.elapsed().as_millis());
-    }
-}
-
-async fn wait_vsync(vsync_pin: &mut Input<'_>) {
-    vsync_pin.wait_for_high().await;
-    vsync_pin.wait_for_low().await;
-}
-
-struct Sprite<'a> {
-    point: Point,
-    size: Option<Size>,
-    bmp_sprite: Bmp<'a, Rgb565>,
-    //TODO pass in background color?
-}
-
-impl<'a> Sprite<'a> {
-    fn new(point: Point, bmp_sprite: Bmp<'a, Rgb565>) -> Self {
-        Self {
-            point,
-            size: None,
-            bmp_sprite,
+        if input.just_pressed(ButtonId::A) {
+            audio::try_play(Tone::new(880, Duration::from_millis(60), 50));
+            hal.pulse_led(Rgb888::WHITE, Duration::from_millis(300));
         }
-    }
-    fn draw(&mut self, display: &mut impl DrawTarget<Color = Rgb565>) {
-        let sprite_image = Image::new(&self.bmp_sprite, self.point);
-        if self.size.is_none() {
-            self.size = Some(sprite_image.bounding_box().size.clone());
+
+        // Beep once when the battery gets low, the LED blinks red by itself
+        let power = hal.power();
+        if power.is_low() && !self.power.is_low() {
+            audio::try_play(Tone::new(440, Duration::from_millis(200), 80));
         }
-        // self.size = sprite_image.bounding_box().size.clone();
-        let _ = sprite_image.draw(display);
+        self.power = power;
     }
 
-    fn move_sprite(&mut self, new_location: Point, display: &mut impl DrawTarget<Color = Rgb565>) {
-        if self.size.is_none() {
-            self.draw(display);
-            return;
-        }
+    fn draw(
+        &mut self,
+        display: &mut (impl DrawTarget<Color = Rgb565> + Blend),
+        stats: &FrameStats,
+    ) {
+        let _ = self.sprites.draw(display, &self.background);
 
-        if new_location == self.point {
-            return;
-        }
+        //Fps counter
+        let char_style = MonoTextStyleBuilder::new()
+            .font(&FONT_10X20)
+            .text_color(Rgb565::CSS_YELLOW)
+            .background_color(Rgb565::BLACK)
+            .build();
+        self.fps.clear();
+        core::write!(&mut self.fps, "fps: {:.1}", stats.fps()).unwrap();
+        let _ = Text::new(&self.fps, Point::new(0, 15), char_style).draw(display);
+
+        let battery = Point::new(WIDTH as i32 - power::INDICATOR_SIZE.width as i32 - 2, 2);
+        let _ = power::draw_indicator(&self.power, display, battery);
+    }
+}
 
-        if let Some(size) = self.size {
-            // let erase_old_sprite = Rectangle::new(self.point, size).into_styled(
-            //     PrimitiveStyleBuilder::new()
-            //         .fill_color(Rgb565::BLACK)
-            //         .build(),
-            // );
-            // let _ = erase_old_sprite.draw(display);
-            self.point = new_location;
-            self.draw(display);
-        }
+#[cfg(all(test, feature = "simulator"))]
+mod tests {
+    use super::*;
+    use embassy_rp_w_template::display::framebuffer::Framebuffer;
+    use embassy_rp_w_template::display::simulator::Simulator;
+    use embassy_rp_w_template::HEIGHT;
+
+    #[test]
+    fn headless_capture_runs_the_demo_and_quits() {
+        let dir = std::env::temp_dir().join("picosystem-capture-test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut simulator = Simulator::headless(&dir, 3);
+        let mut demo = Demo::new();
+
+        run_simulator(&mut demo, &mut simulator);
+
+        // Away from Issac, the fps counter and the battery the screen shows the background
+        let mut pixels = [0; WIDTH * HEIGHT];
+        let mut background = Framebuffer::new(&mut pixels);
+        let _ = Image::new(&demo.background, Point::zero()).draw(&mut background);
+        let point = Point::new(120, 120);
+        assert_eq!(simulator.pixel(point), background.pixel(point));
+        let yellow =
+            (0..100).any(|x| simulator.pixel(Point::new(x, 10)) == Some(Rgb565::CSS_YELLOW));
+        assert!(yellow, "fps counter was not drawn");
     }
 }
@@ -1,30 +1,74 @@
 #![allow(non_snake_case)]
 
+use defmt::info;
+use display_interface_spi::SPIInterface;
+use embassy_embedded_hal::shared_bus::asynch::spi::SpiDeviceWithConfig;
+use embassy_executor::Spawner;
+use embassy_futures::select::{select3, Either3};
 pub use embassy_rp::peripherals::*;
 use embassy_rp::{
+    adc::{self, Adc},
     config::Config,
-    gpio::{AnyPin, Input, Level, Output, Pull},
-    pwm::{ChannelAPin, Pwm, SetDutyCycle, Slice},
-    Peripheral,
+    gpio::{Input, Level, Output, Pull},
+    pwm::{self, Pwm},
+    spi::{self, Spi},
 };
-use embassy_time::Timer;
+use embassy_sync::{
+    blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex},
+    channel::Channel,
+    mutex::Mutex,
+    signal::Signal,
+};
+use embassy_time::{Delay, Duration, Instant, Timer};
+use embedded_graphics::pixelcolor::{Rgb565, Rgb888, RgbColor};
+use static_cell::StaticCell;
+
+use crate::audio::speaker::{audio_task, Speaker};
+use crate::audio::{self, Tone};
+use crate::backlight::{self, pwm::backlight_task};
+use crate::console;
+use crate::display::framebuffer::Framebuffer;
+use crate::display::graphics::framebuffers;
+use crate::display::{Orientation, TearingEffect, ST7789};
+use crate::hal::PicoSystemHal;
+use crate::input::{events, scanner, Buttons};
+use crate::led::{
+    self,
+    rgb::{led_task, RgbLed},
+};
+use crate::power::{self, monitor::power_task, PowerStatus};
+
+type Spi0Bus = Mutex<NoopRawMutex, Spi<'static, SPI0, spi::Async>>;
+
+pub type DisplaySpi =
+    SpiDeviceWithConfig<'static, NoopRawMutex, Spi<'static, SPI0, spi::Async>, Output<'static>>;
+
+pub type Display = ST7789<SPIInterface<DisplaySpi, Output<'static>>, Output<'static>>;
+
+static SPI_BUS: StaticCell<Spi0Bus> = StaticCell::new();
+
+/// Time the backlight takes to fade out before sleeping
+const SLEEP_FADE: Duration = Duration::from_millis(400);
+/// Time the backlight takes to fade back in after waking
+const WAKE_FADE: Duration = Duration::from_millis(200);
+
+/// Frames on their way to `display_task`
+static FRAMES_TO_PRESENT: Channel<CriticalSectionRawMutex, Framebuffer<'static>, 1> =
+    Channel::new();
+/// Frames that are on the screen and free to be drawn into again
+static PRESENTED_FRAMES: Channel<CriticalSectionRawMutex, Framebuffer<'static>, 1> = Channel::new();
+/// Asks `display_task` to turn the screen on (true) or off (false) between frames
+static SCREEN_POWER: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+/// Raised by `display_task` once the screen is on or off
+static SCREEN_POWER_DONE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 
 #[allow(dead_code)]
 pub struct Peripherals {
     pub PIN_0: PIN_0,
     pub PIN_1: PIN_1,
     pub PIN_3: PIN_3,
-    pub PIN_4: PIN_4,
-    pub PIN_5: PIN_5,
-    pub PIN_6: PIN_6,
-    pub PIN_7: PIN_7,
-    pub PIN_8: PIN_8,
-    pub PIN_9: PIN_9,
     pub PIN_27: PIN_27,
     pub PIN_28: PIN_28,
-    pub PIN_VBUS_DETECT: PIN_2,
-    pub CHARGE_STAT: PIN_24,
-    pub BAT_SENSE: PIN_26,
     pub PIN_QSPI_SCLK: PIN_QSPI_SCLK,
     pub PIN_QSPI_SS: PIN_QSPI_SS,
     pub PIN_QSPI_SD0: PIN_QSPI_SD0,
@@ -33,12 +77,9 @@ pub struct Peripherals {
     pub PIN_QSPI_SD3: PIN_QSPI_SD3,
     pub UART0: UART0,
     pub UART1: UART1,
-    pub SPI0: SPI0,
     pub SPI1: SPI1,
     pub I2C0: I2C0,
     pub I2C1: I2C1,
-    pub DMA_CH0: DMA_CH0,
-    pub DMA_CH1: DMA_CH1,
     pub DMA_CH2: DMA_CH2,
     pub DMA_CH3: DMA_CH3,
     pub DMA_CH4: DMA_CH4,
@@ -54,12 +95,8 @@ pub struct Peripherals {
     pub PWM_SLICE2: PWM_SLICE2,
     pub PWM_SLICE3: PWM_SLICE3,
     pub PWM_SLICE4: PWM_SLICE4,
-    pub PWM_SLICE5: PWM_SLICE5,
-    pub PWM_SLICE7: PWM_SLICE7,
-    pub USB: USB,
     pub RTC: RTC,
     pub FLASH: FLASH,
-    pub ADC: ADC,
     pub ADC_TEMP_SENSOR: ADC_TEMP_SENSOR,
     pub CORE1: CORE1,
     pub PIO0: PIO0,
@@ -67,115 +104,134 @@ pub struct Peripherals {
     pub WATCHDOG: WATCHDOG,
     pub BOOTSEL: BOOTSEL,
     //PicoSystem specific peripherals
-    pub SCREEN_BACKLIGHT: Led<'static>,
-    pub LED_G: Output<'static>,
-    pub LED_R: Output<'static>,
-    pub LED_B: Output<'static>,
-    // pub Y_BUTTON: Input<'static>,
-    pub Y_BUTTON: Button<'static>,
-    pub X_BUTTON: Button<'static>,
-    pub A_BUTTON: Button<'static>,
-    pub B_BUTTON: Button<'static>,
-    pub DOWN_BUTTON: Button<'static>,
-    pub RIGHT_BUTTON: Button<'static>,
-    pub LEFT_BUTTON: Button<'static>,
-    pub UP_BUTTON: Button<'static>,
-    pub AUDIO: Output<'static>,
-}
-
-pub struct Led<'a> {
-    pwm: Pwm<'a>,
-    on: bool,
-    brightness: u8,
+    pub DISPLAY: Framebuffer<'static>,
+    pub VSYNC: Input<'static>,
+    frame_start: Instant,
 }
 
-impl<'a> Led<'a> {
-    pub fn new<T: Slice>(
-        slice: impl Peripheral<P = T> + 'a,
-        a: impl Peripheral<P = impl ChannelAPin<T>> + 'a,
-    ) -> Self {
-        let mut c = embassy_rp::pwm::Config::default();
-        c.top = 65535;
-        let pwm = Pwm::new_output_a(slice, a, c.clone());
-        Self {
-            pwm,
-            on: false,
-            brightness: 100,
-        }
-    }
-
-    /// Toggles the light or on
-    /// If it is toggled on it goes back to the previous brightness
-    pub fn toggle(&mut self) {
-        self.on = !self.on;
-        match self.on {
-            true => {
-                let _ = self.pwm.set_duty_cycle_percent(self.brightness);
+/// Sends frames to the display in the background, so the next frame can be drawn meanwhile
+#[embassy_executor::task]
+async fn display_task(mut display: Display) {
+    loop {
+        let frames = FRAMES_TO_PRESENT.receive();
+        match select3(frames, SCREEN_POWER.wait(), console::dumped_frame()).await {
+            Either3::First(frame) => {
+                display.set_framebuffer(frame);
+                let shotgun_start = Instant::now();
+                let _ = display.shotgun_dirty().await;
+                info!("Shotgun: {:?}", shotgun_start.elapsed().as_millis());
+                if let Some(frame) = display.take_framebuffer() {
+                    // The game waits for the frame while the console sends it, so it is
+                    // dumped as it was shown
+                    match console::take_dump_request() {
+                        true => console::lend_for_dump(frame).await,
+                        false => PRESENTED_FRAMES.send(frame).await,
+                    }
+                }
             }
-            false => {
-                let _ = self.pwm.set_duty_cycle_fully_off();
+            Either3::Second(on) => {
+                let _ = match on {
+                    true => display.wake().await,
+                    false => display.sleep().await,
+                };
+                SCREEN_POWER_DONE.signal(());
             }
+            Either3::Third(frame) => PRESENTED_FRAMES.send(frame).await,
         }
     }
-
-    /// Sets the brightness of the led by percentage
-    pub fn set_brightness(&mut self, brightness: u8) {
-        self.brightness = brightness;
-        let _ = self.pwm.set_duty_cycle_percent(self.brightness);
-    }
 }
 
-//TODO move this to a new game engine crate?
-pub struct Button<'a> {
-    input: Input<'a>,
+/// Turns the screen on or off once the frame being sent is done
+async fn set_screen_power(on: bool) {
+    SCREEN_POWER_DONE.reset();
+    SCREEN_POWER.signal(on);
+    SCREEN_POWER_DONE.wait().await;
 }
 
-impl<'a> Button<'a> {
-    pub fn new(pin: AnyPin) -> Self {
-        let input = Input::new(pin, Pull::Up);
-        Self { input }
-    }
+pub async fn init(config: Config, spawner: Spawner) -> Peripherals {
+    let p = embassy_rp::init(config);
 
-    pub fn is_pressed(&self) -> bool {
-        self.input.is_low()
-    }
+    //SPI Display setup
+    let mut spi_config = spi::Config::default();
+    spi_config.frequency = 125_000_000u32;
+    let spi = Spi::new_txonly(p.SPI0, p.PIN_6, p.PIN_7, p.DMA_CH0, spi_config);
+    let spi_bus = SPI_BUS.init(Mutex::new(spi));
 
-    pub async fn debounce(&mut self) -> Level {
-        loop {
-            let l1 = self.input.get_level();
+    let mut display_config = spi::Config::default();
+    // display_config.frequency = 80_000_000;
+    display_config.frequency = 62_500_000u32;
+    display_config.phase = spi::Phase::CaptureOnSecondTransition;
+    display_config.polarity = spi::Polarity::IdleHigh;
 
-            self.input.wait_for_any_edge().await;
+    let display_spi =
+        SpiDeviceWithConfig::new(spi_bus, Output::new(p.PIN_5, Level::High), display_config);
+    let di = SPIInterface::new(display_spi, Output::new(p.PIN_9, Level::Low));
 
-            // Timer::after(self.debounce).await;
-            Timer::after_millis(20).await;
+    let mut display = ST7789::new(di, Some(Output::new(p.PIN_4, Level::Low)), 240, 240);
+    let _ = display.init(&mut Delay).await;
+    let _ = display.set_tearing_effect(TearingEffect::Vertical).await;
+    let _ = display.set_orientation(Orientation::Portrait).await;
+    let _ = display.clear_screen(Rgb565::BLACK).await;
 
-            let l2 = self.input.get_level();
-            if l1 != l2 {
-                break l2;
-            }
-        }
-    }
-}
+    // Nothing is being sent yet, so the front buffer is free right away
+    let [back_buffer, front_buffer] = framebuffers();
+    let _ = PRESENTED_FRAMES.try_send(front_buffer);
+    spawner.spawn(display_task(display)).unwrap();
 
-pub fn init(config: Config) -> Peripherals {
-    let p = embassy_rp::init(config);
+    // Buttons are watched in the background, `buttons()` and the event queue read what it finds
+    spawner
+        .spawn(scanner::scan_task([
+            p.PIN_16.into(),
+            p.PIN_17.into(),
+            p.PIN_18.into(),
+            p.PIN_19.into(),
+            p.PIN_20.into(),
+            p.PIN_21.into(),
+            p.PIN_22.into(),
+            p.PIN_23.into(),
+        ]))
+        .unwrap();
+
+    spawner
+        .spawn(audio_task(Speaker::new(p.PWM_SLICE5, p.PIN_11), p.DMA_CH1))
+        .unwrap();
+
+    // The backlight and the LED share PWM slices: the backlight is 6 A, the LED's green 6 B,
+    // red 7 A and blue 7 B
+    let mut pwm_config = pwm::Config::default();
+    pwm_config.top = 65535;
+    let (backlight, green) =
+        Pwm::new_output_ab(p.PWM_SLICE6, p.PIN_12, p.PIN_13, pwm_config.clone()).split();
+    let (red, blue) = Pwm::new_output_ab(p.PWM_SLICE7, p.PIN_14, p.PIN_15, pwm_config).split();
+    let backlight = backlight.expect("slice 6 has an A output");
+    spawner.spawn(backlight_task(backlight)).unwrap();
+    let led = RgbLed::new(
+        red.expect("slice 7 has an A output"),
+        green.expect("slice 6 has a B output"),
+        blue.expect("slice 7 has a B output"),
+    );
+    spawner.spawn(led_task(led)).unwrap();
+
+    console::init_logger(log::LevelFilter::Info);
+    console::usb::init(spawner, p.USB);
+
+    // The battery is sampled in the background, `power()` and `power::current()` read it
+    spawner
+        .spawn(power_task(
+            Adc::new_blocking(p.ADC, adc::Config::default()),
+            adc::Channel::new_pin(p.PIN_26, Pull::None),
+            Input::new(p.PIN_24, Pull::Up),
+            Input::new(p.PIN_2, Pull::Down),
+        ))
+        .unwrap();
 
     Peripherals {
         PIN_0: p.PIN_0,
         PIN_1: p.PIN_1,
         PIN_3: p.PIN_3,
-        PIN_4: p.PIN_4,
-        PIN_5: p.PIN_5,
-        PIN_6: p.PIN_6,
-        PIN_7: p.PIN_7,
-        PIN_8: p.PIN_8,
-        PIN_9: p.PIN_9,
 
         PIN_27: p.PIN_27,
         PIN_28: p.PIN_28,
-        PIN_VBUS_DETECT: p.PIN_2,
-        CHARGE_STAT: p.PIN_24,
-        BAT_SENSE: p.PIN_26,
         PIN_QSPI_SCLK: p.PIN_QSPI_SCLK,
         PIN_QSPI_SS: p.PIN_QSPI_SS,
         PIN_QSPI_SD0: p.PIN_QSPI_SD0,
@@ -184,12 +240,9 @@ pub fn init(config: Config) -> Peripherals {
         PIN_QSPI_SD3: p.PIN_QSPI_SD3,
         UART0: p.UART0,
         UART1: p.UART1,
-        SPI0: p.SPI0,
         SPI1: p.SPI1,
         I2C0: p.I2C0,
         I2C1: p.I2C1,
-        DMA_CH0: p.DMA_CH0,
-        DMA_CH1: p.DMA_CH1,
         DMA_CH2: p.DMA_CH2,
         DMA_CH3: p.DMA_CH3,
         DMA_CH4: p.DMA_CH4,
@@ -205,13 +258,9 @@ pub fn init(config: Config) -> Peripherals {
         PWM_SLICE2: p.PWM_SLICE2,
         PWM_SLICE3: p.PWM_SLICE3,
         PWM_SLICE4: p.PWM_SLICE4,
-        PWM_SLICE5: p.PWM_SLICE5,
 
-        PWM_SLICE7: p.PWM_SLICE7,
-        USB: p.USB,
         RTC: p.RTC,
         FLASH: p.FLASH,
-        ADC: p.ADC,
         ADC_TEMP_SENSOR: p.ADC_TEMP_SENSOR,
         CORE1: p.CORE1,
         PIO0: p.PIO0,
@@ -219,19 +268,88 @@ pub fn init(config: Config) -> Peripherals {
         WATCHDOG: p.WATCHDOG,
         BOOTSEL: p.BOOTSEL,
         //PicoSystem specific peripherals
-        SCREEN_BACKLIGHT: Led::new(p.PWM_SLICE6, p.PIN_12),
-        //TODO explore moving to led with pwm
-        LED_G: Output::new(p.PIN_13, Level::Low),
-        LED_R: Output::new(p.PIN_14, Level::Low),
-        LED_B: Output::new(p.PIN_15, Level::Low),
-        Y_BUTTON: Button::new(AnyPin::from(p.PIN_16)),
-        X_BUTTON: Button::new(AnyPin::from(p.PIN_17)),
-        A_BUTTON: Button::new(AnyPin::from(p.PIN_18)),
-        B_BUTTON: Button::new(AnyPin::from(p.PIN_19)),
-        DOWN_BUTTON: Button::new(AnyPin::from(p.PIN_20)),
-        RIGHT_BUTTON: Button::new(AnyPin::from(p.PIN_21)),
-        LEFT_BUTTON: Button::new(AnyPin::from(p.PIN_22)),
-        UP_BUTTON: Button::new(AnyPin::from(p.PIN_23)),
-        AUDIO: Output::new(p.PIN_11, Level::Low),
+        DISPLAY: back_buffer,
+        VSYNC: Input::new(p.PIN_8, Pull::Down),
+        frame_start: Instant::now(),
+    }
+}
+
+impl PicoSystemHal for Peripherals {
+    type Display = Framebuffer<'static>;
+
+    fn display(&mut self) -> &mut Self::Display {
+        &mut self.DISPLAY
+    }
+
+    async fn wait_vsync(&mut self) {
+        self.VSYNC.wait_for_high().await;
+        self.VSYNC.wait_for_low().await;
+    }
+
+    /// Hands the frame to `display_task` at the next vsync and returns straight away,
+    /// only waiting if the previous frame is still being sent.
+    /// Drawing carries on in a copy of the frame, so games can keep drawing only what changed.
+    async fn present(&mut self) {
+        info!("Draw: {:?}", self.frame_start.elapsed().as_millis());
+        // The returned frame still shows the one before this, so it only misses what was
+        // drawn since. Both come from `framebuffers()`, so their resolutions always match.
+        let mut next = PRESENTED_FRAMES.receive().await;
+        let _ = next.copy_dirty_from(&self.DISPLAY);
+        self.wait_vsync().await;
+        let frame = core::mem::replace(&mut self.DISPLAY, next);
+        FRAMES_TO_PRESENT.send(frame).await;
+        self.frame_start = Instant::now();
+    }
+
+    fn buttons(&self) -> Buttons {
+        events::current()
+    }
+
+    fn set_backlight(&mut self, brightness: u8) {
+        backlight::set_brightness(brightness);
+    }
+
+    fn set_backlight_dimmed(&mut self, dimmed: bool) {
+        backlight::set_dimmed(dimmed);
+    }
+
+    fn set_led(&mut self, color: Rgb888) {
+        led::set_color(color);
+    }
+
+    fn pulse_led(&mut self, color: Rgb888, duration: Duration) {
+        led::pulse(color, duration);
+    }
+
+    async fn play_tone(&mut self, frequency: u32, duration: Duration) {
+        audio::play(Tone::new(frequency, duration, 100)).await;
+        audio::wait_idle().await;
+    }
+
+    fn power(&mut self) -> PowerStatus {
+        power::current()
+    }
+
+    /// Fades out the backlight, switches off the LED and the screen, then stops the clocks
+    /// until a button is pressed. Sound freezes along with everything else.
+    async fn sleep(&mut self) {
+        let backlight_on = backlight::is_on();
+        let led_brightness = led::brightness();
+        led::set_brightness(0);
+        backlight::turn_off(SLEEP_FADE);
+        backlight::wait_fade().await;
+        log::info!("going to sleep");
+        set_screen_power(false).await;
+        // The panel needs 120ms asleep before it can wake again
+        Timer::after(Duration::from_millis(120)).await;
+
+        scanner::dormant_until_pressed().await;
+        log::info!("woken up by {:?}", events::current());
+
+        set_screen_power(true).await;
+        if backlight_on {
+            backlight::turn_on(WAKE_FADE);
+        }
+        led::set_brightness(led_brightness);
     }
 }
@@ -0,0 +1,309 @@
+//! Battery level and charging, sampled in the background by [`monitor`] on the PicoSystem
+//! and published here, so games and status bars can poll [`current`] or wait for changes.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{PrimitiveStyle, Rectangle};
+
+#[cfg(not(feature = "simulator"))]
+pub mod monitor;
+
+/// At or below this the battery counts as low
+pub const LOW_PERCENT: u8 = 10;
+
+/// At or below this the device is about to turn itself off
+pub const CRITICAL_PERCENT: u8 = 3;
+
+/// Battery voltage against charge left for a LiPo cell under light load, highest first
+const DISCHARGE_CURVE: [(u16, u8); 11] = [
+    (4150, 100),
+    (4050, 90),
+    (3970, 80),
+    (3900, 70),
+    (3840, 60),
+    (3790, 50),
+    (3750, 40),
+    (3710, 30),
+    (3670, 20),
+    (3600, 10),
+    (3300, 0),
+];
+
+/// What the charger is doing.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, defmt::Format)]
+pub enum ChargeState {
+    /// Running on the battery
+    Discharging,
+    /// Plugged in and charging
+    Charging,
+    /// Plugged in with a full battery
+    Charged,
+}
+
+/// The battery as of the last sample.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, defmt::Format)]
+pub struct PowerStatus {
+    pub millivolts: u16,
+    /// Charge left going by the discharge curve
+    pub percent: u8,
+    pub charge: ChargeState,
+    /// USB power is connected
+    pub usb: bool,
+}
+
+impl PowerStatus {
+    ///
+    /// # Arguments
+    ///
+    /// * `millivolts` - battery voltage
+    /// * `charging` - the charger says it is charging
+    /// * `usb` - USB power is connected
+    ///
+    pub fn new(millivolts: u16, charging: bool, usb: bool) -> Self {
+        let charge = match (charging, usb) {
+            (true, _) => ChargeState::Charging,
+            (false, true) => ChargeState::Charged,
+            (false, false) => ChargeState::Discharging,
+        };
+        Self {
+            millivolts,
+            percent: percent(millivolts),
+            charge,
+            usb,
+        }
+    }
+
+    /// Running on a battery that is getting low
+    pub fn is_low(&self) -> bool {
+        self.charge == ChargeState::Discharging && self.percent <= LOW_PERCENT
+    }
+
+    /// Running on a battery that is nearly flat, time to save and sleep
+    pub fn is_critical(&self) -> bool {
+        self.charge == ChargeState::Discharging && self.percent <= CRITICAL_PERCENT
+    }
+}
+
+impl Default for PowerStatus {
+    /// A full battery, until the first sample says otherwise
+    fn default() -> Self {
+        Self::new(DISCHARGE_CURVE[0].0, false, false)
+    }
+}
+
+/// Charge left in percent for a battery voltage, interpolated along the discharge curve
+pub fn percent(millivolts: u16) -> u8 {
+    let (full, _) = DISCHARGE_CURVE[0];
+    if millivolts >= full {
+        return 100;
+    }
+
+    for pair in DISCHARGE_CURVE.windows(2) {
+        let ((high_mv, high), (low_mv, low)) = (pair[0], pair[1]);
+        if millivolts >= low_mv {
+            let span = (high_mv - low_mv) as u32;
+            let above = (millivolts - low_mv) as u32;
+            return low + ((high - low) as u32 * above / span) as u8;
+        }
+    }
+    0
+}
+
+/// Battery voltage from a 12 bit reading of BAT_SENSE, which sits behind a 1/3 divider
+/// and is measured against 3.3V
+pub fn millivolts(raw: u16) -> u16 {
+    (raw as u32 * 3300 * 3 / 4096) as u16
+}
+
+///
+/// Smooths battery readings, the voltage sags and jumps with the load on it.
+///
+#[derive(Clone, Debug, Default)]
+pub struct BatteryFilter {
+    /// Average in 1/16 mV
+    average: Option<u32>,
+}
+
+impl BatteryFilter {
+    pub const fn new() -> Self {
+        Self { average: None }
+    }
+
+    /// Adds a reading and returns the smoothed voltage, each reading counts for 1/8
+    pub fn add(&mut self, millivolts: u16) -> u16 {
+        let sample = (millivolts as u32) << 4;
+        let average = match self.average {
+            Some(average) => average - average / 8 + sample / 8,
+            None => sample,
+        };
+        self.average = Some(average);
+        (average >> 4) as u16
+    }
+}
+
+static STATUS: Mutex<CriticalSectionRawMutex, Cell<Option<PowerStatus>>> =
+    Mutex::new(Cell::new(None));
+
+/// Raised when the percentage or charging changes
+static CHANGED: Signal<CriticalSectionRawMutex, PowerStatus> = Signal::new();
+
+/// The battery as of the last sample
+pub fn current() -> PowerStatus {
+    STATUS.lock(|status| status.get()).unwrap_or_default()
+}
+
+/// Records a new sample, only the monitor should call this
+pub fn publish(status: PowerStatus) {
+    let previous = STATUS.lock(|current| current.replace(Some(status)));
+    let changed = previous.is_none_or(|previous| {
+        (previous.percent, previous.charge, previous.usb)
+            != (status.percent, status.charge, status.usb)
+    });
+    if changed {
+        CHANGED.signal(status);
+    }
+}
+
+/// Waits until the percentage or the charging changes
+pub async fn wait_change() -> PowerStatus {
+    CHANGED.wait().await
+}
+
+/// Size of the battery drawn by [`draw_indicator`]
+pub const INDICATOR_SIZE: Size = Size::new(22, 10);
+
+///
+/// Draws a small battery for a status bar: filled to the charge left, green while plugged in
+/// and red once it is low
+///
+/// # Arguments
+///
+/// * `status` - the battery to show
+/// * `target` - where to draw it
+/// * `top_left` - top left corner, it takes [`INDICATOR_SIZE`]
+///
+pub fn draw_indicator<D>(
+    status: &PowerStatus,
+    target: &mut D,
+    top_left: Point,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = Rgb565>,
+{
+    let color = match (status.charge, status.is_low()) {
+        (ChargeState::Charging | ChargeState::Charged, _) => Rgb565::GREEN,
+        (ChargeState::Discharging, true) => Rgb565::RED,
+        (ChargeState::Discharging, false) => Rgb565::WHITE,
+    };
+
+    let body = Size::new(INDICATOR_SIZE.width - 2, INDICATOR_SIZE.height);
+    Rectangle::new(top_left, body)
+        .into_styled(PrimitiveStyle::with_fill(Rgb565::BLACK))
+        .draw(target)?;
+    Rectangle::new(top_left, body)
+        .into_styled(PrimitiveStyle::with_stroke(color, 1))
+        .draw(target)?;
+    Rectangle::new(top_left + Point::new(body.width as i32, 3), Size::new(2, 4))
+        .into_styled(PrimitiveStyle::with_fill(color))
+        .draw(target)?;
+
+    let inside = body.width - 4;
+    let level = (inside * status.percent as u32).div_ceil(100);
+    Rectangle::new(
+        top_left + Point::new(2, 2),
+        Size::new(level, body.height - 4),
+    )
+    .into_styled(PrimitiveStyle::with_fill(color))
+    .draw(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_percent_off_the_discharge_curve() {
+        assert_eq!(percent(3000), 0);
+        assert_eq!(percent(3300), 0);
+        assert_eq!(percent(3600), 10);
+        assert_eq!(percent(3635), 15);
+        assert_eq!(percent(4100), 95);
+        assert_eq!(percent(4150), 100);
+        assert_eq!(percent(4300), 100);
+    }
+
+    #[test]
+    fn undoes_the_divider() {
+        assert_eq!(millivolts(0), 0);
+        assert_eq!(millivolts(2048), 4950);
+        assert_eq!(millivolts(4095), 9897);
+    }
+
+    #[test]
+    fn filter_starts_at_the_first_reading_and_settles_on_new_ones() {
+        let mut filter = BatteryFilter::new();
+        assert_eq!(filter.add(3700), 3700);
+
+        let first = filter.add(3900);
+        assert!(3700 < first && first < 3900, "{}", first);
+        let settled = (0..100).map(|_| filter.add(3900)).last();
+        assert_eq!(settled, Some(3900));
+    }
+
+    #[test]
+    fn charge_state_comes_from_the_charger_and_usb() {
+        assert_eq!(
+            PowerStatus::new(3800, true, true).charge,
+            ChargeState::Charging
+        );
+        assert_eq!(
+            PowerStatus::new(4200, false, true).charge,
+            ChargeState::Charged
+        );
+        assert_eq!(
+            PowerStatus::new(3800, false, false).charge,
+            ChargeState::Discharging
+        );
+    }
+
+    #[test]
+    fn only_low_or_critical_on_the_battery() {
+        let flat = PowerStatus::new(3310, false, false);
+        assert!(flat.is_low() && flat.is_critical());
+        let low = PowerStatus::new(3600, false, false);
+        assert!(low.is_low() && !low.is_critical());
+
+        let plugged_in = PowerStatus::new(3310, true, true);
+        assert!(!plugged_in.is_low() && !plugged_in.is_critical());
+        let charged = PowerStatus::new(3310, false, true);
+        assert!(!charged.is_low() && !charged.is_critical());
+    }
+
+    #[test]
+    fn publish_signals_only_what_games_see_change() {
+        CHANGED.reset();
+        publish(PowerStatus::new(3800, false, false));
+        assert!(CHANGED.try_take().is_some());
+        assert_eq!(current().millivolts, 3800);
+
+        // A few millivolts either way stays on the same percent
+        publish(PowerStatus::new(3801, false, false));
+        assert!(CHANGED.try_take().is_none());
+        assert_eq!(current().millivolts, 3801);
+
+        publish(PowerStatus::new(3801, false, true));
+        assert_eq!(CHANGED.try_take().map(|status| status.usb), Some(true));
+        publish(PowerStatus::new(3801, true, true));
+        assert_eq!(
+            CHANGED.try_take().map(|status| status.charge),
+            Some(ChargeState::Charging)
+        );
+        publish(PowerStatus::new(3500, true, true));
+        assert!(CHANGED.try_take().is_some());
+    }
+}
@@ -0,0 +1,53 @@
+//! Samples the battery on the PicoSystem: BAT_SENSE (GPIO 26) through the ADC, CHARGE_STAT
+//! (GPIO 24), which the charger pulls low while charging, and VBUS_DETECT (GPIO 2).
+
+use embassy_futures::select::select3;
+use embassy_rp::adc::{self, Adc};
+use embassy_rp::gpio::Input;
+use embassy_time::{Duration, Timer};
+
+use super::{millivolts, publish, BatteryFilter, PowerStatus};
+
+/// Time between battery readings, plugging in or out is noticed straight away
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+///
+/// Samples the battery forever, spawn it once
+///
+/// # Arguments
+///
+/// * `adc` - the ADC, in blocking mode
+/// * `sense` - ADC channel on BAT_SENSE
+/// * `charge_stat` - CHARGE_STAT, pulled up
+/// * `vbus` - VBUS_DETECT
+///
+#[embassy_executor::task]
+pub async fn power_task(
+    mut adc: Adc<'static, adc::Blocking>,
+    mut sense: adc::Channel<'static>,
+    mut charge_stat: Input<'static>,
+    mut vbus: Input<'static>,
+) -> ! {
+    let mut filter = BatteryFilter::new();
+    let mut voltage = None;
+
+    loop {
+        if let Ok(raw) = adc.blocking_read(&mut sense) {
+            voltage = Some(filter.add(millivolts(raw)));
+        }
+        if let Some(voltage) = voltage {
+            publish(PowerStatus::new(
+                voltage,
+                charge_stat.is_low(),
+                vbus.is_high(),
+            ));
+        }
+
+        select3(
+            Timer::after(SAMPLE_INTERVAL),
+            charge_stat.wait_for_any_edge(),
+            vbus.wait_for_any_edge(),
+        )
+        .await;
+    }
+}